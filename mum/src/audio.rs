@@ -2,32 +2,112 @@
 //!
 //! Audio is handled mostly as signals from [dasp_signal]. Input/output is handled by [cpal].
 
+pub mod conversion;
+pub mod ducking;
+pub mod echo;
 pub mod input;
 pub mod output;
+pub mod positional;
 pub mod sound_effects;
 pub mod transformers;
 
-use crate::error::AudioError;
+use crate::error::{AudioError, AudioStream};
 use crate::network::VoiceStreamType;
-use crate::state::StatePhase;
+use crate::state::{State, StatePhase};
 
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::StreamError;
+use futures_util::future;
 use futures_util::stream::Stream;
 use futures_util::StreamExt;
+use log::*;
 use mumble_protocol::voice::{VoicePacket, VoicePacketPayload};
 use mumble_protocol::Serverbound;
-use mumlib::config::SoundEffect;
-use std::collections::{hash_map::Entry, HashMap};
+use mumlib::config::{DuckingConfig, LimiterConfig, PositionalConfig, SoundEffect, TransformerConfig};
+use std::collections::{hash_map::Entry, HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 use tokio::sync::watch;
+use tokio::time::Instant;
 
-use self::input::{AudioInputDevice, DefaultAudioInputDevice};
-use self::output::{AudioOutputDevice, ClientStream, DefaultAudioOutputDevice};
+use self::ducking::Ducker;
+use self::echo::EchoReference;
+use self::input::{AudioInputDevice, DefaultAudioInputDevice, EncodedPacket, EncoderSettings, InputStreamBuilder, NewInputStream};
+use self::output::{AudioOutputDevice, ClientStream, DefaultAudioOutputDevice, NewOutputStream, OutputStreamBuilder};
+use self::positional::{Listener, PositionalSettings};
 use self::sound_effects::NotificationEvents;
+use self::transformers::{InputLevel, Limiter};
 
 /// The sample rate used internally.
 const SAMPLE_RATE: u32 = 48000;
 
+/// The session ID that our own audio is played as while it's looped back.
+const LOOPBACK_SESSION: u32 = u32::MAX;
+
+/// How long to wait before moving a stream to another device after an error,
+/// and between attempts while there's no device to move to.
+const DEVICE_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Builds a new stream for [AudioInput] or [AudioOutput] on another device.
+#[derive(Debug)]
+pub enum StreamBuilder {
+    Input(InputStreamBuilder),
+    Output(OutputStreamBuilder),
+}
+
+impl StreamBuilder {
+    /// Builds and starts a stream on some device.
+    pub fn build(&self, device: &cpal::Device) -> Result<NewStream, AudioError> {
+        match self {
+            StreamBuilder::Input(builder) => builder.build(device).map(NewStream::Input),
+            StreamBuilder::Output(builder) => builder.build(device).map(NewStream::Output),
+        }
+    }
+}
+
+/// A stream that was built by a [StreamBuilder].
+#[derive(Debug)]
+pub enum NewStream {
+    Input(NewInputStream),
+    Output(NewOutputStream),
+}
+
+/// Returns the device with some name, or the default device if None or if
+/// there's no such device.
+fn find_device(name: Option<&str>, stream: AudioStream) -> Result<cpal::Device, AudioError> {
+    let host = cpal::default_host();
+    if let Some(name) = name {
+        let devices = match stream {
+            AudioStream::Input => host.input_devices().map(|devices| devices.collect::<Vec<_>>()),
+            AudioStream::Output => host.output_devices().map(|devices| devices.collect::<Vec<_>>()),
+        };
+        match devices {
+            Ok(devices) => {
+                let names: Vec<_> = devices.iter().filter_map(|device| device.name().ok()).collect();
+                if let Some(device) = devices
+                    .into_iter()
+                    .find(|device| device.name().ok().as_deref() == Some(name))
+                {
+                    return Ok(device);
+                }
+                warn!(
+                    "No {} device called '{}', using the default. Available devices: {}",
+                    stream,
+                    name,
+                    names.join(", ")
+                );
+            }
+            Err(e) => warn!("Couldn't list {} devices, using the default: {}", stream, e),
+        }
+    }
+    match stream {
+        AudioStream::Input => host.default_input_device(),
+        AudioStream::Output => host.default_output_device(),
+    }
+    .ok_or(AudioError::NoDevice(stream))
+}
+
 /// Input audio state. Input audio is picket up from an [AudioInputDevice] (e.g.
 /// a microphone) and sent over the network.
 pub struct AudioInput {
@@ -36,26 +116,65 @@ pub struct AudioInput {
     /// Outgoing voice packets that should be sent over the network.
     channel_receiver:
         Arc<tokio::sync::Mutex<Box<dyn Stream<Item = VoicePacket<Serverbound>> + Unpin>>>,
+
+    /// Our position, which is sent with our audio.
+    position_sender: watch::Sender<Option<[f32; 3]>>,
+
+    /// Encoded audio that should be played back to us.
+    loopback_receiver: Arc<tokio::sync::Mutex<futures_channel::mpsc::Receiver<Vec<u8>>>>,
+
+    /// How long looped back audio is delayed, or None if it isn't looped
+    /// back.
+    loopback_delay: (Arc<watch::Sender<Option<Duration>>>, watch::Receiver<Option<Duration>>),
+
+    /// Errors that occur while listening.
+    error_receiver: Arc<tokio::sync::Mutex<futures_channel::mpsc::Receiver<StreamError>>>,
 }
 
 impl AudioInput {
     pub fn new(
+        device_name: Option<&str>,
         input_volume: f32,
+        transformers: Option<Vec<TransformerConfig>>,
+        encoder_settings: EncoderSettings,
+        echo_reference: EchoReference,
         phase_watcher: watch::Receiver<StatePhase>,
     ) -> Result<Self, AudioError> {
-        let mut default = DefaultAudioInputDevice::new(input_volume, phase_watcher, 4)?;
+        let (loopback_delay_sender, loopback_delay_receiver) = watch::channel(None);
+        let mut default = DefaultAudioInputDevice::new(
+            device_name,
+            input_volume,
+            transformers.unwrap_or_else(transformers::default_config),
+            encoder_settings,
+            echo_reference,
+            loopback_delay_receiver.clone(),
+            phase_watcher,
+        )?;
 
+        let (position_sender, position_receiver) = watch::channel(None);
+        let loopback_receiver = default.loopback_receiver().unwrap();
+        let error_receiver = default.error_receiver().unwrap();
+
+        // The sequence number counts 10 ms frames, not packets.
         let opus_stream = default
             .sample_receiver()
             .unwrap()
-            .enumerate()
-            .map(|(i, e)| VoicePacket::Audio {
-                _dst: std::marker::PhantomData,
-                target: 0,      // normal speech
-                session_id: (), // unused for server-bound packets
-                seq_num: i as u64,
-                payload: VoicePacketPayload::Opus(e.into(), false),
-                position_info: None,
+            .scan(0, move |seq_num, e: EncodedPacket| {
+                *seq_num += e.skipped_frames;
+                let frames = opus::packet::get_nb_samples(&e.payload, SAMPLE_RATE).unwrap_or(0) as u64
+                    / (SAMPLE_RATE as u64 / 100);
+                let packet = VoicePacket::Audio {
+                    _dst: std::marker::PhantomData,
+                    target: 0,      // normal speech
+                    session_id: (), // unused for server-bound packets
+                    seq_num: *seq_num,
+                    payload: VoicePacketPayload::Opus(e.payload.into(), false),
+                    position_info: position_receiver
+                        .borrow()
+                        .map(|position| positional::encode_position(position).into()),
+                };
+                *seq_num += frames.max(1);
+                future::ready(Some(packet))
             });
 
         default.play()?;
@@ -63,6 +182,10 @@ impl AudioInput {
         let res = Self {
             device: default,
             channel_receiver: Arc::new(tokio::sync::Mutex::new(Box::new(opus_stream))),
+            position_sender,
+            loopback_receiver: Arc::new(tokio::sync::Mutex::new(loopback_receiver)),
+            loopback_delay: (Arc::new(loopback_delay_sender), loopback_delay_receiver),
+            error_receiver: Arc::new(tokio::sync::Mutex::new(error_receiver)),
         };
         Ok(res)
     }
@@ -73,9 +196,56 @@ impl AudioInput {
         Arc::clone(&self.channel_receiver)
     }
 
+    /// Plays our own audio back to us after some delay instead of sending it
+    /// to the server, until the returned [Loopback] is dropped.
+    pub fn loopback(&self, delay: Duration) -> Loopback {
+        let sender = Arc::clone(&self.loopback_delay.0);
+        let _ = sender.send(Some(delay));
+        Loopback { sender }
+    }
+
+    /// The name of the device that is listened to.
+    pub fn device_name(&self) -> &str {
+        self.device.device_name()
+    }
+
+    /// Returns what's needed to move the input to another device.
+    pub fn stream_builder(&self) -> InputStreamBuilder {
+        self.device.stream_builder()
+    }
+
+    /// Moves the input to a stream that was built on another device. Returns
+    /// the old stream, which should be dropped after the state is unlocked
+    /// since dropping it waits for its callback to finish.
+    pub fn set_stream(&mut self, stream: NewInputStream) -> cpal::Stream {
+        self.device.set_stream(stream)
+    }
+
+    /// Sets the position that is sent with our audio.
+    pub fn set_position(&self, position: Option<[f32; 3]>) {
+        let _ = self.position_sender.send(position);
+    }
+
     pub fn set_volume(&self, input_volume: f32) {
         self.device.set_volume(input_volume);
     }
+
+    /// Sets how audio is processed before it's sent. The default transformers
+    /// are used if None.
+    pub fn set_transformers(&self, transformers: Option<Vec<TransformerConfig>>) {
+        self.device
+            .set_transformers(transformers.unwrap_or_else(transformers::default_config));
+    }
+
+    /// Sets how audio is encoded.
+    pub fn set_encoder_settings(&self, settings: EncoderSettings) {
+        self.device.set_encoder_settings(settings);
+    }
+
+    /// Returns a receiver of the current input level and noise gate state.
+    pub fn level_receiver(&self) -> watch::Receiver<InputLevel> {
+        self.device.level_receiver()
+    }
 }
 
 impl Debug for AudioInput {
@@ -83,6 +253,10 @@ impl Debug for AudioInput {
         f.debug_struct("AudioInput")
             .field("device", &self.device)
             .field("channel_receiver", &"receiver")
+            .field("position_sender", &self.position_sender)
+            .field("loopback_receiver", &"receiver")
+            .field("loopback_delay", &self.loopback_delay)
+            .field("error_receiver", &"receiver")
             .finish()
     }
 }
@@ -103,22 +277,38 @@ pub struct AudioOutput {
 
     /// Which sound effect should be played on an event.
     sounds: HashMap<NotificationEvents, Vec<f32>>,
+    sound_effects_enabled: bool,
+
+    /// Errors that occur while playing.
+    error_receiver: Arc<tokio::sync::Mutex<futures_channel::mpsc::Receiver<StreamError>>>,
 }
 
 impl AudioOutput {
-    pub fn new(output_volume: f32) -> Result<Self, AudioError> {
-        let user_volumes = Arc::new(std::sync::Mutex::new(HashMap::new()));
+    pub fn new(
+        device_name: Option<&str>,
+        output_volume: f32,
+        echo_reference: EchoReference,
+    ) -> Result<Self, AudioError> {
+        let user_volumes = Arc::new(Mutex::new(HashMap::new()));
 
-        let default = DefaultAudioOutputDevice::new(output_volume, Arc::clone(&user_volumes))?;
+        let mut default = DefaultAudioOutputDevice::new(
+            device_name,
+            output_volume,
+            Arc::clone(&user_volumes),
+            echo_reference,
+        )?;
         default.play()?;
 
         let client_streams = default.client_streams();
+        let error_receiver = default.error_receiver().unwrap();
 
         let mut res = Self {
             device: default,
             sounds: HashMap::new(),
+            sound_effects_enabled: true,
             client_streams,
             user_volumes,
+            error_receiver: Arc::new(tokio::sync::Mutex::new(error_receiver)),
         };
         res.load_sound_effects(&[]);
         Ok(res)
@@ -130,17 +320,96 @@ impl AudioOutput {
         self.sounds = sound_effects::load_sound_effects(overrides, self.device.num_channels());
     }
 
-    /// Decodes a voice packet.
+    /// Sets whether sound effects are played at all.
+    pub fn set_sound_effects_enabled(&mut self, enabled: bool) {
+        self.sound_effects_enabled = enabled;
+    }
+
+    /// The name of the device that is played to.
+    pub fn device_name(&self) -> &str {
+        self.device.device_name()
+    }
+
+    /// Returns what's needed to move the output to another device.
+    pub fn stream_builder(&self) -> OutputStreamBuilder {
+        self.device.stream_builder()
+    }
+
+    /// Moves the output to a stream that was built on another device. Returns
+    /// the old stream, which should be dropped after the state is unlocked
+    /// since dropping it waits for its callback to finish.
+    pub fn set_stream(&mut self, stream: NewOutputStream) -> cpal::Stream {
+        self.device.set_stream(stream)
+    }
+
+    /// Decodes a voice packet. `target` is the voice target of the packet,
+    /// where 1 to 30 means that the user is whispering to us.
     pub fn decode_packet_payload(
         &self,
         stream_type: VoiceStreamType,
         session_id: u32,
+        target: u8,
         payload: VoicePacketPayload,
+        position: Option<[f32; 3]>,
     ) {
+        let whisper_started = {
+            let mut client_streams = self.client_streams.lock().unwrap();
+            client_streams.decode_packet((stream_type, session_id), payload, position);
+            (1..31).contains(&target) && client_streams.register_whisper(session_id)
+        };
+        if whisper_started {
+            self.play_effect(NotificationEvents::Whisper);
+        }
+    }
+
+    /// Returns the sessions of the users whose audio is currently being
+    /// played.
+    pub fn talking_sessions(&self) -> HashSet<u32> {
+        self.client_streams.lock().unwrap().talking_sessions()
+    }
+
+    /// Sets our own position, which other users are rendered relative to.
+    pub fn set_listener(&self, listener: Option<Listener>) {
+        self.client_streams.lock().unwrap().set_listener(listener);
+    }
+
+    pub fn set_positional_config(&self, config: Option<&PositionalConfig>) {
+        self.client_streams
+            .lock()
+            .unwrap()
+            .set_positional_settings(PositionalSettings::new(config));
+    }
+
+    /// Sets how other users are made quieter while some users are talking.
+    pub fn set_ducking_config(&self, config: Option<&DuckingConfig>) {
+        self.client_streams
+            .lock()
+            .unwrap()
+            .set_ducker(Ducker::new(config));
+    }
+
+    /// Sets which users make other users quieter while they're talking.
+    pub fn set_ducking_sessions(&self, sessions: HashSet<u32>) {
         self.client_streams
             .lock()
             .unwrap()
-            .decode_packet((stream_type, session_id), payload);
+            .set_ducking_sessions(sessions);
+    }
+
+    /// Sets how the mixed audio is kept from clipping.
+    pub fn set_limiter_config(&self, config: Option<&LimiterConfig>) {
+        let config = config.cloned().unwrap_or_default();
+        let limiter = if config.enabled.unwrap_or(true) {
+            Some(Limiter::new(
+                config.threshold.unwrap_or(-1.0),
+                config.lookahead.unwrap_or(5.0),
+                config.release.unwrap_or(100.0),
+                self.device.num_channels(),
+            ))
+        } else {
+            None
+        };
+        self.client_streams.lock().unwrap().set_limiter(limiter);
     }
 
     /// Sets the volume of the output device.
@@ -148,6 +417,26 @@ impl AudioOutput {
         self.device.set_volume(output_volume);
     }
 
+    /// Returns the incoming volume of a user and whether they're muted.
+    pub fn user_volume(&self, id: u32) -> (f32, bool) {
+        self.user_volumes
+            .lock()
+            .unwrap()
+            .get(&id)
+            .copied()
+            .unwrap_or((1.0, false))
+    }
+
+    /// Returns where in the stereo field a user is placed.
+    pub fn user_pan(&self, id: u32) -> f32 {
+        self.client_streams.lock().unwrap().pan(id)
+    }
+
+    /// Places a user in the stereo field, from -1 (left) to 1 (right).
+    pub fn set_user_pan(&self, id: u32, pan: f32) {
+        self.client_streams.lock().unwrap().set_pan(id, pan);
+    }
+
     /// Sets the incoming volume of a user.
     pub fn set_user_volume(&self, id: u32, volume: f32) {
         match self.user_volumes.lock().unwrap().entry(id) {
@@ -160,6 +449,19 @@ impl AudioOutput {
         }
     }
 
+    /// Forgets the volume, mute and pan of a user, e.g. since they left and
+    /// their session might be given to someone else.
+    pub fn remove_user(&self, id: u32) {
+        self.user_volumes.lock().unwrap().remove(&id);
+        self.client_streams.lock().unwrap().remove_pan(id);
+    }
+
+    /// Forgets the volume, mute and pan of all users.
+    pub fn clear_users(&self) {
+        self.user_volumes.lock().unwrap().clear();
+        self.client_streams.lock().unwrap().clear_pans();
+    }
+
     /// Mutes another user.
     pub fn set_mute(&self, id: u32, mute: bool) {
         match self.user_volumes.lock().unwrap().entry(id) {
@@ -172,9 +474,187 @@ impl AudioOutput {
         }
     }
 
-    /// Queues a sound effect.
+    /// Queues a sound effect, unless sound effects are disabled or the effect
+    /// is silenced.
     pub fn play_effect(&self, effect: NotificationEvents) {
-        let samples = self.sounds.get(&effect).unwrap();
-        self.client_streams.lock().unwrap().add_sound_effect(samples);
+        if !self.sound_effects_enabled {
+            return;
+        }
+        if let Some(samples) = self.sounds.get(&effect) {
+            self.client_streams.lock().unwrap().add_sound_effect(samples);
+        }
+    }
+}
+
+/// Stops looping back our own audio when it's dropped. Returned by
+/// [AudioInput::loopback].
+#[derive(Debug)]
+pub struct Loopback {
+    sender: Arc<watch::Sender<Option<Duration>>>,
+}
+
+impl Drop for Loopback {
+    fn drop(&mut self) {
+        let _ = self.sender.send(None);
+    }
+}
+
+/// Plays our own audio while it's looped back, as set by
+/// [AudioInput::loopback].
+pub async fn handle_loopback(state: Arc<RwLock<State>>) {
+    let (receiver, mut delay_receiver) = {
+        let state = state.read().unwrap();
+        let audio_input = state.audio_input();
+        (
+            Arc::clone(&audio_input.loopback_receiver),
+            audio_input.loopback_delay.1.clone(),
+        )
+    };
+    let mut receiver = receiver.lock().await;
+    // Packets waiting to be played and when to play them.
+    let mut pending: VecDeque<(Instant, Vec<u8>)> = VecDeque::new();
+    loop {
+        let next = pending.front().map(|(at, _)| *at);
+        tokio::select! {
+            packet = receiver.next() => match packet {
+                Some(packet) => {
+                    if let Some(delay) = *delay_receiver.borrow() {
+                        pending.push_back((Instant::now() + delay, packet));
+                    }
+                }
+                None => return,
+            },
+            _ = tokio::time::sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {
+                let (_, packet) = pending.pop_front().unwrap();
+                state.read().unwrap().audio_output().decode_packet_payload(
+                    VoiceStreamType::Udp,
+                    LOOPBACK_SESSION,
+                    0,
+                    VoicePacketPayload::Opus(packet.into(), false),
+                    None,
+                );
+            }
+            changed = delay_receiver.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                if delay_receiver.borrow().is_none() {
+                    pending.clear();
+                    state
+                        .read()
+                        .unwrap()
+                        .audio_output()
+                        .client_streams
+                        .lock()
+                        .unwrap()
+                        .remove_session(LOOPBACK_SESSION);
+                }
+            }
+        }
+    }
+}
+
+/// Moves the audio streams to another device when their device is lost, e.g.
+/// when a USB headset is unplugged, or when another device is configured.
+/// Streams without a device are retried until a device shows up.
+pub async fn handle_devices(state: Arc<RwLock<State>>) {
+    let (input_errors, output_errors) = {
+        let state = state.read().unwrap();
+        (
+            Arc::clone(&state.audio_input().error_receiver),
+            Arc::clone(&state.audio_output().error_receiver),
+        )
+    };
+    let mut input_errors = input_errors.lock().await;
+    let mut output_errors = output_errors.lock().await;
+    let mut device_receiver = state.read().unwrap().device_receiver();
+    let mut devices = device_receiver.borrow().clone();
+    // When to move each stream, if it should be moved.
+    let mut input_retry: Option<Instant> = None;
+    let mut output_retry: Option<Instant> = None;
+    loop {
+        let next = match (input_retry, output_retry) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        tokio::select! {
+            error = input_errors.next() => match error {
+                Some(StreamError::DeviceNotAvailable) => {
+                    if input_retry.is_none() {
+                        warn!("The input device was lost, moving the stream to another device");
+                        input_retry = Some(Instant::now() + DEVICE_RETRY_INTERVAL);
+                    }
+                }
+                Some(error) => warn!("Error on the input stream: {}", error),
+                None => return,
+            },
+            error = output_errors.next() => match error {
+                Some(StreamError::DeviceNotAvailable) => {
+                    if output_retry.is_none() {
+                        warn!("The output device was lost, moving the stream to another device");
+                        output_retry = Some(Instant::now() + DEVICE_RETRY_INTERVAL);
+                    }
+                }
+                Some(error) => warn!("Error on the output stream: {}", error),
+                None => return,
+            },
+            changed = device_receiver.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                let new_devices = device_receiver.borrow().clone();
+                if new_devices.input != devices.input {
+                    input_retry = Some(Instant::now());
+                }
+                if new_devices.output != devices.output {
+                    output_retry = Some(Instant::now());
+                }
+                devices = new_devices;
+            }
+            _ = tokio::time::sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {
+                let now = Instant::now();
+                if matches!(input_retry, Some(at) if at <= now) {
+                    input_retry = if move_stream(&state, AudioStream::Input).await {
+                        // Forget about errors from the old stream.
+                        while let Ok(Some(_)) = input_errors.try_next() {}
+                        None
+                    } else {
+                        Some(now + DEVICE_RETRY_INTERVAL)
+                    };
+                }
+                if matches!(output_retry, Some(at) if at <= now) {
+                    output_retry = if move_stream(&state, AudioStream::Output).await {
+                        while let Ok(Some(_)) = output_errors.try_next() {}
+                        None
+                    } else {
+                        Some(now + DEVICE_RETRY_INTERVAL)
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Moves an audio stream to the configured device, or the default device if
+/// there's none. Returns whether it succeeded.
+///
+/// Finding the device opens every device to list them, so it's done on a
+/// blocking thread. The stream itself can't be sent between threads and is
+/// built here, without locking the state until it replaces the old stream.
+async fn move_stream(state: &RwLock<State>, stream: AudioStream) -> bool {
+    let (builder, name) = state.read().unwrap().audio_stream_builder(stream);
+    let device = tokio::task::spawn_blocking(move || find_device(name.as_deref(), stream))
+        .await
+        .unwrap();
+    match device.and_then(|device| builder.build(&device)) {
+        Ok(new_stream) => {
+            let old_stream = state.write().unwrap().set_audio_stream(new_stream);
+            drop(old_stream);
+            true
+        }
+        Err(e) => {
+            warn!("Couldn't move the {} stream to another device: {}", stream, e);
+            false
+        }
     }
 }
@@ -0,0 +1,258 @@
+//! Converts audio between the format of a device and the format used
+//! internally.
+//!
+//! Devices that don't support [SAMPLE_RATE] are run at a different sample rate
+//! and resampled, and devices with an unsupported number of channels are mixed
+//! up or down.
+
+use crate::audio::SAMPLE_RATE;
+
+use cpal::{SampleRate, SupportedStreamConfig, SupportedStreamConfigRange};
+use dasp_interpolate::linear::Linear;
+use dasp_interpolate::Interpolator;
+use std::fmt::Debug;
+
+/// Picks the device config that needs the least conversion. The sample rate
+/// matters the most, followed by the number of channels. Among unsupported
+/// sample rates, the one closest to [SAMPLE_RATE] is used.
+pub fn select_config(
+    configs: impl Iterator<Item = SupportedStreamConfigRange>,
+    preferred_channels: &[u16],
+) -> Option<SupportedStreamConfig> {
+    configs
+        .map(|config| {
+            let sample_rate = SAMPLE_RATE.clamp(config.min_sample_rate().0, config.max_sample_rate().0);
+            config.with_sample_rate(SampleRate(sample_rate))
+        })
+        .max_by_key(|config| {
+            (
+                config.sample_rate().0 == SAMPLE_RATE,
+                preferred_channels.contains(&config.channels()),
+                -(config.sample_rate().0 as i64 - SAMPLE_RATE as i64).abs(),
+            )
+        })
+}
+
+/// The number of channels that is used internally for a device with some
+/// number of channels.
+pub fn internal_channels(device_channels: u16) -> opus::Channels {
+    if device_channels == 1 {
+        opus::Channels::Mono
+    } else {
+        opus::Channels::Stereo
+    }
+}
+
+/// Converts a stream of interleaved audio from one sample rate and number of
+/// channels to another, one buffer at a time.
+///
+/// Samples are linearly interpolated, which is good enough for voice.
+pub struct Resampler {
+    input_channels: usize,
+    output_channels: usize,
+    /// How many input frames there are per output frame.
+    step: f64,
+    /// How far between the last two input frames the next output frame is.
+    position: f64,
+    interpolators: Vec<Linear<f32>>,
+    /// The next input frame, mixed to the output channels.
+    frame: Vec<f32>,
+    /// The channel of the next input sample.
+    channel: usize,
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32, input_channels: usize, output_rate: u32, output_channels: usize) -> Self {
+        Self {
+            input_channels,
+            output_channels,
+            step: input_rate as f64 / output_rate as f64,
+            // Start by reading two input frames.
+            position: 2.0,
+            interpolators: (0..output_channels).map(|_| Linear::new(0.0, 0.0)).collect(),
+            frame: vec![0.0; output_channels],
+            channel: 0,
+        }
+    }
+
+    pub fn input_channels(&self) -> usize {
+        self.input_channels
+    }
+
+    pub fn output_channels(&self) -> usize {
+        self.output_channels
+    }
+
+    /// How many input frames are used for each output frame.
+    pub fn step(&self) -> f64 {
+        self.step
+    }
+
+    /// Whether the audio only passes through unchanged.
+    fn is_identity(&self) -> bool {
+        self.step == 1.0 && self.input_channels == self.output_channels
+    }
+
+    /// Converts some interleaved input and appends it to `output`. Input that
+    /// isn't enough for another output frame is remembered until the next
+    /// call.
+    pub fn process(&mut self, input: impl IntoIterator<Item = f32>, output: &mut Vec<f32>) {
+        if self.is_identity() {
+            output.extend(input);
+            return;
+        }
+
+        let mut input = input.into_iter();
+        loop {
+            while self.position >= 1.0 {
+                while self.channel < self.input_channels {
+                    match input.next() {
+                        Some(sample) => self.frame_input(self.channel, sample),
+                        // A partially read frame is finished in the next call.
+                        None => return,
+                    }
+                    self.channel += 1;
+                }
+                self.channel = 0;
+                self.mix_frame();
+                self.position -= 1.0;
+            }
+            for interpolator in &self.interpolators {
+                output.push(interpolator.interpolate(self.position));
+            }
+            self.position += self.step;
+        }
+    }
+
+    /// Stores one sample of the next input frame.
+    fn frame_input(&mut self, channel: usize, sample: f32) {
+        if self.input_channels == self.output_channels {
+            self.frame[channel] = sample;
+        } else if self.output_channels == 1 {
+            // Average all channels.
+            if channel == 0 {
+                self.frame[0] = 0.0;
+            }
+            self.frame[0] += sample / self.input_channels as f32;
+        } else if self.input_channels == 1 {
+            // Play mono audio on the front left and right channels only.
+            for (output_channel, frame) in self.frame.iter_mut().enumerate() {
+                *frame = if output_channel < 2 { sample } else { 0.0 };
+            }
+        } else if channel < self.output_channels {
+            // Keep the first channels, which are front left and right.
+            self.frame[channel] = sample;
+            if channel == self.input_channels - 1 {
+                self.frame[self.input_channels..].iter_mut().for_each(|e| *e = 0.0);
+            }
+        }
+    }
+
+    /// Passes a complete input frame on to the interpolators.
+    fn mix_frame(&mut self) {
+        for (interpolator, sample) in self.interpolators.iter_mut().zip(&self.frame) {
+            interpolator.next_source_frame(*sample);
+        }
+    }
+}
+
+impl Debug for Resampler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Resampler")
+            .field("input_channels", &self.input_channels)
+            .field("output_channels", &self.output_channels)
+            .field("step", &self.step)
+            .field("position", &self.position)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn resample(resampler: &mut Resampler, input: &[f32]) -> Vec<f32> {
+        let mut output = Vec::new();
+        resampler.process(input.iter().copied(), &mut output);
+        output
+    }
+
+    #[test]
+    fn passes_matching_audio_through() {
+        let input: Vec<f32> = (0..100).map(|i| i as f32 / 100.0).collect();
+        let mut resampler = Resampler::new(SAMPLE_RATE, 2, SAMPLE_RATE, 2);
+        assert_eq!(resample(&mut resampler, &input), input);
+    }
+
+    #[test]
+    fn keeps_frequency_when_resampling() {
+        const FREQUENCY: f32 = 1000.0;
+        let sine = |rate: u32, i: usize| (2.0 * PI * FREQUENCY * i as f32 / rate as f32).sin();
+        let input: Vec<f32> = (0..44100).map(|i| sine(44100, i)).collect();
+
+        let mut resampler = Resampler::new(44100, 1, SAMPLE_RATE, 1);
+        let mut output = Vec::new();
+        // Odd chunk sizes, so that the position carries over between calls.
+        for chunk in input.chunks(441 * 3 + 7) {
+            resampler.process(chunk.iter().copied(), &mut output);
+        }
+
+        assert!(
+            (output.len() as i64 - SAMPLE_RATE as i64).abs() <= 2,
+            "{} output frames for a second of input",
+            output.len()
+        );
+        for (i, sample) in output.iter().enumerate() {
+            assert!(
+                (sample - sine(SAMPLE_RATE, i)).abs() < 0.01,
+                "frame {} is {} instead of {}",
+                i,
+                sample,
+                sine(SAMPLE_RATE, i)
+            );
+        }
+    }
+
+    #[test]
+    fn splits_frames_between_calls() {
+        let input: Vec<f32> = (0..2000).map(|i| (i as f32 * 0.01).sin()).collect();
+        let whole = resample(&mut Resampler::new(32000, 2, SAMPLE_RATE, 2), &input);
+
+        let mut resampler = Resampler::new(32000, 2, SAMPLE_RATE, 2);
+        let mut split = Vec::new();
+        // An odd chunk size splits stereo frames in the middle.
+        for chunk in input.chunks(333) {
+            resampler.process(chunk.iter().copied(), &mut split);
+        }
+        assert_eq!(split, whole);
+    }
+
+    #[test]
+    fn mixes_channels() {
+        // Each output frame is interpolated towards the next input frame, so
+        // the last input frame is held back until more input arrives.
+        let mut stereo_to_mono = Resampler::new(SAMPLE_RATE, 2, SAMPLE_RATE, 1);
+        assert_eq!(
+            resample(&mut stereo_to_mono, &[0.2, 0.4, 0.6, 1.0, 0.0, 0.0]),
+            vec![0.3, 0.8],
+        );
+
+        let mut mono_to_stereo = Resampler::new(SAMPLE_RATE, 1, SAMPLE_RATE, 2);
+        assert_eq!(
+            resample(&mut mono_to_stereo, &[0.5, 0.25, 0.0]),
+            vec![0.5, 0.5, 0.25, 0.25],
+        );
+
+        let mut surround_to_stereo = Resampler::new(SAMPLE_RATE, 6, SAMPLE_RATE, 2);
+        let frame = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6];
+        let input: Vec<f32> = frame.iter().chain(&frame).copied().collect();
+        assert_eq!(resample(&mut surround_to_stereo, &input), vec![0.1, 0.2]);
+
+        let mut stereo_to_surround = Resampler::new(SAMPLE_RATE, 2, SAMPLE_RATE, 6);
+        assert_eq!(
+            resample(&mut stereo_to_surround, &[0.1, 0.2, 0.1, 0.2]),
+            vec![0.1, 0.2, 0.0, 0.0, 0.0, 0.0],
+        );
+    }
+}
@@ -0,0 +1,152 @@
+//! Ducking, where other users are made quieter while some users are talking.
+
+use crate::audio::SAMPLE_RATE;
+
+use mumlib::config::DuckingConfig;
+
+/// The level above which a user is considered to be talking, around -50 dBFS.
+const TALKING_LEVEL: f32 = 0.003;
+/// How many dB per second the volume is lowered when ducking starts.
+const ATTACK_RATE: f32 = 400.0;
+/// How many dB per second the volume is raised when ducking stops.
+const RELEASE_RATE: f32 = 60.0;
+
+/// Whether some audio is loud enough to be considered talking.
+pub fn is_talking(samples: impl IntoIterator<Item = f32>) -> bool {
+    samples.into_iter().any(|sample| sample.abs() > TALKING_LEVEL)
+}
+
+/// Keeps track of how much other users should currently be ducked.
+#[derive(Debug)]
+pub struct Ducker {
+    /// How much quieter other users are made in dB, or None if ducking is
+    /// disabled.
+    amount: Option<f32>,
+    /// How many frames other users stay ducked after the talking stopped.
+    hold: usize,
+    sound_effects: bool,
+    /// How much other users are currently ducked in dB.
+    reduction: f32,
+    /// How many frames are left until the volume starts to rise again.
+    hold_left: usize,
+}
+
+impl Ducker {
+    pub fn new(config: Option<&DuckingConfig>) -> Self {
+        let hold = config.and_then(|config| config.hold).unwrap_or(500.0).max(0.0);
+        Self {
+            amount: config.map(|config| config.amount.unwrap_or(12.0).max(0.0)),
+            hold: (hold / 1000.0 * SAMPLE_RATE as f32) as usize,
+            sound_effects: config
+                .and_then(|config| config.sound_effects)
+                .unwrap_or(false),
+            reduction: 0.0,
+            hold_left: 0,
+        }
+    }
+
+    /// Whether other users are ducked while sound effects are played.
+    pub fn ducks_sound_effects(&self) -> bool {
+        self.amount.is_some() && self.sound_effects
+    }
+
+    /// Moves some frames forward, where someone who ducks other users is
+    /// talking if `talking` is true. Returns the gain of other users at the
+    /// start and the end of the frames.
+    pub fn advance(&mut self, talking: bool, frames: usize) -> (f32, f32) {
+        let amount = match self.amount {
+            Some(amount) => amount,
+            None => return (1.0, 1.0),
+        };
+        let start = self.reduction;
+        let seconds = frames as f32 / SAMPLE_RATE as f32;
+        if talking {
+            self.hold_left = self.hold;
+            self.reduction = (self.reduction + ATTACK_RATE * seconds).min(amount);
+        } else if self.hold_left > 0 {
+            self.hold_left = self.hold_left.saturating_sub(frames);
+            self.reduction = self.reduction.min(amount);
+        } else {
+            self.reduction = (self.reduction - RELEASE_RATE * seconds).max(0.0);
+        }
+        (db_to_gain(start), db_to_gain(self.reduction))
+    }
+}
+
+impl Default for Ducker {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10.0_f32.powf(-db / 20.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// 10 ms of audio.
+    const FRAMES: usize = SAMPLE_RATE as usize / 100;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4, "{} is not {}", a, b);
+    }
+
+    #[test]
+    fn disabled_ducker_keeps_volume() {
+        let mut ducker = Ducker::default();
+        assert_eq!(ducker.advance(true, FRAMES), (1.0, 1.0));
+        assert!(!ducker.ducks_sound_effects());
+    }
+
+    #[test]
+    fn ducks_holds_and_releases() {
+        let mut ducker = Ducker::new(Some(&DuckingConfig {
+            amount: Some(12.0),
+            hold: Some(100.0),
+            ..DuckingConfig::default()
+        }));
+        let ducked = db_to_gain(12.0);
+
+        // The volume is lowered by 4 dB per 10 ms until it's 12 dB lower.
+        let mut gain = 1.0;
+        for db in &[4.0, 8.0, 12.0, 12.0] {
+            let (start, end) = ducker.advance(true, FRAMES);
+            assert_close(start, gain);
+            assert_close(end, db_to_gain(*db));
+            gain = end;
+        }
+
+        // It stays lowered for 100 ms after the talking stopped.
+        for _ in 0..10 {
+            assert_eq!(ducker.advance(false, FRAMES), (ducked, ducked));
+        }
+
+        // Then it's raised by 0.6 dB per 10 ms.
+        let (start, end) = ducker.advance(false, FRAMES);
+        assert_close(start, ducked);
+        assert_close(end, db_to_gain(11.4));
+        for _ in 0..19 {
+            ducker.advance(false, FRAMES);
+        }
+        assert_eq!(ducker.advance(false, FRAMES).1, 1.0);
+    }
+
+    #[test]
+    fn talking_restarts_hold() {
+        let mut ducker = Ducker::new(Some(&DuckingConfig {
+            amount: Some(6.0),
+            hold: Some(50.0),
+            ..DuckingConfig::default()
+        }));
+        let ducked = db_to_gain(6.0);
+        ducker.advance(true, FRAMES * 2);
+        ducker.advance(false, FRAMES * 4);
+        ducker.advance(true, FRAMES);
+        ducker.advance(false, FRAMES * 4);
+        assert_eq!(ducker.advance(false, FRAMES).1, ducked);
+        assert!(ducker.advance(false, FRAMES).1 > ducked);
+    }
+}
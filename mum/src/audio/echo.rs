@@ -0,0 +1,521 @@
+//! Removes the audio we play from what the microphone picks up.
+//!
+//! The output stream writes everything it plays to an [EchoReference], which
+//! the [EchoCanceller] in the input stream reads from. Since the two streams
+//! run independently, the delay between a sample being played and it being
+//! picked up by the microphone is estimated continuously.
+
+use crate::audio::transformers::Transformer;
+use crate::audio::SAMPLE_RATE;
+
+use log::*;
+use realfft::num_complex::Complex;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+
+/// How many samples of played audio are kept. Needs to cover both the history
+/// used for delay estimation and the maximum delay.
+const REFERENCE_CAPACITY: usize = SAMPLE_RATE as usize * 3;
+/// The number of samples the echo canceller processes at a time. 5 ms.
+const BLOCK_SIZE: usize = 240;
+/// How many blocks of played audio the energy is kept of.
+const REFERENCE_BLOCKS: usize = REFERENCE_CAPACITY / BLOCK_SIZE;
+const FFT_SIZE: usize = BLOCK_SIZE * 2;
+/// How many blocks are used to estimate the delay. 1 s.
+const DELAY_HISTORY: usize = 200;
+/// How far ahead of the estimated delay the adaptive filter starts, to leave
+/// room for jitter between the streams.
+const DELAY_MARGIN: u64 = 2 * BLOCK_SIZE as u64;
+/// How far the read position may drift from the estimated delay before it's
+/// reset. Needs to be larger than the buffers of the audio streams.
+const DRIFT_TOLERANCE: u64 = SAMPLE_RATE as u64 / 20;
+/// The minimum correlation between the played and recorded audio for a delay
+/// estimate to be trusted.
+const MIN_CORRELATION: f64 = 0.4;
+
+/// The most recently played audio, shared between the output stream and the
+/// input stream. The audio is stored as mono.
+#[derive(Clone, Debug)]
+pub struct EchoReference(Arc<Mutex<ReferenceBuffer>>);
+
+#[derive(Debug)]
+struct ReferenceBuffer {
+    samples: Vec<f32>,
+    /// The energy of each complete block of samples, indexed by the position
+    /// of the block divided by [BLOCK_SIZE].
+    block_energy: Vec<f64>,
+    /// The energy and number of samples of the block that is being written.
+    current_energy: f64,
+    current_samples: usize,
+    /// The number of samples that have been written in total.
+    written: u64,
+}
+
+impl EchoReference {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(ReferenceBuffer {
+            samples: vec![0.0; REFERENCE_CAPACITY],
+            block_energy: vec![0.0; REFERENCE_BLOCKS],
+            current_energy: 0.0,
+            current_samples: 0,
+            written: 0,
+        })))
+    }
+
+    /// Adds some samples that are about to be played.
+    pub fn push(&self, samples: impl Iterator<Item = f32>) {
+        let mut buffer = self.0.lock().unwrap();
+        for sample in samples {
+            let index = (buffer.written % REFERENCE_CAPACITY as u64) as usize;
+            buffer.samples[index] = sample;
+            buffer.current_energy += (sample * sample) as f64;
+            buffer.current_samples += 1;
+            buffer.written += 1;
+            if buffer.current_samples == BLOCK_SIZE {
+                let block = (buffer.written / BLOCK_SIZE as u64 - 1) as usize % REFERENCE_BLOCKS;
+                buffer.block_energy[block] = buffer.current_energy;
+                buffer.current_energy = 0.0;
+                buffer.current_samples = 0;
+            }
+        }
+    }
+}
+
+impl Default for EchoReference {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReferenceBuffer {
+    fn available(&self, position: u64) -> bool {
+        position < self.written && position + REFERENCE_CAPACITY as u64 > self.written
+    }
+
+    /// Copies samples starting at `start`. Samples that haven't been written
+    /// yet or have been overwritten are silent.
+    fn read(&self, start: u64, out: &mut [f32]) {
+        for (position, out) in (start..).zip(out.iter_mut()) {
+            *out = if self.available(position) {
+                self.samples[(position % REFERENCE_CAPACITY as u64) as usize]
+            } else {
+                0.0
+            };
+        }
+    }
+}
+
+/// A transform that removes echo of the audio that is played, using an
+/// adaptive filter in the frequency domain (a partitioned block frequency
+/// domain adaptive filter).
+///
+/// The filter models how the played audio reaches the microphone. Adaptation is
+/// paused while the microphone is louder than the played audio, since the
+/// local speaker would otherwise be modeled as echo. The audio is delayed by
+/// one block.
+pub struct EchoCanceller {
+    reference: EchoReference,
+    max_delay: u64,
+
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    fft_input: Vec<f32>,
+    fft_output: Vec<Complex<f32>>,
+
+    /// The estimated delay between a sample being played and it being
+    /// recorded, in samples.
+    delay: u64,
+    /// Where the next block of played audio is read from.
+    position: Option<u64>,
+    /// The energy of recorded blocks and how much had been played when they
+    /// were recorded, used to estimate the delay.
+    history: VecDeque<(f64, u64)>,
+    blocks_since_estimate: usize,
+    /// A copy of the energy of the played blocks, taken so that the delay can
+    /// be estimated without holding the lock of the reference.
+    played_energy: Vec<f64>,
+    /// How many blocks had been played when the copy was taken.
+    played_blocks: u64,
+    /// Scratch buffers for the delay estimation.
+    recorded_levels: Vec<f64>,
+    played_levels: Vec<f64>,
+
+    /// The previous and current block of played audio.
+    reference_block: Vec<f32>,
+    /// The spectra of the latest blocks of played audio, newest first.
+    reference_spectra: VecDeque<Vec<Complex<f32>>>,
+    /// The peak level of the played audio of the latest blocks, newest first.
+    reference_peaks: VecDeque<f32>,
+    channels: Vec<EchoChannel>,
+
+    /// Scratch buffers for processing a block.
+    normalization: Vec<f32>,
+    error_spectrum: Vec<Complex<f32>>,
+}
+
+/// The state of the echo canceller for a single channel.
+struct EchoChannel {
+    input: Vec<f32>,
+    output: VecDeque<f32>,
+    /// The frequency response of the echo path, one per partition.
+    weights: Vec<Vec<Complex<f32>>>,
+}
+
+impl EchoChannel {
+    fn new(partitions: usize) -> Self {
+        Self {
+            input: Vec::with_capacity(BLOCK_SIZE),
+            output: vec![0.0; BLOCK_SIZE].into(),
+            weights: vec![vec![Complex::default(); FFT_SIZE / 2 + 1]; partitions],
+        }
+    }
+}
+
+impl EchoCanceller {
+    /// Create a new echo canceller. `filter_length` is the length of the echo
+    /// that can be removed and `max_delay` is the largest delay that is
+    /// detected, both in milliseconds.
+    pub fn new(reference: EchoReference, filter_length: f32, max_delay: f32) -> Self {
+        let samples_per_ms = SAMPLE_RATE as f32 / 1000.0;
+        let partitions = ((filter_length * samples_per_ms / BLOCK_SIZE as f32).ceil() as usize).max(1);
+        // The delay estimation only looks at history that is still available.
+        let max_delay = (max_delay * samples_per_ms) as u64;
+        let max_delay = max_delay.min((REFERENCE_CAPACITY - (DELAY_HISTORY + 2) * BLOCK_SIZE) as u64);
+
+        let mut planner = RealFftPlanner::new();
+        let forward = planner.plan_fft_forward(FFT_SIZE);
+        let inverse = planner.plan_fft_inverse(FFT_SIZE);
+        Self {
+            reference,
+            max_delay,
+            fft_input: forward.make_input_vec(),
+            fft_output: forward.make_output_vec(),
+            forward,
+            inverse,
+            delay: 0,
+            position: None,
+            history: VecDeque::with_capacity(DELAY_HISTORY),
+            blocks_since_estimate: 0,
+            played_energy: vec![0.0; REFERENCE_BLOCKS],
+            played_blocks: 0,
+            recorded_levels: Vec::with_capacity(DELAY_HISTORY),
+            played_levels: Vec::with_capacity(DELAY_HISTORY),
+            reference_block: vec![0.0; FFT_SIZE],
+            reference_spectra: vec![vec![Complex::default(); FFT_SIZE / 2 + 1]; partitions].into(),
+            reference_peaks: vec![0.0; partitions].into(),
+            channels: Vec::new(),
+            normalization: vec![0.0; FFT_SIZE / 2 + 1],
+            error_spectrum: vec![Complex::default(); FFT_SIZE / 2 + 1],
+        }
+    }
+
+    /// Estimates the delay by finding the delay at which the energy of the
+    /// played audio correlates the most with the energy of the recorded audio.
+    /// Uses the copy of the played energy in `played_energy`.
+    fn estimate_delay(&mut self) {
+        self.recorded_levels.clear();
+        self.recorded_levels
+            .extend(self.history.iter().map(|(energy, _)| energy.sqrt()));
+        // Blocks older than this have been overwritten.
+        let oldest_block = self.played_blocks.saturating_sub(REFERENCE_BLOCKS as u64);
+        let mut best = None;
+        'delays: for delay in (0..=self.max_delay).step_by(BLOCK_SIZE) {
+            self.played_levels.clear();
+            for (_, written) in &self.history {
+                // The last complete block that had been played `delay`
+                // samples before the recorded block.
+                let block = match (written.saturating_sub(delay) / BLOCK_SIZE as u64).checked_sub(1) {
+                    Some(block) if block >= oldest_block => block,
+                    _ => continue 'delays,
+                };
+                self.played_levels
+                    .push(self.played_energy[block as usize % REFERENCE_BLOCKS].sqrt());
+            }
+            let correlation = match correlation(&self.recorded_levels, &self.played_levels) {
+                Some(correlation) => correlation,
+                None => continue,
+            };
+            if best.map(|(_, best)| correlation > best).unwrap_or(true) {
+                best = Some((delay, correlation));
+            }
+        }
+
+        if let Some((delay, correlation)) = best {
+            if correlation > MIN_CORRELATION && delay != self.delay {
+                debug!("Estimated echo delay {} ms (correlation {:.2})", delay * 1000 / SAMPLE_RATE as u64, correlation);
+                // A large change means that the filter is modeling the wrong
+                // part of the played audio.
+                if delay.abs_diff(self.delay) > DELAY_MARGIN {
+                    self.reset_filter();
+                }
+                self.delay = delay;
+            }
+        }
+    }
+
+    fn reset_filter(&mut self) {
+        self.position = None;
+        let partitions = self.reference_spectra.len();
+        for channel in &mut self.channels {
+            *channel = EchoChannel::new(partitions);
+        }
+    }
+
+    /// Reads the next block of played audio and updates the delay estimate.
+    fn next_reference_block(&mut self, recorded_energy: f64) {
+        let reference = self.reference.clone();
+        let buffer = reference.0.lock().unwrap();
+
+        if self.history.len() == DELAY_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back((recorded_energy, buffer.written));
+        self.blocks_since_estimate += 1;
+        let estimate = self.history.len() == DELAY_HISTORY && self.blocks_since_estimate >= DELAY_HISTORY;
+        if estimate {
+            // The output stream waits for the lock, so only copy what's needed
+            // and estimate the delay after releasing it.
+            self.blocks_since_estimate = 0;
+            self.played_energy.copy_from_slice(&buffer.block_energy);
+            self.played_blocks = buffer.written / BLOCK_SIZE as u64;
+        }
+
+        let target = buffer
+            .written
+            .saturating_sub(self.delay.saturating_sub(DELAY_MARGIN) + BLOCK_SIZE as u64);
+        let position = match self.position {
+            Some(position) if position.abs_diff(target) <= DRIFT_TOLERANCE => position,
+            _ => target,
+        };
+        self.position = Some(position + BLOCK_SIZE as u64);
+
+        self.reference_block.copy_within(BLOCK_SIZE.., 0);
+        buffer.read(position, &mut self.reference_block[BLOCK_SIZE..]);
+        drop(buffer);
+
+        if estimate {
+            self.estimate_delay();
+        }
+    }
+
+    /// Removes the echo from one block of every channel.
+    fn process_block(&mut self) {
+        const STEP_SIZE: f32 = 0.5;
+        /// Regularizes the step size when almost nothing is played.
+        const REGULARIZATION: f32 = FFT_SIZE as f32 * 1e-5;
+        /// Adaptation is paused while the recorded audio is louder than this
+        /// factor times the played audio.
+        const DOUBLE_TALK: f32 = 0.5;
+
+        let recorded_energy = self
+            .channels
+            .iter()
+            .flat_map(|channel| &channel.input)
+            .map(|sample| (sample * sample) as f64)
+            .sum();
+        self.next_reference_block(recorded_energy);
+
+        self.fft_input.copy_from_slice(&self.reference_block);
+        self.forward
+            .process(&mut self.fft_input, &mut self.fft_output)
+            .unwrap();
+        let mut spectrum = self.reference_spectra.pop_back().unwrap();
+        spectrum.copy_from_slice(&self.fft_output);
+        self.reference_spectra.push_front(spectrum);
+        self.reference_peaks.pop_back();
+        self.reference_peaks.push_front(
+            self.reference_block[BLOCK_SIZE..]
+                .iter()
+                .map(|e| e.abs())
+                .fold(0.0, f32::max),
+        );
+
+        let reference_peak = self.reference_peaks.iter().copied().fold(0.0, f32::max);
+        for (bin, normalization) in self.normalization.iter_mut().enumerate() {
+            let power: f32 = self.reference_spectra.iter().map(|spectrum| spectrum[bin].norm_sqr()).sum();
+            *normalization = STEP_SIZE / (power + REGULARIZATION);
+        }
+        let scale = 1.0 / FFT_SIZE as f32;
+
+        for channel in &mut self.channels {
+            // Estimate the echo.
+            self.fft_output.iter_mut().for_each(|e| *e = Complex::default());
+            for (weights, spectrum) in channel.weights.iter().zip(&self.reference_spectra) {
+                for ((out, weight), value) in self.fft_output.iter_mut().zip(weights).zip(spectrum) {
+                    *out += weight * value;
+                }
+            }
+            inverse(&*self.inverse, &mut self.fft_output, &mut self.fft_input);
+
+            let mut recorded_peak: f32 = 0.0;
+            let mut error = [0.0; BLOCK_SIZE];
+            for ((error, input), echo) in error.iter_mut().zip(&channel.input).zip(&self.fft_input[BLOCK_SIZE..]) {
+                recorded_peak = recorded_peak.max(input.abs());
+                *error = input - echo * scale;
+            }
+            channel.output.extend(&error);
+            channel.input.clear();
+
+            if reference_peak == 0.0 || recorded_peak > DOUBLE_TALK * reference_peak {
+                continue;
+            }
+
+            // Adapt the filter towards the remaining echo.
+            self.fft_input[..BLOCK_SIZE].iter_mut().for_each(|e| *e = 0.0);
+            self.fft_input[BLOCK_SIZE..].copy_from_slice(&error);
+            self.forward
+                .process(&mut self.fft_input, &mut self.fft_output)
+                .unwrap();
+            self.error_spectrum.copy_from_slice(&self.fft_output);
+            for (weights, spectrum) in channel.weights.iter_mut().zip(&self.reference_spectra) {
+                for (((out, value), error), normalization) in self
+                    .fft_output
+                    .iter_mut()
+                    .zip(spectrum)
+                    .zip(&self.error_spectrum)
+                    .zip(&self.normalization)
+                {
+                    *out = value.conj() * error * *normalization;
+                }
+                // Only the first half of the filter in the time domain is
+                // valid. Constraining the update keeps it that way.
+                inverse(&*self.inverse, &mut self.fft_output, &mut self.fft_input);
+                self.fft_input[BLOCK_SIZE..].iter_mut().for_each(|e| *e = 0.0);
+                self.fft_input[..BLOCK_SIZE].iter_mut().for_each(|e| *e *= scale);
+                self.forward
+                    .process(&mut self.fft_input, &mut self.fft_output)
+                    .unwrap();
+                for (weight, update) in weights.iter_mut().zip(&self.fft_output) {
+                    *weight += update;
+                }
+            }
+        }
+    }
+}
+
+impl Transformer for EchoCanceller {
+    fn transform<'a>(&mut self, (channels, buf): (opus::Channels, &'a mut [f32])) -> Option<(opus::Channels, &'a mut [f32])> {
+        let num_channels = channels as usize;
+        if self.channels.len() != num_channels {
+            let partitions = self.reference_spectra.len();
+            self.channels = (0..num_channels).map(|_| EchoChannel::new(partitions)).collect();
+        }
+
+        for frame in buf.chunks(num_channels) {
+            for (channel, sample) in self.channels.iter_mut().zip(frame) {
+                channel.input.push(*sample);
+            }
+            if self.channels[0].input.len() == BLOCK_SIZE {
+                self.process_block();
+            }
+        }
+        for frame in buf.chunks_mut(num_channels) {
+            for (channel, sample) in self.channels.iter_mut().zip(frame) {
+                *sample = channel.output.pop_front().unwrap_or(0.0);
+            }
+        }
+        Some((channels, buf))
+    }
+}
+
+impl Debug for EchoCanceller {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EchoCanceller")
+            .field("reference", &"EchoReference")
+            .field("max_delay", &self.max_delay)
+            .field("delay", &self.delay)
+            .field("position", &self.position)
+            .finish()
+    }
+}
+
+/// Does an inverse FFT, which requires the imaginary parts of the first and
+/// last values to be zero.
+fn inverse(fft: &dyn ComplexToReal<f32>, input: &mut [Complex<f32>], output: &mut [f32]) {
+    input[0].im = 0.0;
+    input[input.len() - 1].im = 0.0;
+    fft.process(input, output).unwrap();
+}
+
+/// The Pearson correlation coefficient between two series, if neither is
+/// constant.
+fn correlation(a: &[f64], b: &[f64]) -> Option<f64> {
+    let n = a.len() as f64;
+    let mean_a = a.iter().sum::<f64>() / n;
+    let mean_b = b.iter().sum::<f64>() / n;
+    let (mut covariance, mut variance_a, mut variance_b) = (0.0, 0.0, 0.0);
+    for (a, b) in a.iter().zip(b) {
+        covariance += (a - mean_a) * (b - mean_b);
+        variance_a += (a - mean_a) * (a - mean_a);
+        variance_b += (b - mean_b) * (b - mean_b);
+    }
+    if variance_a <= f64::EPSILON || variance_b <= f64::EPSILON {
+        None
+    } else {
+        Some(covariance / (variance_a * variance_b).sqrt())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Noise whose level changes every 20 ms, so that the delay can be
+    /// estimated from the energy.
+    fn played_audio(len: usize) -> Vec<f32> {
+        let mut seed: u32 = 1;
+        let mut random = move || {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            (seed >> 8) as f32 / (1 << 24) as f32
+        };
+        let mut level = 0.0;
+        (0..len)
+            .map(|i| {
+                if i % 960 == 0 {
+                    level = 0.1 + 0.4 * random();
+                }
+                level * (random() * 2.0 - 1.0)
+            })
+            .collect()
+    }
+
+    fn energy(samples: &[f32]) -> f32 {
+        samples.iter().map(|sample| sample * sample).sum()
+    }
+
+    #[test]
+    fn removes_delayed_echo() {
+        const DELAY: usize = 4800;
+        const CHUNK: usize = 480;
+        let played = played_audio(SAMPLE_RATE as usize * 8);
+        let recorded: Vec<f32> = (0..played.len())
+            .map(|i| i.checked_sub(DELAY).map_or(0.0, |i| 0.5 * played[i]))
+            .collect();
+
+        let reference = EchoReference::new();
+        let mut canceller = EchoCanceller::new(reference.clone(), 50.0, 500.0);
+        let mut output = Vec::new();
+        for (played, recorded) in played.chunks(CHUNK).zip(recorded.chunks(CHUNK)) {
+            reference.push(played.iter().copied());
+            let mut buf = recorded.to_vec();
+            canceller.transform((opus::Channels::Mono, &mut buf));
+            output.extend(buf);
+        }
+
+        // The second block of each chunk is processed when a block more has
+        // been played, which makes the estimate up to a block too large.
+        let estimated = canceller.delay as usize;
+        assert!(
+            (DELAY..=DELAY + BLOCK_SIZE).contains(&estimated),
+            "estimated delay {} instead of {}",
+            estimated,
+            DELAY
+        );
+
+        let last_second = recorded.len() - SAMPLE_RATE as usize..;
+        let attenuation = 10.0 * (energy(&output[last_second.clone()]) / energy(&recorded[last_second])).log10();
+        assert!(attenuation < -20.0, "echo only attenuated by {} dB", attenuation);
+    }
+}
@@ -1,44 +1,234 @@
 //! Listens to the microphone and sends it to the networking.
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{InputCallbackInfo, Sample, SampleFormat, SampleRate, StreamConfig};
+use audiopus::coder::Encoder;
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{InputCallbackInfo, Sample, SampleFormat, StreamConfig, StreamError};
 use log::*;
+use mumlib::config::{AudioConfig, TransformerConfig};
 use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::watch;
 
 use crate::audio::SAMPLE_RATE;
-use crate::audio::transformers::{NoiseGate, Transformer};
+use crate::audio::conversion::{self, Resampler};
+use crate::audio::echo::EchoReference;
+use crate::audio::transformers::{self, InputLevel, TransformerChain, TransformerConfigs};
 use crate::error::{AudioError, AudioStream};
 use crate::state::StatePhase;
 
-/// Generates a callback that receives [Sample]s and sends them as floats to a [futures_channel::mpsc::Sender].
+/// The largest encoded packet, as recommended by the Opus documentation.
+const MAX_PACKET_SIZE: usize = 4000;
+
+/// How outgoing audio is encoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EncoderSettings {
+    /// The bitrate in bits per second.
+    pub bitrate: u32,
+    /// How many 10 ms frames are sent in each packet.
+    pub frames_per_packet: u32,
+    /// Whether in-band forward error correction is used.
+    pub fec: bool,
+    /// The expected packet loss in percent.
+    pub packet_loss: u8,
+    /// Whether a variable bitrate is used.
+    pub vbr: bool,
+    /// The complexity of the encoder, from 0 to 10.
+    pub complexity: u8,
+    /// Whether discontinuous transmission is used, where silent frames aren't
+    /// sent.
+    pub dtx: bool,
+}
+
+/// An encoded packet that should be sent to the server.
+#[derive(Debug)]
+pub struct EncodedPacket {
+    /// How many 10 ms frames were skipped since the previous packet because
+    /// of discontinuous transmission. They still count towards the sequence
+    /// number.
+    pub skipped_frames: u64,
+    /// The encoded Opus audio.
+    pub payload: Vec<u8>,
+}
+
+impl EncoderSettings {
+    const DEFAULT_BITRATE: u32 = 40000;
+    const MIN_BITRATE: u32 = 6000;
+    const MAX_BITRATE: u32 = 510000;
+    const MAX_COMPLEXITY: u8 = 10;
+
+    /// Returns the configured encoder settings. The bitrate is lowered so that
+    /// the total bandwidth, including the overhead of each packet, doesn't
+    /// exceed the maximum bandwidth of the server.
+    pub fn new(config: &AudioConfig, max_bandwidth: Option<u32>) -> Self {
+        let frames_per_packet = match config.frames_per_packet {
+            None => 1,
+            Some(frames) if [1, 2, 4, 6].contains(&frames) => frames,
+            Some(frames) => {
+                warn!("Unsupported number of frames per packet {}, expected 1, 2, 4 or 6", frames);
+                1
+            }
+        };
+        let mut bitrate = config.bitrate.unwrap_or(Self::DEFAULT_BITRATE);
+        if let Some(max_bandwidth) = max_bandwidth {
+            // IP, UDP, crypt and voice packet headers, sent once per packet.
+            let overhead = (20 + 8 + 4 + 1 + 2 + frames_per_packet) * 800 / frames_per_packet;
+            let max_bitrate = max_bandwidth.saturating_sub(overhead);
+            if bitrate > max_bitrate {
+                info!(
+                    "Lowering bitrate from {} to {} to stay within the server's maximum bandwidth of {}",
+                    bitrate, max_bitrate, max_bandwidth,
+                );
+                bitrate = max_bitrate;
+            }
+        }
+        Self {
+            bitrate: bitrate.clamp(Self::MIN_BITRATE, Self::MAX_BITRATE),
+            frames_per_packet,
+            fec: config.fec.unwrap_or(false),
+            packet_loss: config.packet_loss.unwrap_or(0).min(100),
+            vbr: config.vbr.unwrap_or(true),
+            complexity: config.complexity.unwrap_or(Self::MAX_COMPLEXITY).min(Self::MAX_COMPLEXITY),
+            dtx: config.dtx.unwrap_or(false),
+        }
+    }
+
+    /// How many samples per channel are encoded at a time.
+    fn frame_size(&self) -> usize {
+        (SAMPLE_RATE / 100 * self.frames_per_packet) as usize
+    }
+
+    fn apply(&self, encoder: &mut Encoder) -> audiopus::Result<()> {
+        encoder.set_bitrate(audiopus::Bitrate::BitsPerSecond(self.bitrate as i32))?;
+        encoder.set_vbr(self.vbr)?;
+        encoder.set_inband_fec(self.fec)?;
+        encoder.set_packet_loss_perc(self.packet_loss)?;
+        encoder.set_complexity(self.complexity)?;
+        encoder.set_dtx(self.dtx)
+    }
+}
+
+/// Where encoded audio is sent. It's sent over the network while connected,
+/// unless it's looped back to our own output.
+#[derive(Clone, Debug)]
+pub struct EncodedSink {
+    network_sender: futures_channel::mpsc::Sender<EncodedPacket>,
+    loopback_sender: futures_channel::mpsc::Sender<Vec<u8>>,
+    /// How long looped back audio is delayed, or None if it isn't looped
+    /// back.
+    loopback_delay: watch::Receiver<Option<Duration>>,
+    phase_watcher: watch::Receiver<StatePhase>,
+}
+
+impl EncodedSink {
+    /// Whether encoded audio is currently wanted anywhere.
+    fn is_open(&self) -> bool {
+        self.loopback_delay.borrow().is_some()
+            || matches!(&*self.phase_watcher.borrow(), StatePhase::Connected(_))
+    }
+
+    fn send(&mut self, packet: EncodedPacket) {
+        let res = if self.loopback_delay.borrow().is_some() {
+            self.loopback_sender.try_send(packet.payload).map_err(|e| e.to_string())
+        } else {
+            self.network_sender.try_send(packet).map_err(|e| e.to_string())
+        };
+        if let Err(e) = res {
+            warn!("Error sending audio: {}", e);
+        }
+    }
+}
+
+/// Generates a callback that receives [Sample]s, encodes them and sends them
+/// to an [EncodedSink].
+///
+/// The samples are converted from the format of the device to [SAMPLE_RATE]
+/// and mono or stereo by `resampler`.
 pub fn callback<T: Sample>(
-    mut input_sender: futures_channel::mpsc::Sender<Vec<u8>>,
-    mut transformers: Vec<Box<dyn Transformer + Send + 'static>>,
-    mut opus_encoder: opus::Encoder,
-    buffer_size: usize,
+    mut sink: EncodedSink,
+    mut transformers: TransformerChain,
+    level_sender: Arc<watch::Sender<InputLevel>>,
+    mut resampler: Resampler,
+    encoder_receiver: watch::Receiver<EncoderSettings>,
     input_volume_receiver: watch::Receiver<f32>,
-    phase_watcher: watch::Receiver<StatePhase>,
 ) -> impl FnMut(&[T], &InputCallbackInfo) + Send + 'static {
+    let channels = conversion::internal_channels(resampler.output_channels() as u16);
+    let mut opus_encoder = Encoder::new(
+        audiopus::SampleRate::Hz48000,
+        match channels {
+            opus::Channels::Mono => audiopus::Channels::Mono,
+            opus::Channels::Stereo => audiopus::Channels::Stereo,
+        },
+        audiopus::Application::Voip,
+    )
+    .unwrap();
+    let mut packet = [0; MAX_PACKET_SIZE];
+    // Frames that weren't sent because of discontinuous transmission since the
+    // last packet.
+    let mut skipped_frames = 0;
+    let mut resampled = Vec::new();
+    let mut encoder_settings = *encoder_receiver.borrow();
+    if let Err(e) = encoder_settings.apply(&mut opus_encoder) {
+        error!("Couldn't configure the encoder: {}", e);
+    }
+    let mut buffer_size = encoder_settings.frame_size() * channels as usize;
     let mut buffer = Vec::with_capacity(buffer_size);
 
     move |data: &[T], _info: &InputCallbackInfo| {
-        if !matches!(&*phase_watcher.borrow(), StatePhase::Connected(_)) {
-            return;
+        // The audio is processed even while disconnected so that the level
+        // meter can be used to calibrate the noise gate.
+        let open = sink.is_open();
+        let transformers = transformers.transformers();
+        {
+            let new_settings = *encoder_receiver.borrow();
+            if new_settings != encoder_settings {
+                encoder_settings = new_settings;
+                if let Err(e) = encoder_settings.apply(&mut opus_encoder) {
+                    error!("Couldn't configure the encoder: {}", e);
+                }
+                buffer_size = encoder_settings.frame_size() * channels as usize;
+                buffer.clear();
+            }
         }
         let input_volume = *input_volume_receiver.borrow();
-        let mut data = data.iter().map(|e| e.to_f32()).map(|e| e * input_volume);
+        resampled.clear();
+        resampler.process(data.iter().map(|e| e.to_f32() * input_volume), &mut resampled);
+        let mut data = resampled.iter().copied();
 
         while buffer.len() + data.len() > buffer_size {
             buffer.extend(data.by_ref().take(buffer_size - buffer.len()));
-            let encoded = transformers
+            let mut level = InputLevel {
+                level: transformers::linear_to_db(
+                    buffer.iter().map(|e| e.abs()).fold(0.0, f32::max),
+                ),
+                ..InputLevel::default()
+            };
+            let transformed = transformers
                 .iter_mut()
-                .try_fold((opus::Channels::Mono, &mut buffer[..]), |acc, e| e.transform(acc))
-                .map(|buf| opus_encoder.encode_vec_float(&*buf.1, buffer_size).unwrap());
+                .try_fold((channels, &mut buffer[..]), |acc, e| e.transform(acc));
+            level.open = transformed.is_some();
+            for transformer in transformers.iter() {
+                transformer.report(&mut level);
+            }
+            let _ = level_sender.send(level);
 
-            if let Some(encoded) = encoded {
-                if let Err(e) = input_sender.try_send(encoded) {
-                    warn!("Error sending audio: {}", e);
+            match transformed.filter(|_| open) {
+                Some((_, buf)) => {
+                    let len = opus_encoder.encode_float(buf, &mut packet).unwrap();
+                    // Packets of at most two bytes don't need to be sent
+                    // when using discontinuous transmission.
+                    if encoder_settings.dtx && len <= 2 {
+                        skipped_frames += encoder_settings.frames_per_packet as u64;
+                    } else {
+                        sink.send(EncodedPacket {
+                            skipped_frames,
+                            payload: packet[..len].to_vec(),
+                        });
+                        skipped_frames = 0;
+                    }
                 }
+                // Frames that aren't sent at all don't count towards the
+                // sequence number.
+                None => skipped_frames = 0,
             }
             buffer.clear();
         }
@@ -48,7 +238,7 @@ pub fn callback<T: Sample>(
 
 /// Something that can listen to audio and send it somewhere.
 ///
-/// One sample is assumed to be an encoded opus frame. See [opus::Encoder].
+/// One sample is assumed to be an encoded opus packet. See [Encoder].
 pub trait AudioInputDevice {
     /// Starts the device.
     fn play(&self) -> Result<(), AudioError>;
@@ -56,113 +246,242 @@ pub trait AudioInputDevice {
     fn pause(&self) -> Result<(), AudioError>;
     /// Sets the input volume of the device.
     fn set_volume(&self, volume: f32);
+    /// Sets how audio is processed before it's encoded.
+    fn set_transformers(&self, transformers: Vec<TransformerConfig>);
+    /// Sets how audio is encoded.
+    fn set_encoder_settings(&self, settings: EncoderSettings);
+    /// Returns a receiver of the current input level.
+    fn level_receiver(&self) -> watch::Receiver<InputLevel>;
     /// Returns a receiver to this device's values.
-    fn sample_receiver(&mut self) -> Option<futures_channel::mpsc::Receiver<Vec<u8>>>;
+    fn sample_receiver(&mut self) -> Option<futures_channel::mpsc::Receiver<EncodedPacket>>;
+    /// Returns a receiver to this device's values while loopback is enabled.
+    fn loopback_receiver(&mut self) -> Option<futures_channel::mpsc::Receiver<Vec<u8>>>;
+    /// Returns a receiver of the errors that occur while listening, e.g. when
+    /// the device is lost.
+    fn error_receiver(&mut self) -> Option<futures_channel::mpsc::Receiver<StreamError>>;
     /// The amount of channels this device has.
     fn num_channels(&self) -> usize;
+    /// The name of the device that is listened to.
+    fn device_name(&self) -> &str;
+    /// Returns what's needed to build the stream on another device.
+    fn stream_builder(&self) -> InputStreamBuilder;
+    /// Replaces the stream with one that was built on another device. Returns
+    /// the old stream.
+    fn set_stream(&mut self, stream: NewInputStream) -> cpal::Stream;
 }
 
 pub struct DefaultAudioInputDevice {
     stream: cpal::Stream,
-    sample_receiver: Option<futures_channel::mpsc::Receiver<Vec<u8>>>,
+    /// The name of the device that is listened to.
+    device_name: String,
+    /// What's needed to build the stream again on another device.
+    parts: StreamParts,
+    sample_receiver: Option<futures_channel::mpsc::Receiver<EncodedPacket>>,
+    loopback_receiver: Option<futures_channel::mpsc::Receiver<Vec<u8>>>,
+    error_receiver: Option<futures_channel::mpsc::Receiver<StreamError>>,
     volume_sender: watch::Sender<f32>,
+    transformers: Arc<Mutex<TransformerConfigs>>,
+    encoder_sender: watch::Sender<EncoderSettings>,
+    level_receiver: watch::Receiver<InputLevel>,
     channels: u16,
 }
 
-impl DefaultAudioInputDevice {
-    /// Initializes the default audio input.
-    pub fn new(
-        input_volume: f32,
-        phase_watcher: watch::Receiver<StatePhase>,
-        frame_size: u32, // blocks of 2.5 ms
-    ) -> Result<Self, AudioError> {
-        let sample_rate = SampleRate(SAMPLE_RATE);
-
-        let host = cpal::default_host();
-
-        let input_device = host
-            .default_input_device()
-            .ok_or(AudioError::NoDevice(AudioStream::Input))?;
-        let input_supported_config = input_device
-            .supported_input_configs()
-            .map_err(|e| AudioError::NoConfigs(AudioStream::Input, e))?
-            .find_map(|c| {
-                if c.min_sample_rate() <= sample_rate && c.max_sample_rate() >= sample_rate {
-                    Some(c)
-                } else {
-                    None
-                }
-            })
-            .ok_or(AudioError::NoSupportedConfig(AudioStream::Input))?
-            .with_sample_rate(sample_rate);
-        let input_supported_sample_format = input_supported_config.sample_format();
-        let input_config: StreamConfig = input_supported_config.into();
-
-        let err_fn = |err| error!("An error occurred on the output audio stream: {}", err);
-
-        let (sample_sender, sample_receiver) = futures_channel::mpsc::channel(1_000_000);
-
-        let (volume_sender, input_volume_receiver) = watch::channel::<f32>(input_volume);
+/// Everything an input stream is built from. It's kept so that a new stream
+/// can continue where the old one left off if the device is lost.
+#[derive(Clone)]
+struct StreamParts {
+    sink: EncodedSink,
+    transformers: Arc<Mutex<TransformerConfigs>>,
+    level_sender: Arc<watch::Sender<InputLevel>>,
+    encoder_receiver: watch::Receiver<EncoderSettings>,
+    input_volume_receiver: watch::Receiver<f32>,
+    error_sender: futures_channel::mpsc::Sender<StreamError>,
+}
 
-        let opus_encoder = opus::Encoder::new(
-            sample_rate.0,
-            match input_config.channels {
-                1 => opus::Channels::Mono,
-                2 => opus::Channels::Stereo,
-                _ => unimplemented!("Only 1 or 2 channels supported, got {}", input_config.channels),
-            },
-            opus::Application::Voip,
+impl StreamParts {
+    /// Builds a stream that listens to a device, with audio encoded with some
+    /// number of channels.
+    fn build(&self, device: &cpal::Device, channels: opus::Channels) -> Result<cpal::Stream, AudioError> {
+        let input_supported_config = conversion::select_config(
+            device
+                .supported_input_configs()
+                .map_err(|e| AudioError::NoConfigs(AudioStream::Input, e))?,
+            &[channels as u16],
         )
-        .unwrap();
-        let buffer_size = (sample_rate.0 * frame_size / 400) as usize;
+        .ok_or(AudioError::NoSupportedConfig(AudioStream::Input))?;
+        let input_supported_sample_format = input_supported_config.sample_format();
+        let input_config: StreamConfig = input_supported_config.into();
+        if input_config.sample_rate.0 != SAMPLE_RATE || input_config.channels != channels as u16 {
+            info!(
+                "Resampling input from {} Hz with {} channels",
+                input_config.sample_rate.0, input_config.channels
+            );
+        }
+        let resampler = Resampler::new(
+            input_config.sample_rate.0,
+            input_config.channels as usize,
+            SAMPLE_RATE,
+            channels as usize,
+        );
 
-        let transformers = vec![Box::new(NoiseGate::new(50)) as Box<dyn Transformer + Send + 'static>];
+        // Errors are handled by whoever receives them. A lost device usually
+        // reports the same error over and over, so errors are dropped while
+        // earlier ones haven't been handled.
+        let mut error_sender = self.error_sender.clone();
+        let err_fn = move |err| {
+            let _ = error_sender.try_send(err);
+        };
 
-        let input_stream = match input_supported_sample_format {
-            SampleFormat::F32 => input_device.build_input_stream(
+        let parts = self.clone();
+        match input_supported_sample_format {
+            SampleFormat::F32 => device.build_input_stream(
                 &input_config,
                 callback::<f32>(
-                    sample_sender,
-                    transformers,
-                    opus_encoder,
-                    buffer_size,
-                    input_volume_receiver,
-                    phase_watcher
+                    parts.sink,
+                    TransformerChain::new(parts.transformers),
+                    parts.level_sender,
+                    resampler,
+                    parts.encoder_receiver,
+                    parts.input_volume_receiver,
                 ),
                 err_fn,
             ),
-            SampleFormat::I16 => input_device.build_input_stream(
+            SampleFormat::I16 => device.build_input_stream(
                 &input_config,
                 callback::<i16>(
-                    sample_sender,
-                    transformers,
-                    opus_encoder,
-                    buffer_size,
-                    input_volume_receiver,
-                    phase_watcher
+                    parts.sink,
+                    TransformerChain::new(parts.transformers),
+                    parts.level_sender,
+                    resampler,
+                    parts.encoder_receiver,
+                    parts.input_volume_receiver,
                 ),
                 err_fn,
             ),
-            SampleFormat::U16 => input_device.build_input_stream(
+            SampleFormat::U16 => device.build_input_stream(
                 &input_config,
                 callback::<u16>(
-                    sample_sender,
-                    transformers,
-                    opus_encoder,
-                    buffer_size,
-                    input_volume_receiver,
-                    phase_watcher
+                    parts.sink,
+                    TransformerChain::new(parts.transformers),
+                    parts.level_sender,
+                    resampler,
+                    parts.encoder_receiver,
+                    parts.input_volume_receiver,
                 ),
                 err_fn,
             ),
         }
-        .map_err(|e| AudioError::InvalidStream(AudioStream::Input, e))?;
+        .map_err(|e| AudioError::InvalidStream(AudioStream::Input, e))
+    }
+}
+
+/// Builds input streams on other devices. It's separate from
+/// [DefaultAudioInputDevice] so that devices can be opened without holding
+/// the state lock.
+#[derive(Clone)]
+pub struct InputStreamBuilder {
+    parts: StreamParts,
+    channels: u16,
+}
+
+impl InputStreamBuilder {
+    /// Builds and starts a stream on some device.
+    pub fn build(&self, device: &cpal::Device) -> Result<NewInputStream, AudioError> {
+        let channels = conversion::internal_channels(self.channels);
+        let stream = self.parts.build(device, channels)?;
+        stream.play().map_err(AudioError::InputPlayError)?;
+        Ok(NewInputStream {
+            stream,
+            device_name: device.name().unwrap_or_default(),
+        })
+    }
+}
+
+/// An input stream that was built by an [InputStreamBuilder].
+pub struct NewInputStream {
+    stream: cpal::Stream,
+    device_name: String,
+}
+
+impl Debug for InputStreamBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InputStreamBuilder")
+            .field("parts", &"StreamParts")
+            .field("channels", &self.channels)
+            .finish()
+    }
+}
+
+impl Debug for NewInputStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NewInputStream")
+            .field("stream", &"cpal::Stream")
+            .field("device_name", &self.device_name)
+            .finish()
+    }
+}
+
+impl DefaultAudioInputDevice {
+    /// Initializes the audio input on the device with some name, or the
+    /// default device if None or if there's no such device.
+    pub fn new(
+        device_name: Option<&str>,
+        input_volume: f32,
+        transformers: Vec<TransformerConfig>,
+        encoder_settings: EncoderSettings,
+        echo_reference: EchoReference,
+        loopback_delay: watch::Receiver<Option<Duration>>,
+        phase_watcher: watch::Receiver<StatePhase>,
+    ) -> Result<Self, AudioError> {
+        let input_device = super::find_device(device_name, AudioStream::Input)?;
+        let input_supported_config = conversion::select_config(
+            input_device
+                .supported_input_configs()
+                .map_err(|e| AudioError::NoConfigs(AudioStream::Input, e))?,
+            &[1, 2],
+        )
+        .ok_or(AudioError::NoSupportedConfig(AudioStream::Input))?;
+        // The number of channels that is encoded stays the same even if the
+        // stream is moved to another device.
+        let channels = conversion::internal_channels(input_supported_config.channels());
+
+        let (sample_sender, sample_receiver) = futures_channel::mpsc::channel(1_000_000);
+        let (loopback_packet_sender, loopback_receiver) = futures_channel::mpsc::channel(1_000_000);
+        let sink = EncodedSink {
+            network_sender: sample_sender,
+            loopback_sender: loopback_packet_sender,
+            loopback_delay,
+            phase_watcher,
+        };
+
+        let (volume_sender, input_volume_receiver) = watch::channel::<f32>(input_volume);
+        let (encoder_sender, encoder_receiver) = watch::channel(encoder_settings);
+        let transformers = Arc::new(Mutex::new(TransformerConfigs::new(transformers, echo_reference)));
+        let (level_sender, level_receiver) = watch::channel(InputLevel::default());
+        let (error_sender, error_receiver) = futures_channel::mpsc::channel(1);
+
+        let parts = StreamParts {
+            sink,
+            transformers: Arc::clone(&transformers),
+            level_sender: Arc::new(level_sender),
+            encoder_receiver,
+            input_volume_receiver,
+            error_sender,
+        };
+        let input_stream = parts.build(&input_device, channels)?;
 
         let res = Self {
             stream: input_stream,
+            device_name: input_device.name().unwrap_or_default(),
+            parts,
             sample_receiver: Some(sample_receiver),
+            loopback_receiver: Some(loopback_receiver),
+            error_receiver: Some(error_receiver),
             volume_sender,
-            channels: input_config.channels,
+            transformers,
+            encoder_sender,
+            level_receiver,
+            channels: channels as u16,
         };
         Ok(res)
     }
@@ -185,22 +504,110 @@ impl AudioInputDevice for DefaultAudioInputDevice {
         self.volume_sender.send(volume).unwrap();
     }
 
-    fn sample_receiver(&mut self) -> Option<futures_channel::mpsc::Receiver<Vec<u8>>> {
+    fn set_transformers(&self, transformers: Vec<TransformerConfig>) {
+        TransformerConfigs::set(&self.transformers, transformers);
+    }
+
+    fn set_encoder_settings(&self, settings: EncoderSettings) {
+        if *self.encoder_sender.borrow() != settings {
+            self.encoder_sender.send(settings).unwrap();
+        }
+    }
+
+    fn level_receiver(&self) -> watch::Receiver<InputLevel> {
+        self.level_receiver.clone()
+    }
+
+    fn sample_receiver(&mut self) -> Option<futures_channel::mpsc::Receiver<EncodedPacket>> {
         self.sample_receiver.take()
     }
 
+    fn loopback_receiver(&mut self) -> Option<futures_channel::mpsc::Receiver<Vec<u8>>> {
+        self.loopback_receiver.take()
+    }
+
+    fn error_receiver(&mut self) -> Option<futures_channel::mpsc::Receiver<StreamError>> {
+        self.error_receiver.take()
+    }
+
     fn num_channels(&self) -> usize {
         self.channels as usize
     }
+
+    fn device_name(&self) -> &str {
+        &self.device_name
+    }
+
+    fn stream_builder(&self) -> InputStreamBuilder {
+        InputStreamBuilder {
+            parts: self.parts.clone(),
+            channels: self.channels,
+        }
+    }
+
+    fn set_stream(&mut self, stream: NewInputStream) -> cpal::Stream {
+        self.device_name = stream.device_name;
+        std::mem::replace(&mut self.stream, stream.stream)
+    }
 }
 
 impl Debug for DefaultAudioInputDevice {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("DefaultAudioInputDevice")
             .field("sample_receiver", &self.sample_receiver)
+            .field("device_name", &self.device_name)
+            .field("loopback_receiver", &self.loopback_receiver)
+            .field("error_receiver", &self.error_receiver)
             .field("channels", &self.channels)
             .field("volume_sender", &self.volume_sender)
+            .field("transformers", &self.transformers)
+            .field("encoder_sender", &self.encoder_sender)
             .field("stream", &"cpal::Stream")
             .finish()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn clamps_complexity() {
+        let config = AudioConfig {
+            complexity: Some(20),
+            ..AudioConfig::default()
+        };
+        assert_eq!(EncoderSettings::new(&config, None).complexity, 10);
+        assert_eq!(EncoderSettings::new(&AudioConfig::default(), None).complexity, 10);
+    }
+
+    #[test]
+    fn dtx_skips_silence() {
+        let settings = EncoderSettings::new(
+            &AudioConfig {
+                dtx: Some(true),
+                bitrate: Some(12000),
+                frames_per_packet: Some(2),
+                ..AudioConfig::default()
+            },
+            None,
+        );
+        let mut encoder = Encoder::new(
+            audiopus::SampleRate::Hz48000,
+            audiopus::Channels::Mono,
+            audiopus::Application::Voip,
+        )
+        .unwrap();
+        settings.apply(&mut encoder).unwrap();
+
+        // Discontinuous transmission only works at bitrates where the speech
+        // mode of the encoder is used.
+        let silence = vec![0.0; settings.frame_size()];
+        let mut packet = [0; MAX_PACKET_SIZE];
+        let lengths: Vec<usize> = (0..50)
+            .map(|_| encoder.encode_float(&silence, &mut packet).unwrap())
+            .collect();
+        // The encoder needs a few frames before it notices the silence.
+        assert!(lengths[10..].iter().filter(|&&len| len <= 2).count() > 30, "{:?}", lengths);
+    }
+}
@@ -1,24 +1,33 @@
 //! Receives audio packets from the networking and plays them.
 
+use crate::audio::conversion::{self, Resampler};
+use crate::audio::ducking::{self, Ducker};
+use crate::audio::echo::EchoReference;
+use crate::audio::positional::{Listener, PositionalSettings};
+use crate::audio::transformers::{Limiter, Transformer};
 use crate::audio::SAMPLE_RATE;
 use crate::error::{AudioError, AudioStream};
 use crate::network::VoiceStreamType;
 
 use bytes::Bytes;
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{OutputCallbackInfo, Sample, SampleFormat, SampleRate, StreamConfig};
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{OutputCallbackInfo, Sample, SampleFormat, StreamConfig, StreamError};
 use dasp_ring_buffer::Bounded;
 use log::*;
 use mumble_protocol::voice::VoicePacketPayload;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
 use std::iter;
-use std::ops::AddAssign;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::watch;
 
 type ClientStreamKey = (VoiceStreamType, u32);
 
+/// How long a user is considered to be talking after the last audio that was
+/// loud enough, so that short pauses between words aren't counted.
+const TALKING_TIMEOUT: Duration = Duration::from_millis(300);
+
 /// State for decoding audio received from another user.
 #[derive(Debug)]
 pub struct ClientAudioData {
@@ -29,6 +38,11 @@ pub struct ClientAudioData {
     // to reuse it.
     mono_decoder: opus::Decoder,
     stereo_decoder: opus::Decoder,
+    /// The position sent with the latest packet, if any.
+    position: Option<[f32; 3]>,
+    /// When audio that was loud enough to be talking was last received, or
+    /// None if the user has ended their transmission since.
+    last_talked: Option<Instant>,
 }
 
 impl ClientAudioData {
@@ -37,11 +51,15 @@ impl ClientAudioData {
             mono_decoder: opus::Decoder::new(sample_rate, opus::Channels::Mono).unwrap(),
             stereo_decoder: opus::Decoder::new(sample_rate, opus::Channels::Stereo).unwrap(),
             output_channels,
+            position: None,
+            last_talked: None,
             buf: Bounded::from_full(vec![0.0; sample_rate as usize * output_channels as usize]), //buffer 1 s of audio
         }
     }
 
-    pub fn store_packet(&mut self, bytes: Bytes) {
+    /// Decodes a packet and stores the audio. `end` is true if it's the last
+    /// packet of a transmission.
+    pub fn store_packet(&mut self, bytes: Bytes, end: bool) {
         let packet_channels = opus::packet::get_nb_channels(&bytes).unwrap();
         let (decoder, channels) = match packet_channels {
             opus::Channels::Mono => (&mut self.mono_decoder, 1),
@@ -52,6 +70,11 @@ impl ClientAudioData {
             .decode_float(&bytes, &mut out, false)
             .expect("Error decoding");
         out.truncate(parsed);
+        if end {
+            self.last_talked = None;
+        } else if ducking::is_talking(out.iter().copied()) {
+            self.last_talked = Some(Instant::now());
+        }
         match (packet_channels, self.output_channels) {
             (opus::Channels::Mono, opus::Channels::Mono) | (opus::Channels::Stereo, opus::Channels::Stereo) => for sample in out {
                 self.buf.push(sample);
@@ -74,6 +97,21 @@ pub struct ClientStream {
     buffer_effects: VecDeque<f32>,
     sample_rate: u32,
     output_channels: opus::Channels,
+    /// Our own position, if we're sending one.
+    listener: Option<Listener>,
+    positional: PositionalSettings,
+    ducker: Ducker,
+    /// The sessions of users that duck other users while they're talking.
+    ducking_sessions: HashSet<u32>,
+    /// Keeps the mixed audio from clipping. It's clipped if None.
+    limiter: Option<Limiter>,
+    /// Where in the stereo field users are placed, from -1 (left) to 1 (right).
+    pans: HashMap<u32, f32>,
+    /// The sessions we've warned about sending audio in a codec we don't
+    /// support.
+    unsupported_codec_sessions: HashSet<u32>,
+    /// When users last whispered to us.
+    whispers: HashMap<u32, Instant>,
 }
 
 impl ClientStream {
@@ -88,6 +126,14 @@ impl ClientStream {
             buffer_effects: VecDeque::new(),
             sample_rate,
             output_channels: channels,
+            listener: None,
+            positional: PositionalSettings::default(),
+            ducker: Ducker::default(),
+            ducking_sessions: HashSet::new(),
+            limiter: None,
+            pans: HashMap::new(),
+            unsupported_codec_sessions: HashSet::new(),
+            whispers: HashMap::new(),
         }
     }
 
@@ -98,52 +144,106 @@ impl ClientStream {
     }
 
     /// Decodes a voice packet.
-    pub fn decode_packet(&mut self, client: ClientStreamKey, payload: VoicePacketPayload) {
+    pub fn decode_packet(
+        &mut self,
+        client: ClientStreamKey,
+        payload: VoicePacketPayload,
+        position: Option<[f32; 3]>,
+    ) {
         match payload {
-            VoicePacketPayload::Opus(bytes, _eot) => {
-                self.get_client(client).store_packet(bytes);
-            }
-            _ => {
-                unimplemented!("Payload type not supported");
+            VoicePacketPayload::Opus(bytes, eot) => {
+                let client = self.get_client(client);
+                client.position = position;
+                client.store_packet(bytes, eot);
             }
+            // Old clients might send CELT or Speex, which we can't decode.
+            // Their audio is dropped with a warning the first time.
+            VoicePacketPayload::CeltAlpha(_) => self.unsupported_codec(client.1, "CELT 0.7.0"),
+            VoicePacketPayload::CeltBeta(_) => self.unsupported_codec(client.1, "CELT 0.11.0"),
+            VoicePacketPayload::Speex(_) => self.unsupported_codec(client.1, "Speex"),
+            _ => self.unsupported_codec(client.1, "unknown"),
         }
     }
 
-    /// Extends the sound effect buffer queue with some received values.
-    pub fn add_sound_effect(&mut self, values: &[f32]) {
-        self.buffer_effects.extend(values.iter().copied());
+    /// Notes that a user whispered to us. Returns true if they weren't already
+    /// whispering.
+    pub fn register_whisper(&mut self, session: u32) -> bool {
+        let now = Instant::now();
+        let last = self.whispers.insert(session, now);
+        !matches!(last, Some(last) if now - last < TALKING_TIMEOUT)
     }
-}
 
-/// Adds two values in some saturating way.
-/// 
-/// Since we support [f32], [i16] and [u16] we need some way of adding two values
-/// without peaking above/below the edge values. This trait ensures that we can
-/// use all three primitive types as a generic parameter.
-pub trait SaturatingAdd {
-    /// Adds two values in some saturating way. See trait documentation.
-    fn saturating_add(self, rhs: Self) -> Self;
-}
-
-impl SaturatingAdd for f32 {
-    fn saturating_add(self, rhs: Self) -> Self {
-        match self + rhs {
-            a if a < -1.0 => -1.0,
-            a if a > 1.0 => 1.0,
-            a => a,
+    fn unsupported_codec(&mut self, session: u32, codec: &str) {
+        if self.unsupported_codec_sessions.insert(session) {
+            warn!(
+                "Dropping {} audio from session {}, only Opus is supported",
+                codec, session
+            );
         }
     }
-}
 
-impl SaturatingAdd for i16 {
-    fn saturating_add(self, rhs: Self) -> Self {
-        i16::saturating_add(self, rhs)
+    /// Returns the sessions of the users whose audio is currently being
+    /// played.
+    pub fn talking_sessions(&self) -> HashSet<u32> {
+        self.buffer_clients
+            .iter()
+            .filter(|(_, data)| {
+                matches!(data.last_talked, Some(time) if time.elapsed() < TALKING_TIMEOUT)
+            })
+            .map(|((_, session), _)| *session)
+            .collect()
+    }
+
+    /// Forgets the audio that is buffered for a session.
+    pub fn remove_session(&mut self, session: u32) {
+        self.buffer_clients.retain(|(_, s), _| *s != session);
+    }
+
+    /// Sets our own position, which other users' positions are relative to.
+    pub fn set_listener(&mut self, listener: Option<Listener>) {
+        self.listener = listener;
+    }
+
+    pub fn set_positional_settings(&mut self, settings: PositionalSettings) {
+        self.positional = settings;
+    }
+
+    pub fn set_ducker(&mut self, ducker: Ducker) {
+        self.ducker = ducker;
+    }
+
+    /// Sets which users duck other users while they're talking.
+    pub fn set_ducking_sessions(&mut self, sessions: HashSet<u32>) {
+        self.ducking_sessions = sessions;
+    }
+
+    /// Returns where in the stereo field a user is placed.
+    pub fn pan(&self, session: u32) -> f32 {
+        self.pans.get(&session).copied().unwrap_or(0.0)
+    }
+
+    /// Places a user in the stereo field, from -1 (left) to 1 (right).
+    pub fn set_pan(&mut self, session: u32, pan: f32) {
+        self.pans.insert(session, pan.clamp(-1.0, 1.0));
+    }
+
+    /// Forgets where a user is placed in the stereo field.
+    pub fn remove_pan(&mut self, session: u32) {
+        self.pans.remove(&session);
     }
-}
 
-impl SaturatingAdd for u16 {
-    fn saturating_add(self, rhs: Self) -> Self {
-        u16::saturating_add(self, rhs)
+    /// Forgets where all users are placed in the stereo field.
+    pub fn clear_pans(&mut self) {
+        self.pans.clear();
+    }
+
+    pub fn set_limiter(&mut self, limiter: Option<Limiter>) {
+        self.limiter = limiter;
+    }
+
+    /// Extends the sound effect buffer queue with some received values.
+    pub fn add_sound_effect(&mut self, values: &[f32]) {
+        self.buffer_effects.extend(values.iter().copied());
     }
 }
 
@@ -153,91 +253,218 @@ pub trait AudioOutputDevice {
     fn set_volume(&self, volume: f32);
     fn num_channels(&self) -> usize;
     fn client_streams(&self) -> Arc<Mutex<ClientStream>>;
+    /// Returns a receiver of the errors that occur while playing, e.g. when
+    /// the device is lost.
+    fn error_receiver(&mut self) -> Option<futures_channel::mpsc::Receiver<StreamError>>;
+    /// The name of the device that is played to.
+    fn device_name(&self) -> &str;
+    /// Returns what's needed to build the stream on another device.
+    fn stream_builder(&self) -> OutputStreamBuilder;
+    /// Replaces the stream with one that was built on another device. Returns
+    /// the old stream.
+    fn set_stream(&mut self, stream: NewOutputStream) -> cpal::Stream;
 }
 
 /// The default audio output device, as determined by [cpal].
 pub struct DefaultAudioOutputDevice {
     config: StreamConfig,
+    /// The number of channels that is mixed, which might differ from the
+    /// number of channels of the device.
+    channels: u16,
     stream: cpal::Stream,
+    /// The name of the device that is played to.
+    device_name: String,
+    /// What's needed to build the stream again on another device.
+    parts: StreamParts,
     /// The client stream per user ID. A separate stream is kept for UDP and TCP.
     ///
     /// Shared with [super::AudioOutput].
     client_streams: Arc<Mutex<ClientStream>>,
     /// Output volume configuration.
     volume_sender: watch::Sender<f32>,
+    error_receiver: Option<futures_channel::mpsc::Receiver<StreamError>>,
 }
 
-impl DefaultAudioOutputDevice {
-    /// Initializes the default audio output.
-    pub fn new(
-        output_volume: f32,
-        user_volumes: Arc<Mutex<HashMap<u32, (f32, bool)>>>,
-    ) -> Result<Self, AudioError> {
-        let sample_rate = SampleRate(SAMPLE_RATE);
-
-        let host = cpal::default_host();
-        let output_device = host
-            .default_output_device()
-            .ok_or(AudioError::NoDevice(AudioStream::Output))?;
-        let output_supported_config = output_device
-            .supported_output_configs()
-            .map_err(|e| AudioError::NoConfigs(AudioStream::Output, e))?
-            .find_map(|c| {
-                if c.min_sample_rate() <= sample_rate && c.max_sample_rate() >= sample_rate && c.channels() == 2 {
-                    Some(c)
-                } else {
-                    None
-                }
-            })
-            .ok_or(AudioError::NoSupportedConfig(AudioStream::Output))?
-            .with_sample_rate(sample_rate);
+/// Everything an output stream is built from. It's kept so that a new stream
+/// can continue where the old one left off if the device is lost.
+#[derive(Clone)]
+struct StreamParts {
+    client_streams: Arc<Mutex<ClientStream>>,
+    output_volume_receiver: watch::Receiver<f32>,
+    user_volumes: Arc<Mutex<HashMap<u32, (f32, bool)>>>,
+    echo_reference: EchoReference,
+    error_sender: futures_channel::mpsc::Sender<StreamError>,
+}
+
+impl StreamParts {
+    /// Builds a stream that plays to a device, with audio mixed with some
+    /// number of channels.
+    fn build(&self, device: &cpal::Device, channels: u16) -> Result<(cpal::Stream, StreamConfig), AudioError> {
+        let output_supported_config = conversion::select_config(
+            device
+                .supported_output_configs()
+                .map_err(|e| AudioError::NoConfigs(AudioStream::Output, e))?,
+            &[channels],
+        )
+        .ok_or(AudioError::NoSupportedConfig(AudioStream::Output))?;
         let output_supported_sample_format = output_supported_config.sample_format();
         let output_config: StreamConfig = output_supported_config.into();
-        let client_streams = Arc::new(std::sync::Mutex::new(ClientStream::new(
-            sample_rate.0,
-            output_config.channels,
-        )));
-
-        let err_fn = |err| error!("An error occurred on the output audio stream: {}", err);
-
-        let (output_volume_sender, output_volume_receiver) = watch::channel::<f32>(output_volume);
+        if output_config.sample_rate.0 != SAMPLE_RATE || output_config.channels != channels {
+            info!(
+                "Resampling output to {} Hz with {} channels",
+                output_config.sample_rate.0, output_config.channels
+            );
+        }
+        let resampler = Resampler::new(
+            SAMPLE_RATE,
+            channels as usize,
+            output_config.sample_rate.0,
+            output_config.channels as usize,
+        );
+
+        // Errors are handled by whoever receives them. A lost device usually
+        // reports the same error over and over, so errors are dropped while
+        // earlier ones haven't been handled.
+        let mut error_sender = self.error_sender.clone();
+        let err_fn = move |err| {
+            let _ = error_sender.try_send(err);
+        };
 
+        let parts = self.clone();
         let output_stream = match output_supported_sample_format {
-            SampleFormat::F32 => output_device.build_output_stream(
+            SampleFormat::F32 => device.build_output_stream(
                 &output_config,
                 callback::<f32>(
-                    Arc::clone(&client_streams),
-                    output_volume_receiver,
-                    user_volumes,
+                    parts.client_streams,
+                    parts.output_volume_receiver,
+                    parts.user_volumes,
+                    parts.echo_reference,
+                    resampler,
                 ),
                 err_fn,
             ),
-            SampleFormat::I16 => output_device.build_output_stream(
+            SampleFormat::I16 => device.build_output_stream(
                 &output_config,
                 callback::<i16>(
-                    Arc::clone(&client_streams),
-                    output_volume_receiver,
-                    user_volumes,
+                    parts.client_streams,
+                    parts.output_volume_receiver,
+                    parts.user_volumes,
+                    parts.echo_reference,
+                    resampler,
                 ),
                 err_fn,
             ),
-            SampleFormat::U16 => output_device.build_output_stream(
+            SampleFormat::U16 => device.build_output_stream(
                 &output_config,
                 callback::<u16>(
-                    Arc::clone(&client_streams),
-                    output_volume_receiver,
-                    user_volumes,
+                    parts.client_streams,
+                    parts.output_volume_receiver,
+                    parts.user_volumes,
+                    parts.echo_reference,
+                    resampler,
                 ),
                 err_fn,
             ),
         }
         .map_err(|e| AudioError::InvalidStream(AudioStream::Output, e))?;
+        Ok((output_stream, output_config))
+    }
+}
+
+/// Builds output streams on other devices. It's separate from
+/// [DefaultAudioOutputDevice] so that devices can be opened without holding
+/// the state lock.
+#[derive(Clone)]
+pub struct OutputStreamBuilder {
+    parts: StreamParts,
+    channels: u16,
+}
+
+impl OutputStreamBuilder {
+    /// Builds and starts a stream on some device.
+    pub fn build(&self, device: &cpal::Device) -> Result<NewOutputStream, AudioError> {
+        let (stream, config) = self.parts.build(device, self.channels)?;
+        stream.play().map_err(AudioError::OutputPlayError)?;
+        Ok(NewOutputStream {
+            stream,
+            config,
+            device_name: device.name().unwrap_or_default(),
+        })
+    }
+}
+
+/// An output stream that was built by an [OutputStreamBuilder].
+pub struct NewOutputStream {
+    stream: cpal::Stream,
+    config: StreamConfig,
+    device_name: String,
+}
+
+impl Debug for OutputStreamBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OutputStreamBuilder")
+            .field("parts", &"StreamParts")
+            .field("channels", &self.channels)
+            .finish()
+    }
+}
+
+impl Debug for NewOutputStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NewOutputStream")
+            .field("stream", &"cpal::Stream")
+            .field("config", &self.config)
+            .field("device_name", &self.device_name)
+            .finish()
+    }
+}
+
+impl DefaultAudioOutputDevice {
+    /// Initializes the audio output on the device with some name, or the
+    /// default device if None or if there's no such device.
+    pub fn new(
+        device_name: Option<&str>,
+        output_volume: f32,
+        user_volumes: Arc<Mutex<HashMap<u32, (f32, bool)>>>,
+        echo_reference: EchoReference,
+    ) -> Result<Self, AudioError> {
+        let output_device = super::find_device(device_name, AudioStream::Output)?;
+        let output_supported_config = conversion::select_config(
+            output_device
+                .supported_output_configs()
+                .map_err(|e| AudioError::NoConfigs(AudioStream::Output, e))?,
+            &[2],
+        )
+        .ok_or(AudioError::NoSupportedConfig(AudioStream::Output))?;
+        // The number of channels that is mixed stays the same even if the
+        // stream is moved to another device.
+        let channels = conversion::internal_channels(output_supported_config.channels()) as u16;
+        let client_streams = Arc::new(Mutex::new(ClientStream::new(
+            SAMPLE_RATE,
+            channels,
+        )));
+
+        let (output_volume_sender, output_volume_receiver) = watch::channel::<f32>(output_volume);
+        let (error_sender, error_receiver) = futures_channel::mpsc::channel(1);
+
+        let parts = StreamParts {
+            client_streams: Arc::clone(&client_streams),
+            output_volume_receiver,
+            user_volumes,
+            echo_reference,
+            error_sender,
+        };
+        let (output_stream, output_config) = parts.build(&output_device, channels)?;
 
         Ok(Self {
             config: output_config,
+            channels,
             stream: output_stream,
+            device_name: output_device.name().unwrap_or_default(),
+            parts,
             volume_sender: output_volume_sender,
             client_streams,
+            error_receiver: Some(error_receiver),
         })
     }
 }
@@ -260,54 +487,159 @@ impl AudioOutputDevice for DefaultAudioOutputDevice {
     }
 
     fn num_channels(&self) -> usize {
-        self.config.channels as usize
+        self.channels as usize
     }
 
     fn client_streams(&self) -> Arc<Mutex<ClientStream>> {
         Arc::clone(&self.client_streams)
     }
+
+    fn error_receiver(&mut self) -> Option<futures_channel::mpsc::Receiver<StreamError>> {
+        self.error_receiver.take()
+    }
+
+    fn device_name(&self) -> &str {
+        &self.device_name
+    }
+
+    fn stream_builder(&self) -> OutputStreamBuilder {
+        OutputStreamBuilder {
+            parts: self.parts.clone(),
+            channels: self.channels,
+        }
+    }
+
+    fn set_stream(&mut self, stream: NewOutputStream) -> cpal::Stream {
+        self.config = stream.config;
+        self.device_name = stream.device_name;
+        std::mem::replace(&mut self.stream, stream.stream)
+    }
 }
 
 /// Returns a function that fills a buffer with audio from client streams
 /// modified according to some audio configuration.
-pub fn callback<T: Sample + AddAssign + SaturatingAdd + std::fmt::Display>(
+///
+/// The audio is mixed at [SAMPLE_RATE] and converted to the format of the
+/// device by `resampler`.
+pub fn callback<T: Sample>(
     user_bufs: Arc<Mutex<ClientStream>>,
     output_volume_receiver: watch::Receiver<f32>,
     user_volumes: Arc<Mutex<HashMap<u32, (f32, bool)>>>,
+    echo_reference: EchoReference,
+    mut resampler: Resampler,
 ) -> impl FnMut(&mut [T], &OutputCallbackInfo) + Send + 'static {
+    let channels = resampler.input_channels();
+    let device_channels = resampler.output_channels();
+    let mut mixed = Vec::new();
+    let mut resampled = Vec::new();
+    let mut converted = VecDeque::new();
+
     move |data: &mut [T], _info: &OutputCallbackInfo| {
+        while converted.len() < data.len() {
+            // Mix enough audio to fill the rest of the buffer, rounding up.
+            let frames = (data.len() - converted.len()) / device_channels + 1;
+            let frames = (frames as f64 * resampler.step()).ceil() as usize;
+            mixed.clear();
+            mixed.resize(frames * channels, 0.0);
+            mix(
+                &mut mixed,
+                &user_bufs,
+                *output_volume_receiver.borrow(),
+                &user_volumes,
+            );
+
+            // Let the echo canceller know what is about to be played.
+            echo_reference.push(mixed.chunks(channels).map(|frame| {
+                frame.iter().sum::<f32>() / channels as f32
+            }));
+
+            resampled.clear();
+            resampler.process(mixed.iter().copied(), &mut resampled);
+            converted.extend(&resampled);
+        }
         for sample in data.iter_mut() {
-            *sample = Sample::from(&0.0);
+            *sample = Sample::from(&converted.pop_front().unwrap_or(0.0));
         }
+    }
+}
 
-        let volume = *output_volume_receiver.borrow();
-
-        let mut user_bufs = user_bufs.lock().unwrap();
-        let user_volumes = user_volumes.lock().unwrap();
-        for (k, v) in user_bufs.buffer_clients.iter_mut() {
-            let (user_volume, muted) = user_volumes.get(&k.1).cloned().unwrap_or((1.0, false));
-            if !muted {
-                for (sample, val) in data.iter_mut().zip(v.buf.drain().chain(iter::repeat(0.0))) {
-                    *sample = sample.saturating_add(Sample::from(
-                        &(val * volume * user_volume),
-                    ));
+/// Mixes the audio of all client streams and sound effects into `data`.
+fn mix(
+    data: &mut [f32],
+    user_bufs: &Mutex<ClientStream>,
+    volume: f32,
+    user_volumes: &Mutex<HashMap<u32, (f32, bool)>>,
+) {
+    let mut user_bufs = user_bufs.lock().unwrap();
+    let user_bufs = &mut *user_bufs;
+    let user_volumes = user_volumes.lock().unwrap();
+    let channels = user_bufs.output_channels as usize;
+    let frames = data.len() / channels;
+
+    let is_muted = |session: u32| matches!(user_volumes.get(&session), Some((_, true)));
+    let talking = user_bufs.buffer_clients.iter().any(|(k, v)| {
+        user_bufs.ducking_sessions.contains(&k.1)
+            && !is_muted(k.1)
+            && ducking::is_talking(v.buf.iter().take(data.len()).copied())
+    }) || (user_bufs.ducker.ducks_sound_effects() && !user_bufs.buffer_effects.is_empty());
+    let (duck_start, duck_end) = user_bufs.ducker.advance(talking, frames);
+
+    for (k, v) in user_bufs.buffer_clients.iter_mut() {
+        let (user_volume, muted) = user_volumes.get(&k.1).cloned().unwrap_or((1.0, false));
+        if !muted {
+            let [mut left, mut right] = match (&user_bufs.listener, v.position) {
+                (Some(listener), Some(position)) => user_bufs.positional.gains(listener, position),
+                _ => [1.0, 1.0],
+            };
+            if let Some(pan) = user_bufs.pans.get(&k.1) {
+                left *= (1.0 - pan).min(1.0);
+                right *= (1.0 + pan).min(1.0);
+            }
+            let gains = if channels == 1 {
+                // Only attenuate mono audio.
+                [left.max(right); 2]
+            } else {
+                [left, right]
+            };
+            let ducked = !user_bufs.ducking_sessions.contains(&k.1);
+            for (i, (sample, val)) in data.iter_mut().zip(v.buf.drain().chain(iter::repeat(0.0))).enumerate() {
+                let mut gain = gains[i % channels];
+                if ducked {
+                    // Fade between the gains to avoid clicks.
+                    let t = (i / channels) as f32 / frames as f32;
+                    gain *= duck_start + (duck_end - duck_start) * t;
                 }
+                *sample += val * volume * user_volume * gain;
             }
         }
-        for sample in data.iter_mut() {
-            *sample = sample.saturating_add(Sample::from(
-                &(user_bufs.buffer_effects.pop_front().unwrap_or(0.0) * volume),
-            ));
+    }
+    for sample in data.iter_mut() {
+        *sample += user_bufs.buffer_effects.pop_front().unwrap_or(0.0) * volume;
+    }
+
+    // The audio is mixed without clipping so that the limiter can lower it
+    // as a whole.
+    match &mut user_bufs.limiter {
+        Some(limiter) => {
+            limiter.transform((user_bufs.output_channels, data));
+        }
+        None => {
+            for sample in data.iter_mut() {
+                *sample = sample.clamp(-1.0, 1.0);
+            }
         }
     }
 }
 
 impl Debug for DefaultAudioOutputDevice {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("DefaultAudioInputDevice")
+        f.debug_struct("DefaultAudioOutputDevice")
             .field("client_streams", &self.client_streams)
+            .field("device_name", &self.device_name)
             .field("config", &self.config)
+            .field("channels", &self.channels)
             .field("volume_sender", &self.volume_sender)
+            .field("error_receiver", &self.error_receiver)
             .field("stream", &"cpal::Stream")
             .finish()
     }
@@ -0,0 +1,142 @@
+//! Positional audio, where other users are panned and attenuated depending on
+//! where they are relative to us.
+//!
+//! Positions are in meters in a left-handed coordinate system, as sent by
+//! other Mumble clients.
+
+use mumlib::config::PositionalConfig;
+
+/// Where we are and which way we're facing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Listener {
+    pub pos: [f32; 3],
+    /// The direction we're facing.
+    pub front: [f32; 3],
+    /// The direction of the top of our head.
+    pub top: [f32; 3],
+}
+
+/// How positional audio is rendered.
+#[derive(Clone, Copy, Debug)]
+pub struct PositionalSettings {
+    enabled: bool,
+    min_distance: f32,
+    max_distance: f32,
+    min_volume: f32,
+}
+
+impl PositionalSettings {
+    pub fn new(config: Option<&PositionalConfig>) -> Self {
+        let config = config.cloned().unwrap_or_default();
+        let min_distance = config.min_distance.unwrap_or(1.0).max(0.0);
+        Self {
+            enabled: config.enabled.unwrap_or(true),
+            min_distance,
+            max_distance: config.max_distance.unwrap_or(15.0).max(min_distance),
+            min_volume: config.min_volume.unwrap_or(0.3).clamp(0.0, 1.0),
+        }
+    }
+
+    /// Returns the gain of the left and right channel for a speaker at some
+    /// position. Users closer than the minimum distance are played at full
+    /// volume, and the volume then falls linearly to the minimum volume at the
+    /// maximum distance.
+    pub fn gains(&self, listener: &Listener, speaker: [f32; 3]) -> [f32; 2] {
+        if !self.enabled {
+            return [1.0, 1.0];
+        }
+        let direction = sub(speaker, listener.pos);
+        let distance = length(direction);
+        let volume = if distance <= self.min_distance {
+            1.0
+        } else if distance >= self.max_distance {
+            self.min_volume
+        } else {
+            let t = (distance - self.min_distance) / (self.max_distance - self.min_distance);
+            1.0 - (1.0 - self.min_volume) * t
+        };
+
+        let right = cross(listener.top, listener.front);
+        let pan = if distance > f32::EPSILON && length(right) > f32::EPSILON {
+            dot(direction, right) / (distance * length(right))
+        } else {
+            0.0
+        };
+        [volume * (1.0 - pan).min(1.0), volume * (1.0 + pan).min(1.0)]
+    }
+}
+
+impl Default for PositionalSettings {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+/// Encodes a position as it's sent in voice packets.
+pub fn encode_position(pos: [f32; 3]) -> Vec<u8> {
+    pos.iter().flat_map(|e| e.to_le_bytes()).collect()
+}
+
+/// Decodes a position received in a voice packet.
+pub fn decode_position(bytes: &[u8]) -> Option<[f32; 3]> {
+    if bytes.len() < 12 {
+        return None;
+    }
+    let float = |i: usize| f32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+    let pos = [float(0), float(4), float(8)];
+    if pos.iter().all(|e| e.is_finite()) {
+        Some(pos)
+    } else {
+        None
+    }
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn length(a: [f32; 3]) -> f32 {
+    dot(a, a).sqrt()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn position_round_trip() {
+        let position = [1.5, -2.25, 1000.0];
+        assert_eq!(decode_position(&encode_position(position)), Some(position));
+    }
+
+    #[test]
+    fn position_is_little_endian() {
+        assert_eq!(
+            encode_position([1.0, 0.0, -2.0]),
+            vec![0, 0, 0x80, 0x3f, 0, 0, 0, 0, 0, 0, 0, 0xc0],
+        );
+    }
+
+    #[test]
+    fn invalid_positions_are_ignored() {
+        assert_eq!(decode_position(&[0; 11]), None);
+        assert_eq!(decode_position(&encode_position([0.0, f32::NAN, 0.0])), None);
+        assert_eq!(decode_position(&encode_position([f32::INFINITY, 0.0, 0.0])), None);
+        // Anything after the position is ignored.
+        let mut bytes = encode_position([1.0, 2.0, 3.0]);
+        bytes.extend(&[1, 2, 3]);
+        assert_eq!(decode_position(&bytes), Some([1.0, 2.0, 3.0]));
+    }
+}
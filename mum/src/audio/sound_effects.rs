@@ -5,6 +5,7 @@ use mumlib::config::SoundEffect;
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::f32::consts::PI;
 use std::fs::File;
 #[cfg(feature = "ogg")]
 use std::io::Cursor;
@@ -52,6 +53,10 @@ pub enum NotificationEvents {
     Unmute,
     Deafen,
     Undeafen,
+    TextMessage,
+    Kicked,
+    PermissionDenied,
+    Whisper,
 }
 
 impl TryFrom<&str> for NotificationEvents {
@@ -69,6 +74,10 @@ impl TryFrom<&str> for NotificationEvents {
             "unmute" => Ok(NotificationEvents::Unmute),
             "deafen" => Ok(NotificationEvents::Deafen),
             "undeafen" => Ok(NotificationEvents::Undeafen),
+            "text_message" => Ok(NotificationEvents::TextMessage),
+            "kicked" => Ok(NotificationEvents::Kicked),
+            "permission_denied" => Ok(NotificationEvents::PermissionDenied),
+            "whisper" => Ok(NotificationEvents::Whisper),
             _ => {
                 Err(())
             }
@@ -77,16 +86,16 @@ impl TryFrom<&str> for NotificationEvents {
 }
 
 /// Loads files into an "event -> data"-map, with support for overriding
-/// specific events with another sound file.
+/// specific events with another sound file. Events that are overriden with
+/// "none" are left out.
 pub fn load_sound_effects(overrides: &[SoundEffect], num_channels: usize) -> HashMap<NotificationEvents, Vec<f32>> {
     let overrides: HashMap<_, _> = overrides
         .iter()
         .filter_map(|sound_effect| {
-            let (event, file) = (&sound_effect.event, &sound_effect.file);
-            if let Ok(event) = NotificationEvents::try_from(event.as_str()) {
-                Some((event, file))
+            if let Ok(event) = NotificationEvents::try_from(sound_effect.event.as_str()) {
+                Some((event, sound_effect))
             } else {
-                warn!("Unknown notification event '{}'", event);
+                warn!("Unknown notification event '{}'", sound_effect.event);
                 None
             }
         })
@@ -95,24 +104,24 @@ pub fn load_sound_effects(overrides: &[SoundEffect], num_channels: usize) -> Has
     // Construct a hashmap that maps every [NotificationEvent] to a vector of
     // plain floating point audio data with the global sample rate as a
     // Vec<f32>. We do this by iterating over all [NotificationEvent]-variants
-    // and opening either the file passed as an override or the fallback sound
-    // effect (if omitted). We then use dasp to convert to the correct sample rate.
+    // and opening either the file passed as an override or synthesizing the
+    // default sound effect (if omitted). We then use dasp to convert to the
+    // correct sample rate.
     NotificationEvents::iter()
-        .map(|event| {
-            let file = overrides.get(&event);
+        .filter_map(|event| {
+            let sound_effect = overrides.get(&event);
+            let file = sound_effect.and_then(|sound_effect| sound_effect.file.as_deref());
+            if file == Some("none") {
+                return None;
+            }
+            let volume = sound_effect
+                .and_then(|sound_effect| sound_effect.volume)
+                .unwrap_or(1.0)
+                .max(0.0);
             // Try to open the file if overriden, otherwise use the default sound effect.
-            let (data, kind) = file
-                .and_then(|file| {
-                    // Try to get the file kind from the extension.
-                    let kind = file
-                        .split('.')
-                        .last()
-                        .and_then(|ext| AudioFileKind::try_from(ext).ok())?;
-                    Some((get_sfx(file), kind))
-                })
-                .unwrap_or_else(|| (get_default_sfx(), AudioFileKind::Wav));
-            // Unpack the samples.
-            let (samples, spec) = unpack_audio(data, kind);
+            let (samples, spec) = file
+                .and_then(load_file)
+                .unwrap_or_else(|| default_sound_effect(event));
             // If the audio is mono (single channel), pad every sample with
             // itself, since we later assume that audio is stored interleaved as
             // LRLRLR (or RLRLRL). Without this, mono audio would be played in
@@ -123,7 +132,7 @@ pub fn load_sound_effects(overrides: &[SoundEffect], num_channels: usize) -> Has
                 _ => unimplemented!("Only mono and stereo sound is supported. See #80."),
             };
             // Create a dasp signal containing stereo sound.
-            let mut signal = signal::from_interleaved_samples_iter::<_, [f32; 2]>(iter);
+            let mut signal = signal::from_interleaved_samples_iter::<_, [f32; 2]>(iter.map(|e| e * volume));
             // Create a linear interpolator, in case we need to convert the sample rate.
             let interp = Linear::new(Signal::next(&mut signal), Signal::next(&mut signal));
             // Create our resulting samples.
@@ -139,13 +148,27 @@ pub fn load_sound_effects(overrides: &[SoundEffect], num_channels: usize) -> Has
                     }
                 })
                 .collect::<Vec<f32>>();
-            (event, samples)
+            Some((event, samples))
         })
         .collect()
 }
 
+/// Opens and unpacks a sound file, or returns None if it couldn't be read.
+fn load_file(file: &str) -> Option<(Vec<f32>, AudioSpec)> {
+    // Try to get the file kind from the extension.
+    let extension = Path::new(file).extension().and_then(|extension| extension.to_str());
+    let kind = match extension.map(AudioFileKind::try_from) {
+        Some(Ok(kind)) => kind,
+        _ => {
+            warn!("Unknown audio file type: '{}'", file);
+            return None;
+        }
+    };
+    unpack_audio(get_sfx(file)?, kind)
+}
+
 /// Unpack audio data. The required audio spec is read from the file and returned as well.
-fn unpack_audio(data: Cow<'_, [u8]>, kind: AudioFileKind) -> (Vec<f32>, AudioSpec) {
+fn unpack_audio(data: Cow<'_, [u8]>, kind: AudioFileKind) -> Option<(Vec<f32>, AudioSpec)> {
     match kind {
         AudioFileKind::Ogg => unpack_ogg(data),
         AudioFileKind::Wav => unpack_wav(data),
@@ -154,8 +177,14 @@ fn unpack_audio(data: Cow<'_, [u8]>, kind: AudioFileKind) -> (Vec<f32>, AudioSpe
 
 #[cfg(feature = "ogg")]
 /// Unpack ogg data.
-fn unpack_ogg(data: Cow<'_, [u8]>) -> (Vec<f32>, AudioSpec) {
-    let mut reader = lewton::inside_ogg::OggStreamReader::new(Cursor::new(data.as_ref())).unwrap();
+fn unpack_ogg(data: Cow<'_, [u8]>) -> Option<(Vec<f32>, AudioSpec)> {
+    let mut reader = match lewton::inside_ogg::OggStreamReader::new(Cursor::new(data.as_ref())) {
+        Ok(reader) => reader,
+        Err(e) => {
+            warn!("Couldn't read ogg file: {}", e);
+            return None;
+        }
+    };
     let mut samples = Vec::new();
     while let Ok(Some(mut frame)) = reader.read_dec_packet_itl() {
         samples.append(&mut frame);
@@ -165,19 +194,25 @@ fn unpack_ogg(data: Cow<'_, [u8]>) -> (Vec<f32>, AudioSpec) {
         channels: reader.ident_hdr.audio_channels as u32,
         sample_rate: reader.ident_hdr.audio_sample_rate,
     };
-    (samples, spec)
+    Some((samples, spec))
 }
 
 #[cfg(not(feature = "ogg"))]
 /// Fallback to default sound effect since ogg is disabled.
-fn unpack_ogg(_: Cow<'_, [u8]>) -> (Vec<f32>, AudioSpec) {
+fn unpack_ogg(_: Cow<'_, [u8]>) -> Option<(Vec<f32>, AudioSpec)> {
     warn!("Can't open .ogg without the ogg-feature enabled.");
-    unpack_wav(get_default_sfx())
+    None
 }
 
 /// Unpack wav data.
-fn unpack_wav(data: Cow<'_, [u8]>) -> (Vec<f32>, AudioSpec) {
-    let reader = hound::WavReader::new(data.as_ref()).unwrap();
+fn unpack_wav(data: Cow<'_, [u8]>) -> Option<(Vec<f32>, AudioSpec)> {
+    let reader = match hound::WavReader::new(data.as_ref()) {
+        Ok(reader) => reader,
+        Err(e) => {
+            warn!("Couldn't read wav file: {}", e);
+            return None;
+        }
+    };
     let spec = reader.spec();
     let samples = match spec.sample_format {
         hound::SampleFormat::Float => reader
@@ -193,24 +228,64 @@ fn unpack_wav(data: Cow<'_, [u8]>) -> (Vec<f32>, AudioSpec) {
         channels: spec.channels as u32,
         sample_rate: spec.sample_rate,
     };
-    (samples, spec)
+    Some((samples, spec))
 }
 
-/// Open and return the data contained in a file, or the default sound effect if
-/// the file couldn't be found.
+/// Open and return the data contained in a file, or None if the file couldn't
+/// be found.
 // moo
-fn get_sfx<P: AsRef<Path>>(file: P) -> Cow<'static, [u8]> {
+fn get_sfx<P: AsRef<Path>>(file: P) -> Option<Cow<'static, [u8]>> {
     let mut buf: Vec<u8> = Vec::new();
     if let Ok(mut file) = File::open(file.as_ref()) {
         file.read_to_end(&mut buf).unwrap();
-        Cow::from(buf)
+        Some(Cow::from(buf))
     } else {
         warn!("File not found: '{}'", file.as_ref().display());
-        get_default_sfx()
+        None
     }
 }
 
-/// Get the default sound effect.
-fn get_default_sfx() -> Cow<'static, [u8]> {
-    Cow::from(include_bytes!("fallback_sfx.wav").as_ref())
+/// Synthesizes the default sound effect of an event, so that each event
+/// sounds different without shipping a file for each of them.
+fn default_sound_effect(event: NotificationEvents) -> (Vec<f32>, AudioSpec) {
+    // The frequency in Hz and length in milliseconds of each note. Rising
+    // notes mean that something started and falling notes that it stopped.
+    let notes: &[(f32, u32)] = match event {
+        NotificationEvents::ServerConnect => &[(523.3, 90), (659.3, 90), (784.0, 160)],
+        NotificationEvents::ServerDisconnect => &[(784.0, 90), (659.3, 90), (523.3, 160)],
+        NotificationEvents::UserConnected => &[(659.3, 80), (880.0, 120)],
+        NotificationEvents::UserDisconnected => &[(880.0, 80), (659.3, 120)],
+        NotificationEvents::UserJoinedChannel => &[(587.3, 70), (740.0, 100)],
+        NotificationEvents::UserLeftChannel => &[(740.0, 70), (587.3, 100)],
+        NotificationEvents::Mute => &[(440.0, 90)],
+        NotificationEvents::Unmute => &[(659.3, 90)],
+        NotificationEvents::Deafen => &[(329.6, 70), (329.6, 90)],
+        NotificationEvents::Undeafen => &[(493.9, 70), (493.9, 90)],
+        NotificationEvents::TextMessage => &[(1046.5, 60), (1318.5, 140)],
+        NotificationEvents::Kicked => &[(392.0, 120), (311.1, 120), (261.6, 240)],
+        NotificationEvents::PermissionDenied => &[(220.0, 80), (0.0, 40), (220.0, 80)],
+        NotificationEvents::Whisper => &[(987.8, 120)],
+    };
+    let samples = notes
+        .iter()
+        .flat_map(|&(frequency, length)| note(frequency, length))
+        .collect();
+    let spec = AudioSpec {
+        channels: 1,
+        sample_rate: SAMPLE_RATE,
+    };
+    (samples, spec)
+}
+
+/// A sine wave that fades in quickly and then decays, so that consecutive
+/// notes don't click. A frequency of 0 is a pause.
+fn note(frequency: f32, length: u32) -> impl Iterator<Item = f32> {
+    let len = (SAMPLE_RATE * length / 1000) as usize;
+    // 5 ms
+    let fade = (SAMPLE_RATE / 200) as usize;
+    (0..len).map(move |i| {
+        let t = i as f32 / SAMPLE_RATE as f32;
+        let envelope = (i.min(len - i) as f32 / fade as f32).min(1.0) * (-6.0 * t).exp();
+        0.25 * envelope * (2.0 * PI * frequency * t).sin()
+    })
 }
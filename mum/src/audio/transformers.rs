@@ -1,50 +1,909 @@
+use crate::audio::echo::{EchoCanceller, EchoReference};
+use crate::audio::SAMPLE_RATE;
+
+use mumlib::config::TransformerConfig;
+use realfft::num_complex::Complex;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+
 /// A trait that represents a transform of a audio buffer in some way.
 pub trait Transformer {
     /// Do the transform. Returning `None` is interpreted as "the buffer is unwanted".
     /// The implementor is free to modify the buffer however it wants to.
     fn transform<'a>(&mut self, buf: (opus::Channels, &'a mut [f32])) -> Option<(opus::Channels, &'a mut [f32])>;
+
+    /// Report the state of the transform to a level meter. Most transformers
+    /// don't have anything to report.
+    fn report(&self, _level: &mut InputLevel) {}
+}
+
+/// The level of the microphone input, reported by the transformers to let the
+/// user calibrate them.
+#[derive(Clone, Copy, Debug)]
+pub struct InputLevel {
+    /// The peak level of the last frame in dBFS.
+    pub level: f32,
+    /// Whether the last frame was sent.
+    pub open: bool,
+    /// The current open and close thresholds of the noise gate in dBFS, if
+    /// there is one.
+    pub thresholds: Option<(f32, f32)>,
+}
+
+impl Default for InputLevel {
+    fn default() -> Self {
+        Self {
+            level: f32::NEG_INFINITY,
+            open: false,
+            thresholds: None,
+        }
+    }
 }
 
 /// A struct representing a noise gate transform.
+///
+/// The gate opens when the level rises above the open threshold and closes
+/// when it has been below the close threshold for the hold time. If a range is
+/// set, the thresholds also follow a reference that tracks the peak level and
+/// decays over time, so that the gate adapts to how loud the speaker is
+/// without a single loud sound closing it for good.
 #[derive(Debug)]
 pub struct NoiseGate {
-    alltime_high: f32,
-    open: usize,
-    deactivation_delay: usize,
+    open_threshold: f32,
+    close_threshold: f32,
+    hold: f32,
+    range: Option<f32>,
+    decay: f32,
+
+    reference: f32,
+    level: f32,
+    open: bool,
+    hold_remaining: f32,
 }
 
 impl NoiseGate {
-    /// Create a new noise gate. `deactivation_delay` is defined in terms of
-    /// how many quiet frames it receives before closing the noise gate.
-    pub fn new(deactivation_delay: usize) -> Self {
+    /// Create a new noise gate. The thresholds and range are in dBFS, `hold`
+    /// is in milliseconds and `decay` is in dB per second.
+    pub fn new(
+        open_threshold: f32,
+        close_threshold: f32,
+        hold: f32,
+        range: Option<f32>,
+        decay: f32,
+    ) -> Self {
         Self {
-            alltime_high: 0.0,
-            open: 0,
-            deactivation_delay,
+            open_threshold,
+            close_threshold: close_threshold.min(open_threshold),
+            hold,
+            range,
+            decay,
+            reference: f32::NEG_INFINITY,
+            level: f32::NEG_INFINITY,
+            open: false,
+            hold_remaining: 0.0,
+        }
+    }
+
+    /// The current open and close thresholds.
+    fn thresholds(&self) -> (f32, f32) {
+        match self.range {
+            Some(range) => {
+                let hysteresis = self.open_threshold - self.close_threshold;
+                (
+                    self.open_threshold.max(self.reference - range),
+                    self.close_threshold.max(self.reference - range - hysteresis),
+                )
+            }
+            None => (self.open_threshold, self.close_threshold),
         }
     }
 }
 
 impl Transformer for NoiseGate {
     fn transform<'a>(&mut self, (channels, buf): (opus::Channels, &'a mut [f32])) -> Option<(opus::Channels, &'a mut [f32])> {
-        const MUTE_PERCENTAGE: f32 = 0.1;
+        let frame_ms = buf.len() as f32 / channels as usize as f32 / SAMPLE_RATE as f32 * 1000.0;
+        let max = buf.iter().map(|e| e.abs()).fold(0.0, f32::max);
+        self.level = linear_to_db(max);
+        self.reference = self
+            .level
+            .max(self.reference - self.decay * frame_ms / 1000.0);
+
+        let (open_threshold, close_threshold) = self.thresholds();
+        if self.level >= open_threshold || (self.open && self.level >= close_threshold) {
+            self.open = true;
+            self.hold_remaining = self.hold;
+        } else if self.hold_remaining > 0.0 {
+            self.hold_remaining -= frame_ms;
+        } else {
+            self.open = false;
+        }
+
+        if self.open {
+            Some((channels, buf))
+        } else {
+            None
+        }
+    }
+
+    fn report(&self, level: &mut InputLevel) {
+        level.level = self.level;
+        level.thresholds = Some(self.thresholds());
+    }
+}
+
+/// Creates the transformers described by some configuration, in order.
+pub fn from_config(
+    configs: &[TransformerConfig],
+    echo_reference: &EchoReference,
+) -> Vec<Box<dyn Transformer + Send + 'static>> {
+    configs
+        .iter()
+        .map(|config| -> Box<dyn Transformer + Send + 'static> {
+            match *config {
+                TransformerConfig::NoiseGate {
+                    open_threshold,
+                    close_threshold,
+                    hold,
+                    range,
+                    decay,
+                } => Box::new(NoiseGate::new(
+                    open_threshold.unwrap_or(-40.0),
+                    close_threshold.unwrap_or(-46.0),
+                    hold.unwrap_or(500.0),
+                    Some(range.unwrap_or(30.0)).filter(|range| *range > 0.0),
+                    decay.unwrap_or(6.0),
+                )),
+                TransformerConfig::Agc { target, max_gain } => Box::new(Agc::new(
+                    target.unwrap_or(-18.0),
+                    max_gain.unwrap_or(20.0),
+                )),
+                TransformerConfig::Compressor {
+                    threshold,
+                    ratio,
+                    attack,
+                    release,
+                    makeup_gain,
+                } => Box::new(Compressor::new(
+                    threshold.unwrap_or(-20.0),
+                    ratio.unwrap_or(4.0),
+                    attack.unwrap_or(5.0),
+                    release.unwrap_or(50.0),
+                    makeup_gain.unwrap_or(0.0),
+                )),
+                TransformerConfig::Limiter { threshold, release } => Box::new(Compressor::new(
+                    threshold.unwrap_or(-1.0),
+                    f32::INFINITY,
+                    0.0,
+                    release.unwrap_or(50.0),
+                    0.0,
+                )),
+                TransformerConfig::HighPass { cutoff } => {
+                    Box::new(HighPass::new(cutoff.unwrap_or(80.0)))
+                }
+                TransformerConfig::DeEsser {
+                    frequency,
+                    threshold,
+                    max_reduction,
+                } => Box::new(DeEsser::new(
+                    frequency.unwrap_or(6000.0),
+                    threshold.unwrap_or(-30.0),
+                    max_reduction.unwrap_or(12.0),
+                )),
+                TransformerConfig::NoiseSuppressor {
+                    reduction,
+                    over_subtraction,
+                    adaptation,
+                } => Box::new(NoiseSuppressor::new(
+                    reduction.unwrap_or(20.0),
+                    over_subtraction.unwrap_or(2.0),
+                    adaptation.unwrap_or(2.0),
+                )),
+                TransformerConfig::EchoCanceller {
+                    filter_length,
+                    max_delay,
+                } => Box::new(EchoCanceller::new(
+                    echo_reference.clone(),
+                    filter_length.unwrap_or(100.0),
+                    max_delay.unwrap_or(500.0),
+                )),
+            }
+        })
+        .collect()
+}
+
+type Transformers = Vec<Box<dyn Transformer + Send + 'static>>;
 
-        let max = buf.iter().map(|e| e.abs()).max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
+/// How the input stream is transformed. When it's reconfigured, the new
+/// transformers are built here instead of in the audio callback, which
+/// shouldn't allocate or block. They're picked up by the [TransformerChain]
+/// of the stream, which leaves the transformers they replaced behind so that
+/// they're dropped here as well.
+pub struct TransformerConfigs {
+    config: Vec<TransformerConfig>,
+    echo_reference: EchoReference,
+    new: Option<Transformers>,
+    old: Option<Transformers>,
+}
 
-        if max > self.alltime_high {
-            self.alltime_high = max;
+impl TransformerConfigs {
+    pub fn new(config: Vec<TransformerConfig>, echo_reference: EchoReference) -> Self {
+        Self {
+            config,
+            echo_reference,
+            new: None,
+            old: None,
         }
+    }
+
+    /// Builds new transformers for the stream if the config changed.
+    pub fn set(configs: &Mutex<Self>, config: Vec<TransformerConfig>) {
+        let echo_reference = {
+            let configs = configs.lock().unwrap();
+            if configs.config == config {
+                return;
+            }
+            configs.echo_reference.clone()
+        };
+        let transformers = from_config(&config, &echo_reference);
+        let mut configs = configs.lock().unwrap();
+        configs.config = config;
+        configs.new = Some(transformers);
+        configs.old = None;
+    }
+}
+
+impl Debug for TransformerConfigs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TransformerConfigs")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+/// The transformers of the input stream, which are replaced when they're
+/// reconfigured.
+pub struct TransformerChain {
+    configs: Arc<Mutex<TransformerConfigs>>,
+    transformers: Transformers,
+}
 
-        if max > self.alltime_high * MUTE_PERCENTAGE {
-            self.open = self.deactivation_delay;
-        } else if self.open > 0 {
-            self.open -= 1;
+impl TransformerChain {
+    /// Builds the currently configured transformers.
+    pub fn new(configs: Arc<Mutex<TransformerConfigs>>) -> Self {
+        let transformers = {
+            let configs = configs.lock().unwrap();
+            from_config(&configs.config, &configs.echo_reference)
+        };
+        Self {
+            configs,
+            transformers,
         }
+    }
 
-        if self.open == 0 {
-            None
-        } else {
-            Some((channels, buf))
+    /// Returns the current transformers. It doesn't block, allocate or
+    /// deallocate, so it can be called in the audio callback.
+    pub fn transformers(&mut self) -> &mut [Box<dyn Transformer + Send + 'static>] {
+        // If the new transformers are being set right now, they're picked up
+        // on the next call instead.
+        if let Ok(mut configs) = self.configs.try_lock() {
+            if let Some(new) = configs.new.take() {
+                configs.old = Some(std::mem::replace(&mut self.transformers, new));
+            }
+        }
+        &mut self.transformers
+    }
+}
+
+impl Debug for TransformerChain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TransformerChain")
+            .field("configs", &self.configs)
+            .finish()
+    }
+}
+
+/// The transformers that are used if none are configured.
+pub fn default_config() -> Vec<TransformerConfig> {
+    vec![TransformerConfig::NoiseGate {
+        open_threshold: None,
+        close_threshold: None,
+        hold: None,
+        range: None,
+        decay: None,
+    }]
+}
+
+fn db_to_linear(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.0)
+}
+
+pub(crate) fn linear_to_db(linear: f32) -> f32 {
+    20.0 * linear.max(1e-10).log10()
+}
+
+/// The coefficient of a one-pole smoothing filter with the specified time
+/// constant, applied once per sample.
+fn smoothing_coefficient(ms: f32) -> f32 {
+    if ms <= 0.0 {
+        0.0
+    } else {
+        (-1.0 / (ms * 0.001 * SAMPLE_RATE as f32)).exp()
+    }
+}
+
+/// A second order high-pass filter. Keeps separate state for each channel.
+#[derive(Debug)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    state: Vec<(f32, f32)>,
+}
+
+impl Biquad {
+    /// A Butterworth high-pass filter, as described in Robert
+    /// Bristow-Johnson's Audio EQ Cookbook.
+    fn high_pass(cutoff: f32) -> Self {
+        let w0 = 2.0 * PI * cutoff / SAMPLE_RATE as f32;
+        let alpha = w0.sin() / (2.0 * std::f32::consts::FRAC_1_SQRT_2);
+        let cos_w0 = w0.cos();
+        let a0 = 1.0 + alpha;
+        Self {
+            b0: (1.0 + cos_w0) / 2.0 / a0,
+            b1: -(1.0 + cos_w0) / a0,
+            b2: (1.0 + cos_w0) / 2.0 / a0,
+            a1: -2.0 * cos_w0 / a0,
+            a2: (1.0 - alpha) / a0,
+            state: Vec::new(),
+        }
+    }
+
+    fn process(&mut self, channel: usize, x: f32) -> f32 {
+        if self.state.len() <= channel {
+            self.state.resize(channel + 1, (0.0, 0.0));
+        }
+        // Transposed direct form II.
+        let (z1, z2) = &mut self.state[channel];
+        let y = self.b0 * x + *z1;
+        *z1 = self.b1 * x - self.a1 * y + *z2;
+        *z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+/// A transform that removes frequencies below some cutoff.
+#[derive(Debug)]
+pub struct HighPass {
+    filter: Biquad,
+}
+
+impl HighPass {
+    /// Create a new high-pass filter with a cutoff frequency in Hz.
+    pub fn new(cutoff: f32) -> Self {
+        Self {
+            filter: Biquad::high_pass(cutoff),
+        }
+    }
+}
+
+impl Transformer for HighPass {
+    fn transform<'a>(&mut self, (channels, buf): (opus::Channels, &'a mut [f32])) -> Option<(opus::Channels, &'a mut [f32])> {
+        let num_channels = channels as usize;
+        for (i, sample) in buf.iter_mut().enumerate() {
+            *sample = self.filter.process(i % num_channels, *sample);
+        }
+        Some((channels, buf))
+    }
+}
+
+/// A transform that adjusts the gain so that the audio is close to a target
+/// level.
+#[derive(Debug)]
+pub struct Agc {
+    target: f32,
+    max_gain: f32,
+    gain: f32,
+}
+
+impl Agc {
+    /// Create a new automatic gain control. `target` is the level in dBFS to
+    /// aim for and `max_gain` is the maximum gain in dB.
+    pub fn new(target: f32, max_gain: f32) -> Self {
+        Self {
+            target,
+            max_gain,
+            gain: 0.0,
+        }
+    }
+}
+
+impl Transformer for Agc {
+    fn transform<'a>(&mut self, (channels, buf): (opus::Channels, &'a mut [f32])) -> Option<(opus::Channels, &'a mut [f32])> {
+        // Frames quieter than this are considered silence and don't affect the gain.
+        const SILENCE: f32 = -60.0;
+        // How much of the way to the wanted gain we move each frame.
+        const ATTACK: f32 = 0.5;
+        const RELEASE: f32 = 0.02;
+
+        if buf.is_empty() {
+            return Some((channels, buf));
+        }
+        let rms = (buf.iter().map(|e| e * e).sum::<f32>() / buf.len() as f32).sqrt();
+        let level = linear_to_db(rms);
+        if level > SILENCE {
+            let wanted = (self.target - level).min(self.max_gain);
+            // Lower the gain quickly to avoid clipping, but raise it slowly to
+            // avoid pumping.
+            let speed = if wanted < self.gain { ATTACK } else { RELEASE };
+            self.gain += (wanted - self.gain) * speed;
+        }
+
+        let gain = db_to_linear(self.gain);
+        for sample in buf.iter_mut() {
+            *sample = (*sample * gain).clamp(-1.0, 1.0);
+        }
+        Some((channels, buf))
+    }
+}
+
+/// A transform that reduces the level of audio above a threshold. With an
+/// infinite ratio it acts as a limiter.
+#[derive(Debug)]
+pub struct Compressor {
+    threshold: f32,
+    ratio: f32,
+    attack: f32,
+    release: f32,
+    makeup_gain: f32,
+    envelope: f32,
+}
+
+impl Compressor {
+    /// Create a new compressor. `threshold` is in dBFS, `attack` and `release`
+    /// are in milliseconds and `makeup_gain` is in dB.
+    pub fn new(threshold: f32, ratio: f32, attack: f32, release: f32, makeup_gain: f32) -> Self {
+        Self {
+            threshold,
+            ratio: ratio.max(1.0),
+            attack: smoothing_coefficient(attack),
+            release: smoothing_coefficient(release),
+            makeup_gain,
+            envelope: 0.0,
+        }
+    }
+}
+
+impl Transformer for Compressor {
+    fn transform<'a>(&mut self, (channels, buf): (opus::Channels, &'a mut [f32])) -> Option<(opus::Channels, &'a mut [f32])> {
+        // All channels share the envelope so the stereo image is kept.
+        for frame in buf.chunks_mut(channels as usize) {
+            let peak = frame.iter().map(|e| e.abs()).fold(0.0, f32::max);
+            let coefficient = if peak > self.envelope {
+                self.attack
+            } else {
+                self.release
+            };
+            self.envelope = coefficient * self.envelope + (1.0 - coefficient) * peak;
+
+            let over = linear_to_db(self.envelope) - self.threshold;
+            let reduction = if over > 0.0 {
+                over * (1.0 - 1.0 / self.ratio)
+            } else {
+                0.0
+            };
+            let gain = db_to_linear(self.makeup_gain - reduction);
+            for sample in frame.iter_mut() {
+                *sample *= gain;
+            }
+        }
+        Some((channels, buf))
+    }
+}
+
+/// A transform that keeps audio below a threshold without distorting it. The
+/// audio is delayed so that the gain can be lowered smoothly before a peak
+/// arrives.
+#[derive(Debug)]
+pub struct Limiter {
+    /// The highest allowed level as a linear value.
+    threshold: f32,
+    release: f32,
+    /// How many frames the limiter looks ahead.
+    lookahead: usize,
+    /// The delayed audio.
+    delay: VecDeque<f32>,
+    /// The gain needed for each of the last `lookahead` frames, together with
+    /// their index, keeping only the ones that could be the minimum of the
+    /// window.
+    required: VecDeque<(u64, f32)>,
+    /// The minimum required gain of the last `lookahead` windows.
+    window_minimums: VecDeque<f32>,
+    window_sum: f64,
+    frame_index: u64,
+    gain: f32,
+}
+
+impl Limiter {
+    /// Create a new limiter. `threshold` is in dBFS, `lookahead` and `release`
+    /// are in milliseconds.
+    pub fn new(threshold: f32, lookahead: f32, release: f32, channels: usize) -> Self {
+        let lookahead = ((lookahead.max(0.0) * 0.001 * SAMPLE_RATE as f32) as usize).max(1);
+        Self {
+            threshold: db_to_linear(threshold.min(0.0)),
+            release: smoothing_coefficient(release),
+            lookahead,
+            delay: vec![0.0; (lookahead - 1) * channels].into(),
+            required: VecDeque::new(),
+            window_minimums: vec![1.0; lookahead].into(),
+            window_sum: lookahead as f64,
+            frame_index: 0,
+            gain: 1.0,
+        }
+    }
+}
+
+impl Transformer for Limiter {
+    fn transform<'a>(&mut self, (channels, buf): (opus::Channels, &'a mut [f32])) -> Option<(opus::Channels, &'a mut [f32])> {
+        for frame in buf.chunks_mut(channels as usize) {
+            let peak = frame.iter().map(|e| e.abs()).fold(0.0, f32::max);
+            let required = if peak > self.threshold {
+                self.threshold / peak
+            } else {
+                1.0
+            };
+
+            // The minimum required gain of the frames in the window.
+            while matches!(self.required.back(), Some((_, gain)) if *gain >= required) {
+                self.required.pop_back();
+            }
+            self.required.push_back((self.frame_index, required));
+            while self.required.front().unwrap().0 + self.lookahead as u64 <= self.frame_index {
+                self.required.pop_front();
+            }
+            self.frame_index += 1;
+            let minimum = self.required.front().unwrap().1;
+
+            // Averaging the minimums of the windows gives a smooth gain that is
+            // still low enough for every frame in them, as long as the audio is
+            // delayed by one frame less than the window.
+            self.window_sum += (minimum - self.window_minimums.pop_front().unwrap()) as f64;
+            self.window_minimums.push_back(minimum);
+            let target = ((self.window_sum / self.lookahead as f64) as f32).min(1.0);
+            self.gain = if target < self.gain {
+                target
+            } else {
+                self.release * self.gain + (1.0 - self.release) * target
+            };
+
+            for sample in frame.iter_mut() {
+                self.delay.push_back(*sample);
+                let delayed = self.delay.pop_front().unwrap();
+                *sample = (delayed * self.gain).clamp(-1.0, 1.0);
+            }
+        }
+        Some((channels, buf))
+    }
+}
+
+/// A transform that reduces the level of high frequencies while they're loud.
+#[derive(Debug)]
+pub struct DeEsser {
+    filter: Biquad,
+    threshold: f32,
+    max_reduction: f32,
+    attack: f32,
+    release: f32,
+    envelope: f32,
+}
+
+impl DeEsser {
+    /// Create a new de-esser. Sibilance is detected above `frequency` (in Hz)
+    /// and reduced by at most `max_reduction` dB when it's louder than
+    /// `threshold` dBFS.
+    pub fn new(frequency: f32, threshold: f32, max_reduction: f32) -> Self {
+        Self {
+            filter: Biquad::high_pass(frequency),
+            threshold,
+            max_reduction,
+            attack: smoothing_coefficient(1.0),
+            release: smoothing_coefficient(60.0),
+            envelope: 0.0,
+        }
+    }
+}
+
+impl Transformer for DeEsser {
+    fn transform<'a>(&mut self, (channels, buf): (opus::Channels, &'a mut [f32])) -> Option<(opus::Channels, &'a mut [f32])> {
+        const RATIO: f32 = 4.0;
+
+        let num_channels = channels as usize;
+        // The high band of the current frame. Opus audio has at most two
+        // channels, so it fits on the stack.
+        let mut high = [0.0; 2];
+        let high = &mut high[..num_channels];
+        for frame in buf.chunks_mut(num_channels) {
+            for (channel, sample) in frame.iter().enumerate() {
+                high[channel] = self.filter.process(channel, *sample);
+            }
+            let peak = high.iter().map(|e| e.abs()).fold(0.0, f32::max);
+            let coefficient = if peak > self.envelope {
+                self.attack
+            } else {
+                self.release
+            };
+            self.envelope = coefficient * self.envelope + (1.0 - coefficient) * peak;
+
+            let over = linear_to_db(self.envelope) - self.threshold;
+            if over > 0.0 {
+                let reduction = (over * (1.0 - 1.0 / RATIO)).min(self.max_reduction);
+                let gain = db_to_linear(-reduction);
+                // Only the high band is attenuated. The low band is whatever
+                // the high-pass filter removed.
+                for (sample, high) in frame.iter_mut().zip(high.iter()) {
+                    *sample = (*sample - high) + high * gain;
+                }
+            }
+        }
+        Some((channels, buf))
+    }
+}
+
+/// The length of the frames the noise suppressor analyzes. 10 ms at 48 kHz.
+const SUPPRESSOR_FRAME_SIZE: usize = 480;
+/// How far apart the analyzed frames are. Every frame overlaps half of the
+/// previous one.
+const SUPPRESSOR_HOP_SIZE: usize = SUPPRESSOR_FRAME_SIZE / 2;
+
+/// A transform that removes stationary background noise like fans and air
+/// conditioning using spectral subtraction.
+///
+/// The noise spectrum is estimated by tracking the minimum of the spectrum,
+/// which is allowed to rise slowly so that the estimate follows changing
+/// noise. Each frequency is then attenuated by how much of it is estimated to
+/// be noise. The audio is delayed by one frame.
+pub struct NoiseSuppressor {
+    floor: f32,
+    over_subtraction: f32,
+    rise: f32,
+
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    window: Vec<f32>,
+    frame: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    channels: Vec<SuppressorChannel>,
+}
+
+/// The state of the noise suppressor for a single channel.
+struct SuppressorChannel {
+    /// Samples that haven't been analyzed yet, including the overlap with the
+    /// previous frame.
+    input: Vec<f32>,
+    /// Processed samples that are ready to be returned.
+    output: VecDeque<f32>,
+    /// The second half of the previous frame, which is added to the first half
+    /// of the next one.
+    overlap: Vec<f32>,
+    power: Vec<f32>,
+    noise: Vec<f32>,
+    gain: Vec<f32>,
+    clean: Vec<f32>,
+}
+
+impl SuppressorChannel {
+    fn new() -> Self {
+        let bins = SUPPRESSOR_FRAME_SIZE / 2 + 1;
+        Self {
+            input: Vec::with_capacity(SUPPRESSOR_FRAME_SIZE),
+            // Fill the output with enough silence that a sample can be
+            // returned for every sample that is received.
+            output: vec![0.0; SUPPRESSOR_FRAME_SIZE].into(),
+            overlap: vec![0.0; SUPPRESSOR_HOP_SIZE],
+            power: vec![0.0; bins],
+            noise: vec![f32::MAX; bins],
+            gain: vec![1.0; bins],
+            clean: vec![0.0; bins],
+        }
+    }
+}
+
+impl NoiseSuppressor {
+    /// Create a new noise suppressor. Noise is reduced by at most `reduction`
+    /// dB. `over_subtraction` scales the estimated noise before it's
+    /// subtracted, trading speech quality for less residual noise.
+    /// `adaptation` is the time in seconds it takes for the noise estimate to
+    /// rise by 10 dB.
+    pub fn new(reduction: f32, over_subtraction: f32, adaptation: f32) -> Self {
+        let mut planner = RealFftPlanner::new();
+        let forward = planner.plan_fft_forward(SUPPRESSOR_FRAME_SIZE);
+        let inverse = planner.plan_fft_inverse(SUPPRESSOR_FRAME_SIZE);
+        // A square root Hann window is applied both before and after
+        // processing. The windows of overlapping frames sum to one.
+        let window = (0..SUPPRESSOR_FRAME_SIZE)
+            .map(|i| (PI * i as f32 / SUPPRESSOR_FRAME_SIZE as f32).sin())
+            .collect();
+        let hops_per_second = SAMPLE_RATE as f32 / SUPPRESSOR_HOP_SIZE as f32;
+        Self {
+            floor: db_to_linear(-reduction),
+            over_subtraction,
+            rise: 10.0_f32.powf(1.0 / (adaptation.max(0.01) * hops_per_second)),
+            frame: forward.make_input_vec(),
+            spectrum: forward.make_output_vec(),
+            forward,
+            inverse,
+            window,
+            channels: Vec::new(),
+        }
+    }
+
+    /// Analyzes and suppresses the noise of a full frame in `channel.input`.
+    fn process_frame(&mut self, channel: usize) {
+        const POWER_SMOOTHING: f32 = 0.9;
+        const DECISION_DIRECTED: f32 = 0.98;
+        // The minimum of the smoothed power is lower than the average power of
+        // the noise.
+        const MINIMUM_BIAS: f32 = 1.5;
+
+        let state = &mut self.channels[channel];
+        for ((frame, input), window) in self.frame.iter_mut().zip(&state.input).zip(&self.window) {
+            *frame = input * window;
+        }
+        self.forward
+            .process(&mut self.frame, &mut self.spectrum)
+            .unwrap();
+
+        for (bin, value) in self.spectrum.iter_mut().enumerate() {
+            let power = value.norm_sqr();
+            state.power[bin] = POWER_SMOOTHING * state.power[bin] + (1.0 - POWER_SMOOTHING) * power;
+            state.noise[bin] = (state.noise[bin] * self.rise).min(state.power[bin]);
+
+            // The gain is based on a "decision-directed" estimate of the
+            // signal-to-noise ratio, which mostly relies on the previous
+            // frame. This avoids "musical noise", short tones left where the
+            // noise happened to be louder than estimated.
+            let noise = (self.over_subtraction * MINIMUM_BIAS * state.noise[bin]).max(f32::MIN_POSITIVE);
+            let snr = DECISION_DIRECTED * state.clean[bin] / noise
+                + (1.0 - DECISION_DIRECTED) * (power / noise - 1.0).max(0.0);
+            state.gain[bin] = (snr / (1.0 + snr)).max(self.floor);
+            state.clean[bin] = state.gain[bin] * state.gain[bin] * power;
+            *value *= state.gain[bin];
+        }
+        // The inverse transform requires these to be real.
+        self.spectrum[0].im = 0.0;
+        self.spectrum[SUPPRESSOR_FRAME_SIZE / 2].im = 0.0;
+        self.inverse
+            .process(&mut self.spectrum, &mut self.frame)
+            .unwrap();
+
+        let scale = 1.0 / SUPPRESSOR_FRAME_SIZE as f32;
+        let (first, second) = self.frame.split_at(SUPPRESSOR_HOP_SIZE);
+        let (first_window, second_window) = self.window.split_at(SUPPRESSOR_HOP_SIZE);
+        for ((overlap, sample), window) in state.overlap.iter().zip(first).zip(first_window) {
+            state.output.push_back(overlap + sample * window * scale);
+        }
+        for ((overlap, sample), window) in state.overlap.iter_mut().zip(second).zip(second_window) {
+            *overlap = sample * window * scale;
+        }
+        state.input.drain(..SUPPRESSOR_HOP_SIZE);
+    }
+}
+
+impl Transformer for NoiseSuppressor {
+    fn transform<'a>(&mut self, (channels, buf): (opus::Channels, &'a mut [f32])) -> Option<(opus::Channels, &'a mut [f32])> {
+        let num_channels = channels as usize;
+        if self.channels.len() != num_channels {
+            self.channels = (0..num_channels).map(|_| SuppressorChannel::new()).collect();
+        }
+
+        for channel in 0..num_channels {
+            for sample in buf.iter().skip(channel).step_by(num_channels) {
+                self.channels[channel].input.push(*sample);
+                if self.channels[channel].input.len() == SUPPRESSOR_FRAME_SIZE {
+                    self.process_frame(channel);
+                }
+            }
+            let output = &mut self.channels[channel].output;
+            for sample in buf.iter_mut().skip(channel).step_by(num_channels) {
+                *sample = output.pop_front().unwrap_or(0.0);
+            }
+        }
+        Some((channels, buf))
+    }
+}
+
+impl Debug for NoiseSuppressor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NoiseSuppressor")
+            .field("floor", &self.floor)
+            .field("over_subtraction", &self.over_subtraction)
+            .field("rise", &self.rise)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chain_picks_up_new_transformers() {
+        let configs = Arc::new(Mutex::new(TransformerConfigs::new(
+            default_config(),
+            EchoReference::new(),
+        )));
+        let mut chain = TransformerChain::new(Arc::clone(&configs));
+        assert_eq!(chain.transformers().len(), 1);
+
+        TransformerConfigs::set(&configs, Vec::new());
+        assert_eq!(chain.transformers().len(), 0);
+        // The replaced transformers are dropped the next time they're set.
+        assert_eq!(configs.lock().unwrap().old.as_ref().map(Vec::len), Some(1));
+        TransformerConfigs::set(&configs, default_config());
+        assert!(configs.lock().unwrap().old.is_none());
+        assert_eq!(chain.transformers().len(), 1);
+    }
+
+    #[test]
+    fn limiter_lowers_gain_before_step() {
+        const STEP: usize = 2400;
+        let threshold = db_to_linear(-6.0);
+        let mut limiter = Limiter::new(-6.0, 5.0, 100.0, 1);
+        let delay = limiter.lookahead - 1;
+        let mut buf: Vec<f32> = (0..STEP * 2)
+            .map(|i| if i < STEP { 0.1 } else { 0.9 })
+            .collect();
+        limiter.transform((opus::Channels::Mono, &mut buf));
+
+        // The quiet part is untouched until the step is seen.
+        assert!(buf[delay..STEP].iter().all(|sample| (sample - 0.1).abs() < 1e-6));
+        // The gain has reached its target when the step is played.
+        assert!(
+            (buf[STEP + delay] - threshold).abs() < 1e-4,
+            "step played at {} instead of {}",
+            buf[STEP + delay],
+            threshold
+        );
+        // It was lowered gradually while the quiet part was played.
+        assert!(buf[STEP..STEP + delay].windows(2).all(|w| w[1] < w[0]));
+    }
+
+    #[test]
+    fn limiter_never_exceeds_threshold() {
+        const CHUNK: usize = 960;
+        let threshold = db_to_linear(-3.0);
+        let mut seed: u32 = 1;
+        let mut random = move || {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            (seed >> 8) as f32 / (1 << 24) as f32
+        };
+        // Stereo noise with a level that jumps around every 5 ms.
+        let mut level = 0.0;
+        let input: Vec<f32> = (0..SAMPLE_RATE as usize * 2)
+            .map(|i| {
+                if i % 480 == 0 {
+                    level = 2.0 * random();
+                }
+                level * (random() * 2.0 - 1.0)
+            })
+            .collect();
+
+        let mut limiter = Limiter::new(-3.0, 5.0, 50.0, 2);
+        for chunk in input.chunks(CHUNK) {
+            let mut buf = chunk.to_vec();
+            limiter.transform((opus::Channels::Stereo, &mut buf));
+            for sample in buf {
+                assert!(
+                    sample.abs() <= threshold + 1e-5,
+                    "{} is above the threshold {}",
+                    sample,
+                    threshold
+                );
+            }
         }
     }
 }
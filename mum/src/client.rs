@@ -1,10 +1,11 @@
+use crate::audio;
 use crate::error::ClientError;
-use crate::network::{tcp, udp, ConnectionInfo};
-use crate::state::State;
+use crate::network::{tcp, udp, udp::CryptStateUpdate, ConnectionInfo};
+use crate::state::{self, State};
 use crate::{command, network::tcp::TcpEventQueue};
 
 use futures_util::{select, FutureExt};
-use mumble_protocol::{control::ControlPacket, crypt::ClientCryptState, Serverbound};
+use mumble_protocol::{control::ControlPacket, Serverbound};
 use mumlib::command::{Command, CommandResponse};
 use std::sync::{Arc, RwLock};
 use tokio::sync::{mpsc, watch};
@@ -18,7 +19,7 @@ pub async fn handle(
 ) -> Result<(), ClientError> {
     let (connection_info_sender, connection_info_receiver) =
         watch::channel::<Option<ConnectionInfo>>(None);
-    let (crypt_state_sender, crypt_state_receiver) = mpsc::channel::<ClientCryptState>(1);
+    let (crypt_state_sender, crypt_state_receiver) = mpsc::channel::<CryptStateUpdate>(1);
     let (packet_sender, packet_receiver) = mpsc::unbounded_channel::<ControlPacket<Serverbound>>();
     let (ping_request_sender, ping_request_receiver) = mpsc::unbounded_channel();
     let event_queue = TcpEventQueue::new();
@@ -38,7 +39,11 @@ pub async fn handle(
             Arc::clone(&state),
             connection_info_receiver.clone(),
             crypt_state_receiver,
+            packet_sender.clone(),
         ).fuse() => Ok(()),
+        _ = audio::handle_loopback(Arc::clone(&state)).fuse() => Ok(()),
+        _ = audio::handle_devices(Arc::clone(&state)).fuse() => Ok(()),
+        _ = state::handle_talking(Arc::clone(&state)).fuse() => Ok(()),
         _ = command::handle(
             state,
             command_receiver,
@@ -1,6 +1,7 @@
 use crate::network::{tcp::TcpEventQueue, udp::PingRequest, ConnectionInfo};
 use crate::state::{ExecutionContext, State};
 
+use futures_util::StreamExt;
 use log::*;
 use mumble_protocol::{control::ControlPacket, Serverbound};
 use mumlib::command::{Command, CommandResponse};
@@ -60,6 +61,16 @@ pub async fn handle(
                 }
                 drop(response_sender);
             }
+            ExecutionContext::Stream(mut stream) => {
+                tokio::spawn(async move {
+                    while let Some(response) = stream.next().await {
+                        if response_sender.send(response).is_err() {
+                            // The client has disconnected.
+                            break;
+                        }
+                    }
+                });
+            }
             ExecutionContext::Ping(generator, converter) => {
                 let ret = generator();
                 debug!("Ping generated: {:?}", ret);
@@ -68,7 +68,7 @@ impl fmt::Display for ClientError {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum AudioStream {
     Input,
     Output,
@@ -110,6 +110,34 @@ impl fmt::Display for AudioError {
     }
 }
 
+#[derive(Debug)]
+pub enum NotificationError {
+    #[cfg(feature = "notifications")]
+    Libnotify(String),
+    #[cfg(feature = "dbus")]
+    Dbus(dbus::Error),
+    Command(std::io::Error),
+}
+
+impl fmt::Display for NotificationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "notifications")]
+            NotificationError::Libnotify(e) => write!(f, "libnotify error: {}", e),
+            #[cfg(feature = "dbus")]
+            NotificationError::Dbus(e) => write!(f, "D-Bus error: {}", e),
+            NotificationError::Command(e) => write!(f, "Couldn't run notification command: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "dbus")]
+impl From<dbus::Error> for NotificationError {
+    fn from(e: dbus::Error) -> Self {
+        NotificationError::Dbus(e)
+    }
+}
+
 #[derive(Debug)]
 pub enum StateError {
     AudioError(AudioError),
@@ -0,0 +1,54 @@
+use log::*;
+use mumlib::command::MumbleEventKind;
+use mumlib::config::HookConfig;
+use std::process::Stdio;
+use std::time::Duration;
+
+/// How long a hook may run if it doesn't configure a timeout.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs the hooks that are configured for an event in the background.
+///
+/// Hooks are run with `sh -c`. The name of the event is passed in MUM_EVENT
+/// and the values of the event in variables like MUM_USER and MUM_CHANNEL.
+/// Hooks that run for longer than their timeout are killed.
+pub fn run(hooks: &[HookConfig], event: &MumbleEventKind) {
+    for hook in hooks.iter().filter(|hook| hook.event == event.name()) {
+        let mut command = tokio::process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg(&hook.command)
+            .env("MUM_EVENT", event.name())
+            .envs(
+                event
+                    .placeholders()
+                    .into_iter()
+                    .map(|(name, value)| (format!("MUM_{}", name.to_uppercase()), value.into_owned())),
+            )
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Couldn't run hook {:?}: {}", hook.command, e);
+                continue;
+            }
+        };
+        let hook_command = hook.command.clone();
+        let timeout = hook
+            .timeout
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_TIMEOUT);
+        tokio::spawn(async move {
+            match tokio::time::timeout(timeout, child.wait()).await {
+                Ok(Ok(status)) if !status.success() => {
+                    warn!("Hook {:?} exited with {}", hook_command, status)
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => warn!("Hook {:?} failed: {}", hook_command, e),
+                // The child is killed when it's dropped.
+                Err(_) => warn!("Hook {:?} timed out and was killed", hook_command),
+            }
+        });
+    }
+}
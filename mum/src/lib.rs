@@ -17,6 +17,7 @@ pub mod audio;
 pub mod client;
 pub mod command;
 pub mod error;
+pub mod hooks;
 pub mod network;
 pub mod notifications;
 pub mod state;
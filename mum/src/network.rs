@@ -3,6 +3,9 @@ pub mod udp;
 
 use futures_util::FutureExt;
 use log::*;
+use mumble_protocol::control::msgs;
+use mumlib::state::PacketStats;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{future::Future, net::SocketAddr};
 use tokio::{
     select,
@@ -34,6 +37,98 @@ pub enum VoiceStreamType {
     Udp,
 }
 
+/// Running average and variance of ping round-trip times.
+#[derive(Clone, Debug, Default)]
+struct PingStats {
+    count: u32,
+    mean: f64,
+    m2: f64,
+}
+
+impl PingStats {
+    fn push(&mut self, rtt: Duration) {
+        // Welford's online algorithm.
+        let ms = rtt.as_secs_f64() * 1000.0;
+        self.count += 1;
+        let delta = ms - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (ms - self.mean);
+    }
+
+    fn average(&self) -> f32 {
+        self.mean as f32
+    }
+
+    fn variance(&self) -> f32 {
+        if self.count > 1 {
+            (self.m2 / (self.count - 1) as f64) as f32
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Statistics about our own connection to the server. Some of these are sent
+/// back to the server in our pings.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionStats {
+    tcp_ping: PingStats,
+    udp_ping: PingStats,
+    /// Voice packets received by the server from us, as reported by the server.
+    pub from_client: PacketStats,
+    /// Voice packets received by us from the server.
+    pub from_server: PacketStats,
+}
+
+impl ConnectionStats {
+    pub fn push_tcp_ping(&mut self, rtt: Duration) {
+        self.tcp_ping.push(rtt);
+    }
+
+    pub fn push_udp_ping(&mut self, rtt: Duration) {
+        self.udp_ping.push(rtt);
+    }
+
+    /// Fills in the statistics that the server expects in our pings.
+    pub fn fill_ping(&self, msg: &mut msgs::Ping) {
+        msg.set_good(self.from_server.good);
+        msg.set_late(self.from_server.late);
+        msg.set_lost(self.from_server.lost);
+        msg.set_resync(self.from_server.resync);
+        msg.set_tcp_packets(self.tcp_ping.count);
+        msg.set_udp_packets(self.udp_ping.count);
+        msg.set_tcp_ping_avg(self.tcp_ping.average());
+        msg.set_tcp_ping_var(self.tcp_ping.variance());
+        msg.set_udp_ping_avg(self.udp_ping.average());
+        msg.set_udp_ping_var(self.udp_ping.variance());
+    }
+
+    pub fn to_mumlib(&self, udp: bool) -> mumlib::state::ConnectionStats {
+        mumlib::state::ConnectionStats {
+            tcp_ping: (self.tcp_ping.average(), self.tcp_ping.variance()),
+            udp_ping: (self.udp_ping.average(), self.udp_ping.variance()),
+            tcp_packets: self.tcp_ping.count,
+            udp_packets: self.udp_ping.count,
+            from_client: self.from_client,
+            from_server: self.from_server,
+            udp,
+        }
+    }
+}
+
+/// Returns the current time in microseconds, used to timestamp pings.
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// Returns the round-trip time of a ping that was sent at `timestamp`.
+fn rtt(timestamp: u64) -> Duration {
+    Duration::from_micros(self::timestamp().saturating_sub(timestamp))
+}
+
 async fn run_until<F, R>(
     phase_checker: impl Fn(StatePhase) -> bool,
     fut: F,
@@ -1,7 +1,9 @@
+use crate::audio::positional;
+use crate::audio::sound_effects::NotificationEvents;
 use crate::error::{ServerSendError, TcpError};
+use crate::network::udp::CryptStateUpdate;
 use crate::network::ConnectionInfo;
-use crate::notifications;
-use crate::state::{State, StatePhase};
+use crate::state::{blob, read_avatar, State, StatePhase};
 
 use futures_util::select;
 use futures_util::stream::{SplitSink, SplitStream, Stream};
@@ -23,7 +25,7 @@ use tokio::time::{self, Duration};
 use tokio_native_tls::{TlsConnector, TlsStream};
 use tokio_util::codec::{Decoder, Framed};
 
-use super::{run_until, VoiceStreamType};
+use super::{rtt, run_until, timestamp, VoiceStreamType};
 
 type TcpSender = SplitSink<
     Framed<TlsStream<TcpStream>, ControlCodec<Serverbound, Clientbound>>,
@@ -49,6 +51,9 @@ pub enum TcpEvent {
     Connected,    //fires when the client has connected to a server
     Disconnected(DisconnectedReason), //fires when the client has disconnected from a server
     TextMessage,  //fires when a text message comes in
+    UserState,    //fires after a user state has been parsed or the user was removed
+    ChannelState, //fires after a channel state has been parsed or the channel was removed
+    UserStats,    //fires when user stats comes in or can't be answered anymore
 }
 
 /// When a [TcpEvent] occurs, this contains the data for the event.
@@ -63,6 +68,15 @@ pub enum TcpEventData<'a> {
     Connected(Result<&'a msgs::ServerSync, mumlib::Error>),
     Disconnected(DisconnectedReason),
     TextMessage(&'a msgs::TextMessage),
+    /// The session of the user that changed, or `None` if every user is gone
+    /// since we disconnected.
+    UserState(Option<u32>),
+    /// The id of the channel that changed, or `None` if every channel is gone
+    /// since we disconnected.
+    ChannelState(Option<u32>),
+    /// The stats of a user, or why requested stats won't arrive: we
+    /// disconnected, a request was denied or the user with some name left.
+    UserStats(Result<&'a msgs::UserStats, mumlib::Error>),
 }
 
 impl From<&TcpEventData<'_>> for TcpEvent {
@@ -71,6 +85,9 @@ impl From<&TcpEventData<'_>> for TcpEvent {
             TcpEventData::Connected(_) => TcpEvent::Connected,
             TcpEventData::Disconnected(reason) => TcpEvent::Disconnected(*reason),
             TcpEventData::TextMessage(_) => TcpEvent::TextMessage,
+            TcpEventData::UserState(_) => TcpEvent::UserState,
+            TcpEventData::ChannelState(_) => TcpEvent::ChannelState,
+            TcpEventData::UserStats(_) => TcpEvent::UserStats,
         }
     }
 }
@@ -150,7 +167,7 @@ impl Debug for TcpEventQueue {
 pub async fn handle(
     state: Arc<RwLock<State>>,
     mut connection_info_receiver: watch::Receiver<Option<ConnectionInfo>>,
-    crypt_state_sender: mpsc::Sender<ClientCryptState>,
+    crypt_state_sender: mpsc::Sender<CryptStateUpdate>,
     packet_sender: mpsc::UnboundedSender<ControlPacket<Serverbound>>,
     mut packet_receiver: mpsc::UnboundedReceiver<ControlPacket<Serverbound>>,
     event_queue: TcpEventQueue,
@@ -210,11 +227,12 @@ pub async fn handle(
             |phase| matches!(phase, StatePhase::Disconnected),
             async {
                 select! {
-                    r = send_pings(packet_sender.clone(), 10).fuse() => r,
+                    r = send_pings(Arc::clone(&state), packet_sender.clone(), 10).fuse() => r,
                     r = listen(
                         Arc::clone(&state),
                         stream,
                         crypt_state_sender.clone(),
+                        packet_sender.clone(),
                         event_queue.clone(),
                     ).fuse() => r,
                     r = send_voice(
@@ -234,6 +252,10 @@ pub async fn handle(
             Ok(()) => event_queue.resolve(TcpEventData::Disconnected(DisconnectedReason::User)),
             Err(_) => event_queue.resolve(TcpEventData::Disconnected(DisconnectedReason::TcpError)),
         }
+        // Stop waiting for comments and descriptions that will never arrive.
+        event_queue.resolve(TcpEventData::UserState(None));
+        event_queue.resolve(TcpEventData::ChannelState(None));
+        event_queue.resolve(TcpEventData::UserStats(Err(mumlib::Error::Disconnected)));
 
         debug!("Fully disconnected TCP stream, waiting for new connection info");
     }
@@ -273,12 +295,15 @@ async fn authenticate(
     if let Some(password) = password {
         msg.set_password(password);
     }
+    // Only Opus is supported, so no CELT versions are sent. This lets the
+    // server know that we can't decode CELT.
     msg.set_opus(true);
     sink.send(msg.into()).await?;
     Ok(())
 }
 
 async fn send_pings(
+    state: Arc<RwLock<State>>,
     packet_sender: mpsc::UnboundedSender<ControlPacket<Serverbound>>,
     delay_seconds: u64,
 ) -> Result<(), TcpError> {
@@ -286,7 +311,9 @@ async fn send_pings(
     loop {
         interval.tick().await;
         trace!("Sending TCP ping");
-        let msg = msgs::Ping::new();
+        let mut msg = msgs::Ping::new();
+        msg.set_timestamp(timestamp());
+        state.read().unwrap().connection_stats().fill_ping(&mut msg);
         packet_sender.send(msg.into())?;
     }
 }
@@ -343,11 +370,12 @@ async fn send_voice(
 async fn listen(
     state: Arc<RwLock<State>>,
     mut stream: TcpReceiver,
-    crypt_state_sender: mpsc::Sender<ClientCryptState>,
+    crypt_state_sender: mpsc::Sender<CryptStateUpdate>,
+    packet_sender: mpsc::UnboundedSender<ControlPacket<Serverbound>>,
     event_queue: TcpEventQueue,
 ) -> Result<(), TcpError> {
     let mut crypt_state = None;
-    let mut crypt_state_sender = Some(crypt_state_sender);
+    let mut logged_in = false;
 
     let mut last_late = 0;
     let mut last_lost = 0;
@@ -374,44 +402,63 @@ async fn listen(
         match packet {
             ControlPacket::TextMessage(msg) => {
                 let mut state = state.write().unwrap();
-                let user = state
-                    .server()
-                    .and_then(|server| server.users().get(&msg.get_actor()))
-                    .map(|user| user.name());
-                if let Some(user) = user {
-                    notifications::send(format!("{}: {}", user, msg.get_message()));
-                    //TODO: probably want a config flag for this
-                    let user = user.to_string();
-                    state.push_event(MumbleEventKind::TextMessageReceived(user)) //TODO also include message target
+                if let Some(message) = state.received_message(&msg) {
+                    state.push_event(MumbleEventKind::TextMessageReceived(message.clone()));
+                    state.audio_output().play_effect(NotificationEvents::TextMessage);
+                    state.register_message(message);
                 }
-                state.register_message((msg.get_message().to_owned(), msg.get_actor()));
                 drop(state);
                 event_queue.resolve(TcpEventData::TextMessage(&*msg));
             }
             ControlPacket::CryptSetup(msg) => {
                 debug!("Crypt setup");
-                // Wait until we're fully connected before initiating UDP voice
-                crypt_state = Some(ClientCryptState::new_from(
-                    msg.get_key()
-                        .try_into()
-                        .expect("Server sent private key with incorrect size"),
-                    msg.get_client_nonce()
-                        .try_into()
-                        .expect("Server sent client_nonce with incorrect size"),
-                    msg.get_server_nonce()
-                        .try_into()
-                        .expect("Server sent server_nonce with incorrect size"),
-                ));
+                if msg.has_key() && msg.has_client_nonce() && msg.has_server_nonce() {
+                    let new_crypt_state = ClientCryptState::new_from(
+                        msg.get_key()
+                            .try_into()
+                            .expect("Server sent private key with incorrect size"),
+                        msg.get_client_nonce()
+                            .try_into()
+                            .expect("Server sent client_nonce with incorrect size"),
+                        msg.get_server_nonce()
+                            .try_into()
+                            .expect("Server sent server_nonce with incorrect size"),
+                    );
+                    if logged_in {
+                        let _ = crypt_state_sender
+                            .send(CryptStateUpdate::New(Box::new(new_crypt_state)))
+                            .await;
+                    } else {
+                        // Wait until we're fully connected before initiating UDP voice
+                        crypt_state = Some(new_crypt_state);
+                    }
+                } else if msg.has_server_nonce() {
+                    // The server answered our resync request.
+                    match msg.get_server_nonce().try_into() {
+                        Ok(nonce) => {
+                            let _ = crypt_state_sender
+                                .send(CryptStateUpdate::DecryptNonce(nonce))
+                                .await;
+                        }
+                        Err(_) => warn!("Server sent server_nonce with incorrect size"),
+                    }
+                } else {
+                    // The server wants to resync and asks for our nonce.
+                    let _ = crypt_state_sender
+                        .send(CryptStateUpdate::EncryptNonceRequested)
+                        .await;
+                }
             }
             ControlPacket::ServerSync(msg) => {
                 info!("Logged in");
-                if let Some(sender) = crypt_state_sender.take() {
-                    let _ = sender
-                        .send(
+                if !logged_in {
+                    logged_in = true;
+                    let _ = crypt_state_sender
+                        .send(CryptStateUpdate::New(Box::new(
                             crypt_state
                                 .take()
                                 .expect("Server didn't send us any CryptSetup packet!"),
-                        )
+                        )))
                         .await;
                 }
                 event_queue.resolve(TcpEventData::Connected(Ok(&msg)));
@@ -425,8 +472,35 @@ async fn listen(
                 for channel in server.channels().values() {
                     info!("Found channel {}", channel.name());
                 }
+                state.update_encoder_settings();
                 state.initialized();
             }
+            ControlPacket::ServerConfig(msg) => {
+                let avatar = {
+                    let mut state = state.write().unwrap();
+                    state.server_mut().unwrap().parse_server_config(*msg);
+                    state.update_encoder_settings();
+                    // The server config contains the maximum avatar size, so
+                    // wait for it before setting our initial comment and
+                    // avatar.
+                    state.initial_avatar()
+                };
+                // The avatar might take a while to read, so the state isn't
+                // locked meanwhile.
+                let avatar = match avatar {
+                    Some((path, max_length)) => match read_avatar(path, max_length).await {
+                        Ok(texture) => Some(texture),
+                        Err(e) => {
+                            warn!("Couldn't set avatar: {}", e);
+                            None
+                        }
+                    },
+                    None => None,
+                };
+                if let Some(msg) = state.read().unwrap().initial_user_state(avatar) {
+                    packet_sender.send(msg.into())?;
+                }
+            }
             ControlPacket::Reject(msg) => {
                 debug!("Login rejected: {:?}", msg);
                 match msg.get_field_type() {
@@ -441,42 +515,117 @@ async fn listen(
                 }
             }
             ControlPacket::UserState(msg) => {
-                state.write().unwrap().parse_user_state(*msg);
+                let session = msg.get_session();
+                let mut hashes = Vec::new();
+                if msg.has_comment_hash() {
+                    hashes.push(msg.get_comment_hash().to_vec());
+                }
+                if msg.has_texture_hash() {
+                    hashes.push(msg.get_texture_hash().to_vec());
+                }
+                let blobs = blob::load_all(hashes).await;
+                {
+                    let mut state = state.write().unwrap();
+                    state.parse_user_state(*msg);
+                    if let Some(user) = state
+                        .server_mut()
+                        .and_then(|s| s.users_mut().get_mut(&session))
+                    {
+                        user.load_cached_blobs(&blobs);
+                    }
+                }
+                event_queue.resolve(TcpEventData::UserState(Some(session)));
             }
             ControlPacket::UserRemove(msg) => {
-                state.write().unwrap().remove_client(*msg);
+                let session = msg.get_session();
+                let name = {
+                    let mut state = state.write().unwrap();
+                    let name = state
+                        .server()
+                        .and_then(|s| s.users().get(&session))
+                        .map(|u| u.name().to_string());
+                    state.remove_client(*msg);
+                    name
+                };
+                event_queue.resolve(TcpEventData::UserState(Some(session)));
+                if let Some(name) = name {
+                    event_queue.resolve(TcpEventData::UserStats(Err(mumlib::Error::InvalidUsername(name))));
+                }
             }
             ControlPacket::ChannelState(msg) => {
                 debug!("Channel state received");
-                state
-                    .write()
-                    .unwrap()
-                    .server_mut()
-                    .unwrap()
-                    .parse_channel_state(*msg); //TODO parse initial if initial
+                let channel_id = msg.get_channel_id();
+                let hashes = if msg.has_description_hash() {
+                    vec![msg.get_description_hash().to_vec()]
+                } else {
+                    Vec::new()
+                };
+                let blobs = blob::load_all(hashes).await;
+                {
+                    let mut state = state.write().unwrap();
+                    let server = state.server_mut().unwrap();
+                    server.parse_channel_state(*msg); //TODO parse initial if initial
+                    if let Some(channel) = server.channels_mut().get_mut(&channel_id) {
+                        channel.load_cached_blobs(&blobs);
+                    }
+                }
+                event_queue.resolve(TcpEventData::ChannelState(Some(channel_id)));
             }
             ControlPacket::ChannelRemove(msg) => {
+                let channel_id = msg.get_channel_id();
                 state
                     .write()
                     .unwrap()
                     .server_mut()
                     .unwrap()
                     .parse_channel_remove(*msg);
+                event_queue.resolve(TcpEventData::ChannelState(Some(channel_id)));
+            }
+            ControlPacket::UserStats(msg) => {
+                event_queue.resolve(TcpEventData::UserStats(Ok(&*msg)));
+            }
+            ControlPacket::PermissionDenied(msg) => {
+                let reason = if msg.has_reason() {
+                    msg.get_reason().to_string()
+                } else {
+                    format!("{:?}", msg.get_field_type())
+                };
+                warn!("Permission denied: {}", reason);
+                // We can't tell which request was denied, so stop waiting for
+                // stats that might never arrive.
+                event_queue.resolve(TcpEventData::UserStats(Err(mumlib::Error::PermissionDenied(reason))));
+                state
+                    .read()
+                    .unwrap()
+                    .audio_output()
+                    .play_effect(NotificationEvents::PermissionDenied);
+            }
+            ControlPacket::CodecVersion(msg) => {
+                // The server falls back to CELT if some users don't support
+                // Opus.
+                if msg.get_opus() {
+                    debug!("The server uses Opus");
+                } else {
+                    warn!("The server uses CELT since some users don't support Opus. Only Opus is supported, so their audio can't be played");
+                }
             }
             ControlPacket::UDPTunnel(msg) => {
                 match *msg {
                     VoicePacket::Ping { .. } => {}
                     VoicePacket::Audio {
+                        target,
                         session_id,
                         // seq_num,
                         payload,
-                        // position_info,
+                        position_info,
                         ..
                     } => {
                         state.read().unwrap().audio_output().decode_packet_payload(
                             VoiceStreamType::Tcp,
                             session_id,
+                            target,
                             payload,
+                            position_info.as_deref().and_then(positional::decode_position),
                         );
                     }
                 }
@@ -484,6 +633,18 @@ async fn listen(
             ControlPacket::Ping(msg) => {
                 trace!("Received Ping {:?}", *msg);
 
+                {
+                    let mut state = state.write().unwrap();
+                    let stats = state.connection_stats_mut();
+                    if msg.has_timestamp() {
+                        stats.push_tcp_ping(rtt(msg.get_timestamp()));
+                    }
+                    stats.from_client.good = msg.get_good();
+                    stats.from_client.late = msg.get_late();
+                    stats.from_client.lost = msg.get_lost();
+                    stats.from_client.resync = msg.get_resync();
+                }
+
                 let late = msg.get_late();
                 let lost = msg.get_lost();
                 let resync = msg.get_resync();
@@ -1,38 +1,108 @@
+use crate::audio::positional;
 use crate::error::UdpError;
 use crate::network::ConnectionInfo;
 use crate::state::{State, StatePhase};
 
+use bytes::BytesMut;
 use futures_util::future::join4;
 use futures_util::stream::{SplitSink, SplitStream, Stream};
 use futures_util::{FutureExt, SinkExt, StreamExt};
 use log::*;
-use mumble_protocol::crypt::ClientCryptState;
+use mumble_protocol::control::{msgs, ControlPacket};
+use mumble_protocol::crypt::{ClientCryptState, BLOCK_SIZE};
 use mumble_protocol::ping::{PingPacket, PongPacket};
 use mumble_protocol::voice::VoicePacket;
-use mumble_protocol::Serverbound;
+use mumble_protocol::{Clientbound, Serverbound};
 use std::collections::{hash_map::Entry, HashMap};
 use std::convert::TryFrom;
+use std::io;
 use std::net::{Ipv6Addr, SocketAddr};
 use std::sync::{
     atomic::{AtomicU64, Ordering},
     Arc, RwLock,
 };
 use tokio::sync::{mpsc, oneshot, watch, Mutex};
-use tokio::time::{interval, timeout, Duration};
+use tokio::time::{interval, timeout, Duration, Instant};
 use tokio::{join, net::UdpSocket};
+use tokio_util::codec::{Decoder, Encoder};
 use tokio_util::udp::UdpFramed;
 
-use super::{run_until, VoiceStreamType};
+use super::{rtt, run_until, timestamp, VoiceStreamType};
 
 pub type PingRequest = (u64, SocketAddr, Box<dyn FnOnce(Option<PongPacket>) + Send>);
 
-type UdpSender = SplitSink<UdpFramed<ClientCryptState>, (VoicePacket<Serverbound>, SocketAddr)>;
-type UdpReceiver = SplitStream<UdpFramed<ClientCryptState>>;
+/// How many packets in a row that need to fail decryption before we ask the
+/// server to resync our nonces.
+const RESYNC_THRESHOLD: u32 = 8;
+/// How long to wait between resync requests.
+const RESYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Changes to the UDP crypt state, as sent by the server over TCP.
+pub enum CryptStateUpdate {
+    /// A new key and nonces. The socket is rebound.
+    New(Box<ClientCryptState>),
+    /// The server's encrypt nonce, sent as a response to our resync request.
+    DecryptNonce([u8; BLOCK_SIZE]),
+    /// The server wants to resync and requested our encrypt nonce.
+    EncryptNonceRequested,
+}
+
+impl std::fmt::Debug for CryptStateUpdate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CryptStateUpdate::New(_) => "New",
+            CryptStateUpdate::DecryptNonce(_) => "DecryptNonce",
+            CryptStateUpdate::EncryptNonceRequested => "EncryptNonceRequested",
+        })
+    }
+}
+
+type UdpSender = SplitSink<UdpFramed<SharedCryptState>, (VoicePacket<Serverbound>, SocketAddr)>;
+type UdpReceiver = SplitStream<UdpFramed<SharedCryptState>>;
+
+/// Wraps a [ClientCryptState] so that its packet statistics can be read while
+/// it's used as the codec of the UDP socket.
+#[derive(Clone)]
+struct SharedCryptState(Arc<std::sync::Mutex<ClientCryptState>>);
+
+impl SharedCryptState {
+    fn new(crypt_state: ClientCryptState) -> Self {
+        Self(Arc::new(std::sync::Mutex::new(crypt_state)))
+    }
+
+    /// Returns how many packets we've received that were good, late and lost.
+    fn packet_stats(&self) -> (u32, u32, u32) {
+        let crypt_state = self.0.lock().unwrap();
+        (
+            crypt_state.get_good(),
+            crypt_state.get_late(),
+            crypt_state.get_lost(),
+        )
+    }
+}
+
+impl Decoder for SharedCryptState {
+    type Item = VoicePacket<Clientbound>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.0.lock().unwrap().decode(buf)
+    }
+}
+
+impl Encoder<VoicePacket<Serverbound>> for SharedCryptState {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: VoicePacket<Serverbound>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.0.lock().unwrap().encode(item, dst)
+    }
+}
 
 pub async fn handle(
     state: Arc<RwLock<State>>,
     mut connection_info_receiver: watch::Receiver<Option<ConnectionInfo>>,
-    mut crypt_state_receiver: mpsc::Receiver<ClientCryptState>,
+    mut crypt_state_receiver: mpsc::Receiver<CryptStateUpdate>,
+    packet_sender: mpsc::UnboundedSender<ControlPacket<Serverbound>>,
 ) -> Result<(), UdpError> {
     let receiver = state.read().unwrap().audio_input().receiver();
 
@@ -46,10 +116,11 @@ pub async fn handle(
                 return Err(UdpError::NoConnectionInfoReceived);
             }
         };
-        let (sink, source) = connect(&mut crypt_state_receiver).await?;
+        let (sink, source, crypt_state) = connect(&mut crypt_state_receiver).await?;
 
         let sink = Arc::new(Mutex::new(sink));
         let source = Arc::new(Mutex::new(source));
+        let crypt_state = Arc::new(Mutex::new(crypt_state));
 
         let phase_watcher = state.read().unwrap().phase_receiver();
         let last_ping_recv = AtomicU64::new(0);
@@ -57,7 +128,12 @@ pub async fn handle(
         run_until(
             |phase| matches!(phase, StatePhase::Disconnected),
             join4(
-                listen(Arc::clone(&state), Arc::clone(&source), &last_ping_recv),
+                listen(
+                    Arc::clone(&state),
+                    Arc::clone(&source),
+                    packet_sender.clone(),
+                    &last_ping_recv,
+                ),
                 send_voice(
                     Arc::clone(&sink),
                     connection_info.socket_addr,
@@ -67,10 +143,18 @@ pub async fn handle(
                 send_pings(
                     Arc::clone(&state),
                     Arc::clone(&sink),
+                    Arc::clone(&crypt_state),
                     connection_info.socket_addr,
                     &last_ping_recv,
                 ),
-                new_crypt_state(&mut crypt_state_receiver, sink, source),
+                new_crypt_state(
+                    Arc::clone(&state),
+                    &mut crypt_state_receiver,
+                    packet_sender.clone(),
+                    sink,
+                    source,
+                    crypt_state,
+                ),
             )
             .map(|_| ()),
             phase_watcher,
@@ -82,37 +166,82 @@ pub async fn handle(
 }
 
 async fn connect(
-    crypt_state: &mut mpsc::Receiver<ClientCryptState>,
-) -> Result<(UdpSender, UdpReceiver), UdpError> {
+    crypt_state: &mut mpsc::Receiver<CryptStateUpdate>,
+) -> Result<(UdpSender, UdpReceiver, SharedCryptState), UdpError> {
     // Bind UDP socket
     let udp_socket = UdpSocket::bind((Ipv6Addr::from(0u128), 0u16)).await?;
 
     // Wait for initial CryptState
-    let crypt_state = match crypt_state.recv().await {
-        Some(crypt_state) => crypt_state,
-        // disconnected before we received the CryptSetup packet, oh well
-        None => return Err(UdpError::DisconnectBeforeCryptSetup),
+    let crypt_state = loop {
+        match crypt_state.recv().await {
+            Some(CryptStateUpdate::New(crypt_state)) => break *crypt_state,
+            Some(update) => debug!("Ignoring {:?} before initial crypt state", update),
+            // disconnected before we received the CryptSetup packet, oh well
+            None => return Err(UdpError::DisconnectBeforeCryptSetup),
+        }
     };
     debug!("UDP connected");
 
     // Wrap the raw UDP packets in Mumble's crypto and voice codec (CryptState does both)
-    Ok(UdpFramed::new(udp_socket, crypt_state).split())
+    let crypt_state = SharedCryptState::new(crypt_state);
+    let (sink, source) = UdpFramed::new(udp_socket, crypt_state.clone()).split();
+    Ok((sink, source, crypt_state))
 }
 
 async fn new_crypt_state(
-    crypt_state: &mut mpsc::Receiver<ClientCryptState>,
+    state: Arc<RwLock<State>>,
+    crypt_state: &mut mpsc::Receiver<CryptStateUpdate>,
+    packet_sender: mpsc::UnboundedSender<ControlPacket<Serverbound>>,
     sink: Arc<Mutex<UdpSender>>,
     source: Arc<Mutex<UdpReceiver>>,
+    shared_crypt_state: Arc<Mutex<SharedCryptState>>,
 ) {
     loop {
-        if let Some(crypt_state) = crypt_state.recv().await {
-            info!("Received new crypt state");
-            let udp_socket = UdpSocket::bind((Ipv6Addr::from(0u128), 0u16))
-                .await
-                .expect("Failed to bind UDP socket");
-            let (new_sink, new_source) = UdpFramed::new(udp_socket, crypt_state).split();
-            *sink.lock().await = new_sink;
-            *source.lock().await = new_source;
+        match crypt_state.recv().await {
+            Some(CryptStateUpdate::New(crypt_state)) => {
+                info!("Received new crypt state");
+                let udp_socket = UdpSocket::bind((Ipv6Addr::from(0u128), 0u16))
+                    .await
+                    .expect("Failed to bind UDP socket");
+                let crypt_state = SharedCryptState::new(*crypt_state);
+                let (new_sink, new_source) =
+                    UdpFramed::new(udp_socket, crypt_state.clone()).split();
+                *sink.lock().await = new_sink;
+                *source.lock().await = new_source;
+                *shared_crypt_state.lock().await = crypt_state;
+            }
+            Some(CryptStateUpdate::DecryptNonce(nonce)) => {
+                info!("Resynced crypt state");
+                shared_crypt_state
+                    .lock()
+                    .await
+                    .0
+                    .lock()
+                    .unwrap()
+                    .set_decrypt_nonce(&nonce);
+                state
+                    .write()
+                    .unwrap()
+                    .connection_stats_mut()
+                    .from_server
+                    .resync += 1;
+            }
+            Some(CryptStateUpdate::EncryptNonceRequested) => {
+                debug!("Server requested our encrypt nonce");
+                let nonce = shared_crypt_state
+                    .lock()
+                    .await
+                    .0
+                    .lock()
+                    .unwrap()
+                    .get_encrypt_nonce();
+                let mut msg = msgs::CryptSetup::new();
+                msg.set_client_nonce(nonce.to_vec());
+                if packet_sender.send(msg.into()).is_err() {
+                    warn!("Couldn't send encrypt nonce to server");
+                }
+            }
+            None => {}
         }
     }
 }
@@ -120,37 +249,60 @@ async fn new_crypt_state(
 async fn listen(
     state: Arc<RwLock<State>>,
     source: Arc<Mutex<UdpReceiver>>,
+    packet_sender: mpsc::UnboundedSender<ControlPacket<Serverbound>>,
     last_ping_recv: &AtomicU64,
 ) {
+    let mut failed_decrypts = 0;
+    let mut last_resync_request: Option<Instant> = None;
     loop {
         let packet = source.lock().await.next().await.unwrap();
         let (packet, _src_addr) = match packet {
             Ok(packet) => packet,
             Err(err) => {
                 warn!("Got an invalid UDP packet: {}", err);
-                // To be expected, considering this is the internet, just ignore it
+                // To be expected, considering this is the internet, just ignore it.
+                // If it keeps happening our nonces have probably drifted apart
+                // (e.g. after a NAT rebinding) and we need to resync them.
+                if err.kind() == io::ErrorKind::InvalidData {
+                    failed_decrypts += 1;
+                    if failed_decrypts >= RESYNC_THRESHOLD
+                        && last_resync_request
+                            .map(|t| t.elapsed() >= RESYNC_INTERVAL)
+                            .unwrap_or(true)
+                    {
+                        info!("Requesting crypt resync after {} failed packets", failed_decrypts);
+                        if packet_sender.send(msgs::CryptSetup::new().into()).is_err() {
+                            warn!("Couldn't request crypt resync");
+                        }
+                        last_resync_request = Some(Instant::now());
+                        failed_decrypts = 0;
+                    }
+                }
                 continue;
             }
         };
+        failed_decrypts = 0;
         match packet {
             VoicePacket::Ping { timestamp } => {
-                state
-                    .read()
-                    .unwrap()
-                    .broadcast_phase(StatePhase::Connected(VoiceStreamType::Udp));
+                let mut state = state.write().unwrap();
+                state.broadcast_phase(StatePhase::Connected(VoiceStreamType::Udp));
+                state.connection_stats_mut().push_udp_ping(rtt(timestamp));
                 last_ping_recv.store(timestamp, Ordering::Relaxed);
             }
             VoicePacket::Audio {
+                target,
                 session_id,
                 // seq_num,
                 payload,
-                // position_info,
+                position_info,
                 ..
             } => {
                 state.read().unwrap().audio_output().decode_packet_payload(
                     VoiceStreamType::Udp,
                     session_id,
+                    target,
                     payload,
+                    position_info.as_deref().and_then(positional::decode_position),
                 );
             }
         }
@@ -160,6 +312,7 @@ async fn listen(
 async fn send_pings(
     state: Arc<RwLock<State>>,
     sink: Arc<Mutex<UdpSender>>,
+    crypt_state: Arc<Mutex<SharedCryptState>>,
     server_addr: SocketAddr,
     last_ping_recv: &AtomicU64,
 ) {
@@ -176,19 +329,25 @@ async fn send_pings(
                 .unwrap()
                 .broadcast_phase(StatePhase::Connected(VoiceStreamType::Tcp));
         }
+
+        let (good, late, lost) = crypt_state.lock().await.packet_stats();
+        {
+            let mut state = state.write().unwrap();
+            let from_server = &mut state.connection_stats_mut().from_server;
+            from_server.good = good;
+            from_server.late = late;
+            from_server.lost = lost;
+        }
+
+        let timestamp = timestamp();
         match sink
             .lock()
             .await
-            .send((
-                VoicePacket::Ping {
-                    timestamp: last_recv + 1,
-                },
-                server_addr,
-            ))
+            .send((VoicePacket::Ping { timestamp }, server_addr))
             .await
         {
             Ok(_) => {
-                last_send = Some(last_recv + 1);
+                last_send = Some(timestamp);
             }
             Err(e) => {
                 debug!("Error sending UDP ping: {}", e);
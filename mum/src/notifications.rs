@@ -1,17 +1,112 @@
-#[cfg(feature = "notifications")]
+use crate::error::NotificationError;
+
 use log::*;
+use mumlib::command::MumbleEventKind;
+use mumlib::config::{NotificationBackendKind, NotificationUrgency, NotificationsConfig};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::process::Stdio;
+#[cfg(feature = "dbus")]
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
 
-pub fn init() {
-    #[cfg(feature = "notifications")]
-    if let Err(e) = libnotify::init("mumd") {
-        warn!("Unable to initialize notifications: {}", e);
+/// A notification about an event, ready to be shown.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Notification {
+    /// The name of the event, e.g. "user_connected".
+    pub event: String,
+    /// The rendered template.
+    pub text: String,
+    pub urgency: NotificationUrgency,
+    /// How long the notification is shown in milliseconds, or the default
+    /// time of the backend if None.
+    pub timeout: Option<u32>,
+    /// The values of the placeholders of the event, e.g. "user".
+    pub values: BTreeMap<String, String>,
+}
+
+/// Something that can show notifications.
+pub trait NotificationBackend: Debug + Send + Sync {
+    fn send(&self, notification: &Notification) -> Result<(), NotificationError>;
+}
+
+/// Creates the backend selected in the config. Notifications aren't shown if
+/// no backend is selected and mumd is built without libnotify and D-Bus, or
+/// if the selected backend isn't available.
+pub fn backend(config: &NotificationsConfig) -> Box<dyn NotificationBackend> {
+    let kind = match config.backend {
+        Some(kind) => kind,
+        None if cfg!(feature = "notifications") => NotificationBackendKind::Libnotify,
+        None if cfg!(feature = "dbus") => NotificationBackendKind::Dbus,
+        None => return Box::new(NoBackend),
+    };
+    match kind {
+        #[cfg(feature = "notifications")]
+        NotificationBackendKind::Libnotify => match LibnotifyBackend::new() {
+            Ok(backend) => return Box::new(backend),
+            Err(e) => warn!("Unable to initialize notifications: {}", e),
+        },
+        #[cfg(feature = "dbus")]
+        NotificationBackendKind::Dbus => match DbusBackend::new() {
+            Ok(backend) => return Box::new(backend),
+            Err(e) => warn!("Unable to initialize notifications: {}", e),
+        },
+        NotificationBackendKind::Command => match &config.command {
+            Some(command) if !command.is_empty() => {
+                return Box::new(CommandBackend::new(command.clone()))
+            }
+            _ => warn!("The command notification backend needs a command to run"),
+        },
+        NotificationBackendKind::Stdout => return Box::new(StdoutBackend),
+        #[allow(unreachable_patterns)]
+        kind => warn!("mumd was built without support for {:?} notifications", kind),
     }
+    Box::new(NoBackend)
 }
 
-#[cfg(feature = "notifications")]
-pub fn send(msg: String) -> Option<bool> {
-    match libnotify::Notification::new("mumd", Some(msg.as_str()), None).show() {
-        Ok(_) => Some(true),
+/// Sends a notification about an event if it's enabled in the config, using
+/// the configured template, urgency and timeout.
+///
+/// Returns whether the notification was sent, or None if it's disabled.
+pub fn send_event(
+    backend: &dyn NotificationBackend,
+    config: &NotificationsConfig,
+    event: &MumbleEventKind,
+) -> Option<bool> {
+    let overrides = config
+        .events
+        .iter()
+        .flatten()
+        .find(|overrides| overrides.event == event.name());
+    let enabled = config.enabled.unwrap_or(true)
+        && overrides
+            .and_then(|overrides| overrides.enabled)
+            .unwrap_or_else(|| enabled_by_default(event));
+    if !enabled {
+        return None;
+    }
+    let template = overrides
+        .and_then(|overrides| overrides.template.as_deref())
+        .unwrap_or_else(|| event.default_template());
+    let notification = Notification {
+        event: event.name().to_string(),
+        text: event.render(template),
+        urgency: overrides
+            .and_then(|overrides| overrides.urgency)
+            .or(config.urgency)
+            .unwrap_or(NotificationUrgency::Normal),
+        timeout: overrides
+            .and_then(|overrides| overrides.timeout)
+            .or(config.timeout),
+        values: event
+            .placeholders()
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+    };
+    match backend.send(&notification) {
+        Ok(()) => Some(true),
         Err(e) => {
             warn!("Unable to send notification: {}", e);
             Some(false)
@@ -19,7 +114,287 @@ pub fn send(msg: String) -> Option<bool> {
     }
 }
 
-#[cfg(not(feature = "notifications"))]
-pub fn send(_: String) -> Option<bool> {
-    None
+/// Whether notifications are sent for an event unless configured otherwise.
+/// Talking happens too often to be notified about.
+fn enabled_by_default(event: &MumbleEventKind) -> bool {
+    !matches!(
+        event,
+        MumbleEventKind::UserStartedTalking(_) | MumbleEventKind::UserStoppedTalking(_)
+    )
+}
+
+/// Shows notifications using libnotify.
+#[cfg(feature = "notifications")]
+#[derive(Debug)]
+pub struct LibnotifyBackend;
+
+#[cfg(feature = "notifications")]
+impl LibnotifyBackend {
+    pub fn new() -> Result<Self, NotificationError> {
+        libnotify::init("mumd").map_err(NotificationError::Libnotify)?;
+        Ok(Self)
+    }
+}
+
+#[cfg(feature = "notifications")]
+impl NotificationBackend for LibnotifyBackend {
+    fn send(&self, notification: &Notification) -> Result<(), NotificationError> {
+        let n = libnotify::Notification::new("mumd", Some(notification.text.as_str()), None);
+        n.set_urgency(match notification.urgency {
+            NotificationUrgency::Low => libnotify::Urgency::Low,
+            NotificationUrgency::Normal => libnotify::Urgency::Normal,
+            NotificationUrgency::Critical => libnotify::Urgency::Critical,
+        });
+        if let Some(timeout) = notification.timeout {
+            n.set_timeout(timeout.min(i32::MAX as u32) as i32);
+        }
+        n.show()
+            .map_err(|e| NotificationError::Libnotify(e.to_string()))
+    }
+}
+
+/// Shows notifications by talking to the notification server over D-Bus
+/// directly, without pulling in GTK.
+#[cfg(feature = "dbus")]
+pub struct DbusBackend {
+    connection: Arc<Mutex<dbus::blocking::Connection>>,
+}
+
+#[cfg(feature = "dbus")]
+impl DbusBackend {
+    pub fn new() -> Result<Self, NotificationError> {
+        Ok(Self {
+            connection: Arc::new(Mutex::new(dbus::blocking::Connection::new_session()?)),
+        })
+    }
+}
+
+#[cfg(feature = "dbus")]
+impl Debug for DbusBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DbusBackend").finish()
+    }
+}
+
+#[cfg(feature = "dbus")]
+impl NotificationBackend for DbusBackend {
+    /// Sends the notification in the background, since the notification
+    /// server might take a while to answer. Errors are logged.
+    fn send(&self, notification: &Notification) -> Result<(), NotificationError> {
+        let connection = Arc::clone(&self.connection);
+        let notification = notification.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = dbus_notify(&connection.lock().unwrap(), &notification) {
+                warn!("Unable to send notification: {}", e);
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(feature = "dbus")]
+fn dbus_notify(
+    connection: &dbus::blocking::Connection,
+    notification: &Notification,
+) -> Result<(), dbus::Error> {
+    use dbus::arg::{RefArg, Variant};
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    let urgency: u8 = match notification.urgency {
+        NotificationUrgency::Low => 0,
+        NotificationUrgency::Normal => 1,
+        NotificationUrgency::Critical => 2,
+    };
+    let mut hints: HashMap<&str, Variant<Box<dyn RefArg>>> = HashMap::new();
+    hints.insert("urgency", Variant(Box::new(urgency)));
+    let timeout = notification
+        .timeout
+        .map(|timeout| timeout.min(i32::MAX as u32) as i32)
+        .unwrap_or(-1);
+
+    let proxy = connection.with_proxy(
+        "org.freedesktop.Notifications",
+        "/org/freedesktop/Notifications",
+        Duration::from_secs(5),
+    );
+    let _: (u32,) = proxy.method_call(
+        "org.freedesktop.Notifications",
+        "Notify",
+        (
+            "mumd",
+            0u32,
+            "",
+            "mumd",
+            notification.text.as_str(),
+            Vec::<&str>::new(),
+            hints,
+            timeout,
+        ),
+    )?;
+    Ok(())
+}
+
+/// Shows notifications by running a command. The rendered text is passed as
+/// the last argument and the whole notification as JSON on standard input.
+#[derive(Debug)]
+pub struct CommandBackend {
+    /// The program followed by its arguments.
+    command: Vec<String>,
+}
+
+impl CommandBackend {
+    pub fn new(command: Vec<String>) -> Self {
+        Self { command }
+    }
+}
+
+impl NotificationBackend for CommandBackend {
+    fn send(&self, notification: &Notification) -> Result<(), NotificationError> {
+        let mut child = tokio::process::Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .arg(&notification.text)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(NotificationError::Command)?;
+        let mut stdin = child.stdin.take();
+        // Serializing a notification can't fail since all keys are strings.
+        let json = serde_json::to_vec(notification).unwrap();
+        let program = self.command[0].clone();
+        tokio::spawn(async move {
+            if let Some(stdin) = &mut stdin {
+                // The command is free to ignore its input.
+                let _ = stdin.write_all(&json).await;
+            }
+            drop(stdin);
+            match child.wait().await {
+                Ok(status) if !status.success() => {
+                    warn!("Notification command {} exited with {}", program, status)
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Notification command {} failed: {}", program, e),
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Shows notifications by printing them to standard output.
+#[derive(Debug)]
+pub struct StdoutBackend;
+
+impl NotificationBackend for StdoutBackend {
+    fn send(&self, notification: &Notification) -> Result<(), NotificationError> {
+        println!("{}", notification.text);
+        Ok(())
+    }
+}
+
+/// Doesn't show notifications.
+#[derive(Debug)]
+pub struct NoBackend;
+
+impl NotificationBackend for NoBackend {
+    fn send(&self, _: &Notification) -> Result<(), NotificationError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mumlib::config::NotificationEventConfig;
+    use std::sync::Mutex;
+
+    /// Stores notifications instead of showing them.
+    #[derive(Debug, Default)]
+    struct RecordingBackend(Mutex<Vec<Notification>>);
+
+    impl NotificationBackend for RecordingBackend {
+        fn send(&self, notification: &Notification) -> Result<(), NotificationError> {
+            self.0.lock().unwrap().push(notification.clone());
+            Ok(())
+        }
+    }
+
+    /// Sends some events and returns the notifications that were shown.
+    fn send_events(config: &NotificationsConfig, events: &[MumbleEventKind]) -> Vec<Notification> {
+        let backend = RecordingBackend::default();
+        for event in events {
+            send_event(&backend, config, event);
+        }
+        backend.0.into_inner().unwrap()
+    }
+
+    fn connected() -> MumbleEventKind {
+        MumbleEventKind::UserConnected("user".to_string(), "channel".to_string())
+    }
+
+    fn talking() -> MumbleEventKind {
+        MumbleEventKind::UserStartedTalking("user".to_string())
+    }
+
+    #[test]
+    fn default_notifications() {
+        let notifications = send_events(&NotificationsConfig::default(), &[connected(), talking()]);
+        assert_eq!(
+            notifications,
+            vec![Notification {
+                event: "user_connected".to_string(),
+                text: "user connected to channel".to_string(),
+                urgency: NotificationUrgency::Normal,
+                timeout: None,
+                values: vec![
+                    ("channel".to_string(), "channel".to_string()),
+                    ("user".to_string(), "user".to_string()),
+                ]
+                .into_iter()
+                .collect(),
+            }]
+        );
+    }
+
+    #[test]
+    fn disabled_notifications() {
+        let config = NotificationsConfig {
+            enabled: Some(false),
+            events: Some(vec![NotificationEventConfig {
+                event: "user_connected".to_string(),
+                enabled: Some(true),
+                ..NotificationEventConfig::default()
+            }]),
+            ..NotificationsConfig::default()
+        };
+        assert_eq!(send_events(&config, &[connected(), talking()]), vec![]);
+    }
+
+    #[test]
+    fn overridden_events() {
+        let config = NotificationsConfig {
+            urgency: Some(NotificationUrgency::Low),
+            timeout: Some(1000),
+            events: Some(vec![
+                NotificationEventConfig {
+                    event: "user_connected".to_string(),
+                    enabled: Some(false),
+                    ..NotificationEventConfig::default()
+                },
+                NotificationEventConfig {
+                    event: "user_started_talking".to_string(),
+                    enabled: Some(true),
+                    template: Some("{user} talks in {channel}".to_string()),
+                    urgency: Some(NotificationUrgency::Critical),
+                    timeout: None,
+                },
+            ]),
+            ..NotificationsConfig::default()
+        };
+        let notifications = send_events(&config, &[connected(), talking()]);
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].event, "user_started_talking");
+        assert_eq!(notifications[0].text, "user talks in {channel}");
+        assert_eq!(notifications[0].urgency, NotificationUrgency::Critical);
+        assert_eq!(notifications[0].timeout, Some(1000));
+    }
 }
@@ -1,29 +1,43 @@
+pub mod blob;
 pub mod channel;
 pub mod server;
 pub mod user;
 
-use crate::audio::{AudioInput, AudioOutput, sound_effects::NotificationEvents};
-use crate::error::StateError;
+use crate::audio::{AudioInput, AudioOutput, NewStream, StreamBuilder, echo::EchoReference, input::EncoderSettings, positional::Listener, sound_effects::NotificationEvents, transformers::InputLevel};
+use crate::error::{AudioStream, StateError};
 use crate::network::tcp::{DisconnectedReason, TcpEvent, TcpEventData};
-use crate::network::{ConnectionInfo, VoiceStreamType};
-use crate::notifications;
+use crate::network::{ConnectionInfo, ConnectionStats, VoiceStreamType};
+use crate::hooks;
+use crate::notifications::{self, NotificationBackend};
 use crate::state::server::Server;
 use crate::state::user::UserDiff;
 
-use chrono::NaiveDateTime;
+use futures_util::stream::{self, Stream};
 use log::*;
 use mumble_protocol::control::{ControlPacket, msgs};
 use mumble_protocol::ping::PongPacket;
 use mumble_protocol::voice::Serverbound;
-use mumlib::command::{ChannelTarget, Command, CommandResponse, MessageTarget, MumbleEvent, MumbleEventKind};
-use mumlib::config::Config;
+use mumlib::command::{ChannelTarget, Command, CommandResponse, MessageKind, MessageTarget, MumbleEvent, MumbleEventKind, ReceivedMessage};
+use mumlib::config::{Config, ServerConfig, UserConfig};
+use mumlib::error::ChannelIdentifierError;
 use mumlib::Error;
 use std::fmt::Debug;
 use std::iter;
 use std::net::{SocketAddr, ToSocketAddrs};
-use std::sync::{Arc, RwLock};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 use tokio::sync::{mpsc, watch};
 
+/// How often the input level is sent when it's requested continuously.
+const INPUT_LEVEL_INTERVAL: Duration = Duration::from_millis(50);
+/// How often an empty response is sent while our audio is looped back, which
+/// is how it's noticed that the client has gone away.
+const LOOPBACK_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+/// How often it's checked who is talking.
+const TALKING_INTERVAL: Duration = Duration::from_millis(50);
+
 macro_rules! at {
     ( $( $event:expr => $generator:expr ),+ $(,)? ) => {
         ExecutionContext::TcpEventCallback(vec![
@@ -48,6 +62,9 @@ type TcpEventSubscriberCallback = Box<
     ) -> bool
 >;
 
+type ResponseStream =
+    Pin<Box<dyn Stream<Item = mumlib::error::Result<Option<CommandResponse>>> + Send>>;
+
 //TODO give me a better name
 pub enum ExecutionContext {
     TcpEventCallback(Vec<(TcpEvent, TcpEventCallback)>),
@@ -59,6 +76,9 @@ pub enum ExecutionContext {
             dyn FnOnce(Option<PongPacket>) -> mumlib::error::Result<Option<CommandResponse>> + Send,
         >,
     ),
+    /// Responses that are sent as they become available, until the stream
+    /// ends or the client disconnects.
+    Stream(ResponseStream),
 }
 
 impl Debug for ExecutionContext {
@@ -68,6 +88,7 @@ impl Debug for ExecutionContext {
             ExecutionContext::TcpEventSubscriber(_, _) => "TcpEventSubscriber",
             ExecutionContext::Now(_) => "Now",
             ExecutionContext::Ping(_, _) => "Ping",
+            ExecutionContext::Stream(_) => "Stream",
         }).finish()
     }
 }
@@ -79,15 +100,91 @@ pub enum StatePhase {
     Connected(VoiceStreamType),
 }
 
+/// Who is talking, as reported to controllers.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Talking {
+    /// The names of the users we hear talking, sorted.
+    users: Vec<String>,
+    /// Whether we're sending audio.
+    transmitting: bool,
+}
+
+/// The configured input and output devices, or None for the default device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioDevices {
+    pub input: Option<String>,
+    pub output: Option<String>,
+}
+
+/// User configs that are waiting to be written to the config file.
+#[derive(Debug, Default)]
+struct PendingUserConfigs {
+    configs: Mutex<Vec<UserConfig>>,
+    /// Held while the config file is written, so that saves are written in
+    /// order.
+    writing: Mutex<()>,
+}
+
+impl PendingUserConfigs {
+    /// Writes the pending user configs to the config file.
+    fn write(&self) {
+        let _writing = self.writing.lock().unwrap();
+        let pending = std::mem::take(&mut *self.configs.lock().unwrap());
+        if pending.is_empty() {
+            return;
+        }
+        // Read the config again so that changes that haven't been reloaded
+        // aren't overwritten.
+        let path = mumlib::config::default_cfg_path();
+        let mut config = match mumlib::config::read_cfg(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("Couldn't save user settings: {}", e);
+                return;
+            }
+        };
+        for user_config in pending {
+            upsert_user_config(&mut config.users, user_config);
+        }
+        if let Err(e) = config.write(&path, true) {
+            warn!("Couldn't save user settings: {}", e);
+        }
+    }
+}
+
+/// Replaces the saved config of a user, or adds it if there is none.
+fn upsert_user_config(users: &mut Vec<UserConfig>, user_config: UserConfig) {
+    match users
+        .iter_mut()
+        .find(|u| u.matches(&user_config.name, user_config.hash.as_deref()))
+    {
+        Some(existing) => *existing = user_config,
+        None => users.push(user_config),
+    }
+}
+
 #[derive(Debug)]
 pub struct State {
     config: Config,
     server: Option<Server>,
     audio_input: AudioInput,
     audio_output: AudioOutput,
-    message_buffer: Vec<(NaiveDateTime, String, u32)>,
+    message_buffer: Vec<ReceivedMessage>,
+    connection_stats: ConnectionStats,
+    /// The context and identity of the game we're sending our position from.
+    plugin: Option<(String, String)>,
+    /// Whether desktop notifications are held back.
+    do_not_disturb: bool,
+    /// Events whose notifications were held back, which are sent when do not
+    /// disturb is turned off.
+    held_notifications: Vec<MumbleEventKind>,
+    notification_backend: Box<dyn NotificationBackend>,
+    pending_user_configs: Arc<PendingUserConfigs>,
 
     phase_watcher: (watch::Sender<StatePhase>, watch::Receiver<StatePhase>),
+    talking_watcher: (watch::Sender<Talking>, watch::Receiver<Talking>),
+    /// The configured input and output devices.
+    device_watcher: (watch::Sender<AudioDevices>, watch::Receiver<AudioDevices>),
 
     events: Vec<MumbleEvent>,
 }
@@ -96,20 +193,42 @@ impl State {
     pub fn new() -> Result<Self, StateError> {
         let config = mumlib::config::read_cfg(&mumlib::config::default_cfg_path())?;
         let phase_watcher = watch::channel(StatePhase::Disconnected);
+        let echo_reference = EchoReference::new();
         let audio_input = AudioInput::new(
+            config.audio.input_device.as_deref(),
             config.audio.input_volume.unwrap_or(1.0),
+            config.audio.transformers.clone(),
+            EncoderSettings::new(&config.audio, None),
+            echo_reference.clone(),
             phase_watcher.1.clone(),
         )
         .map_err(StateError::AudioError)?;
-        let audio_output = AudioOutput::new(config.audio.output_volume.unwrap_or(1.0))
-            .map_err(StateError::AudioError)?;
+        let audio_output = AudioOutput::new(
+            config.audio.output_device.as_deref(),
+            config.audio.output_volume.unwrap_or(1.0),
+            echo_reference,
+        )
+        .map_err(StateError::AudioError)?;
+        let notification_backend = notifications::backend(&config.notifications);
+        let device_watcher = watch::channel(AudioDevices {
+            input: config.audio.input_device.clone(),
+            output: config.audio.output_device.clone(),
+        });
         let mut state = Self {
             config,
             server: None,
             audio_input,
             audio_output,
             message_buffer: Vec::new(),
+            connection_stats: ConnectionStats::default(),
+            plugin: None,
+            do_not_disturb: false,
+            held_notifications: Vec::new(),
+            notification_backend,
+            pending_user_configs: Arc::default(),
             phase_watcher,
+            talking_watcher: watch::channel(Talking::default()),
+            device_watcher,
             events: Vec::new(),
         };
         state.reload_config();
@@ -128,6 +247,7 @@ impl State {
         } else {
             self.update_user(msg);
         }
+        self.update_ducking_sessions();
     }
 
     fn create_user(&mut self, msg: msgs::UserState) {
@@ -157,11 +277,6 @@ impl State {
                     .to_string();
 
                 if this_channel == other_channel {
-                    notifications::send(format!(
-                        "{} connected and joined {}",
-                        msg.get_name(),
-                        this_channel_name,
-                    ));
                     self.push_event(MumbleEventKind::UserConnected(msg.get_name().to_string(), this_channel_name));
                     self.audio_output.play_effect(NotificationEvents::UserConnected);
                 }
@@ -172,6 +287,56 @@ impl State {
             .unwrap()
             .users_mut()
             .insert(session, user::User::new(msg));
+        self.apply_user_config(session);
+    }
+
+    /// Applies the volume, mute and pan we've saved for a user, if any.
+    fn apply_user_config(&mut self, session: u32) {
+        let user = match self.server.as_mut().and_then(|s| s.users_mut().get_mut(&session)) {
+            Some(user) => user,
+            None => return,
+        };
+        let user_config = match self
+            .config
+            .users
+            .iter()
+            .find(|u| u.matches(user.name(), user.hash()))
+        {
+            Some(user_config) => user_config,
+            None => return,
+        };
+        if let Some(mute) = user_config.mute {
+            user.set_suppressed(mute);
+            self.audio_output.set_mute(session, mute);
+        }
+        if let Some(volume) = user_config.volume {
+            self.audio_output.set_user_volume(session, volume);
+        }
+        if let Some(pan) = user_config.pan {
+            self.audio_output.set_user_pan(session, pan);
+        }
+    }
+
+    /// Saves the volume, mute and pan of a user to the config so that they're
+    /// applied the next time we see them. The config file is written in the
+    /// background.
+    fn save_user_config(&mut self, session: u32) {
+        let user = match self.server().and_then(|s| s.users().get(&session)) {
+            Some(user) => user,
+            None => return,
+        };
+        let (volume, mute) = self.audio_output.user_volume(session);
+        let user_config = UserConfig {
+            name: user.name().to_string(),
+            hash: user.hash().map(str::to_string),
+            volume: Some(volume),
+            mute: Some(mute),
+            pan: Some(self.audio_output.user_pan(session)),
+        };
+        upsert_user_config(&mut self.config.users, user_config.clone());
+        self.pending_user_configs.configs.lock().unwrap().push(user_config);
+        let pending = Arc::clone(&self.pending_user_configs);
+        tokio::task::spawn_blocking(move || pending.write());
     }
 
     fn update_user(&mut self, msg: msgs::UserState) {
@@ -212,11 +377,6 @@ impl State {
                     // User moved from our channel to somewhere else
                     if let Some(channel) = self.server().unwrap().channels().get(&to_channel) {
                         let channel = channel.name().to_string();
-                        notifications::send(format!(
-                            "{} moved to channel {}",
-                            &username,
-                            &channel,
-                        ));
                         self.push_event(MumbleEventKind::UserLeftChannel(username.clone(), channel));
                     }
                     self.audio_output.play_effect(NotificationEvents::UserLeftChannel);
@@ -224,11 +384,6 @@ impl State {
                     // User moved from somewhere else to our channel
                     if let Some(channel) = self.server().unwrap().channels().get(&from_channel) {
                         let channel = channel.name().to_string();
-                        notifications::send(format!(
-                            "{} moved to your channel from {}",
-                            &username,
-                            &channel,
-                        ));
                         self.push_event(MumbleEventKind::UserJoinedChannel(username.clone(), channel));
                     }
                     self.audio_output.play_effect(NotificationEvents::UserJoinedChannel);
@@ -248,7 +403,6 @@ impl State {
                     s += if deaf { " deafened" } else { " undeafened" };
                 }
                 s += " themselves";
-                notifications::send(s.clone());
                 self.push_event(MumbleEventKind::UserMuteStateChanged(s));
             }
         }
@@ -260,6 +414,13 @@ impl State {
             return;
         }
 
+        if Some(msg.get_session()) == self.server().unwrap().session_id() {
+            // The server disconnects us after this.
+            info!("Kicked from the server: {}", msg.get_reason());
+            self.audio_output.play_effect(NotificationEvents::Kicked);
+            return;
+        }
+
         let this_channel = self.get_users_channel(self.server().unwrap().session_id().unwrap());
         let other_channel = self.get_users_channel(msg.get_session());
         if this_channel == other_channel {
@@ -279,7 +440,6 @@ impl State {
                 .map(|u| u.name())
                 .unwrap_or("<unknown user>")
                 .to_string();
-            notifications::send(format!("{} disconnected", &user_name));
             self.push_event(MumbleEventKind::UserDisconnected(user_name, channel_name));
             self.audio_output.play_effect(NotificationEvents::UserDisconnected);
         }
@@ -288,13 +448,28 @@ impl State {
             .unwrap()
             .users_mut()
             .remove(&msg.get_session());
+        self.audio_output.remove_user(msg.get_session());
         info!("User {} disconnected", msg.get_session());
+        self.update_ducking_sessions();
     }
 
     pub fn reload_config(&mut self) {
         match mumlib::config::read_cfg(&mumlib::config::default_cfg_path()) {
             Ok(config) => {
-                self.config = config;
+                let old_config = std::mem::replace(&mut self.config, config);
+                let old_audio = old_config.audio;
+                if self.config.notifications != old_config.notifications {
+                    self.notification_backend = notifications::backend(&self.config.notifications);
+                }
+                if self.config.audio.input_device != old_audio.input_device
+                    || self.config.audio.output_device != old_audio.output_device
+                {
+                    // The streams are moved by audio::handle_devices.
+                    let _ = self.device_watcher.0.send(AudioDevices {
+                        input: self.config.audio.input_device.clone(),
+                        output: self.config.audio.output_device.clone(),
+                    });
+                }
             }
             Err(e) => error!("Couldn't read config: {}", e),
         }
@@ -304,13 +479,235 @@ impl State {
         if let Some(output_volume) = self.config.audio.output_volume {
             self.audio_output.set_volume(output_volume);
         }
-        if let Some(sound_effects) = &self.config.audio.sound_effects {
-            self.audio_output.load_sound_effects(sound_effects);
+        self.audio_input
+            .set_transformers(self.config.audio.transformers.clone());
+        self.update_encoder_settings();
+        self.audio_output.load_sound_effects(
+            self.config.audio.sound_effects.as_deref().unwrap_or_default(),
+        );
+        self.audio_output
+            .set_sound_effects_enabled(self.config.audio.sound_effects_enabled.unwrap_or(true));
+        self.audio_output
+            .set_positional_config(self.config.audio.positional.as_ref());
+        self.audio_output
+            .set_ducking_config(self.config.audio.ducking.as_ref());
+        self.audio_output
+            .set_limiter_config(self.config.audio.limiter.as_ref());
+        self.update_ducking_sessions();
+    }
+
+    /// Returns what's needed to build an audio stream on another device and
+    /// the name of the configured device.
+    pub fn audio_stream_builder(&self, stream: AudioStream) -> (StreamBuilder, Option<String>) {
+        match stream {
+            AudioStream::Input => (
+                StreamBuilder::Input(self.audio_input.stream_builder()),
+                self.config.audio.input_device.clone(),
+            ),
+            AudioStream::Output => (
+                StreamBuilder::Output(self.audio_output.stream_builder()),
+                self.config.audio.output_device.clone(),
+            ),
+        }
+    }
+
+    /// Moves an audio stream to a stream that was built on another device.
+    /// Returns the old stream, which should be dropped after the state is
+    /// unlocked since dropping it waits for its callback to finish.
+    pub fn set_audio_stream(&mut self, stream: NewStream) -> cpal::Stream {
+        let (old_stream, event) = match stream {
+            NewStream::Input(stream) => {
+                let old_stream = self.audio_input.set_stream(stream);
+                let name = self.audio_input.device_name().to_string();
+                (old_stream, MumbleEventKind::InputDeviceChanged(name))
+            }
+            NewStream::Output(stream) => {
+                let old_stream = self.audio_output.set_stream(stream);
+                let name = self.audio_output.device_name().to_string();
+                (old_stream, MumbleEventKind::OutputDeviceChanged(name))
+            }
+        };
+        info!("{}", event);
+        self.push_event(event);
+        old_stream
+    }
+
+    /// Lets the audio output know which users make other users quieter while
+    /// they're talking.
+    fn update_ducking_sessions(&self) {
+        let (config, server) = match (&self.config.audio.ducking, self.server()) {
+            (Some(config), Some(server)) => (config, server),
+            _ => return,
+        };
+        let priority_speakers = config.priority_speakers.unwrap_or(true);
+        let users = config.users.as_deref().unwrap_or_default();
+        let sessions = server
+            .users()
+            .iter()
+            .filter(|(_, user)| {
+                (priority_speakers && user.priority_speaker())
+                    || users.iter().any(|name| name == user.name())
+            })
+            .map(|(session, _)| *session)
+            .collect();
+        self.audio_output.set_ducking_sessions(sessions);
+    }
+
+    /// Applies the configured encoder settings, limited by the maximum
+    /// bandwidth of the current server.
+    pub fn update_encoder_settings(&self) {
+        let max_bandwidth = self.server().and_then(|server| server.max_bandwidth());
+        self.audio_input
+            .set_encoder_settings(EncoderSettings::new(&self.config.audio, max_bandwidth));
+    }
+
+    pub fn register_message(&mut self, msg: ReceivedMessage) {
+        self.message_buffer.push(msg);
+    }
+
+    /// Describes a text message from the server, with the names of its sender
+    /// and target channels. Returns None if we aren't connected.
+    pub fn received_message(&self, msg: &msgs::TextMessage) -> Option<ReceivedMessage> {
+        let server = self.server()?;
+        let channel_names = |ids: &[u32]| -> Vec<String> {
+            ids.iter()
+                .map(|id| {
+                    server
+                        .channels()
+                        .get(id)
+                        .map(|channel| channel.name().to_string())
+                        .unwrap_or(format!("Unknown channel {}", id))
+                })
+                .collect()
+        };
+        let kind = if !msg.get_tree_id().is_empty() {
+            MessageKind::Tree
+        } else if !msg.get_channel_id().is_empty() {
+            MessageKind::Channel
+        } else {
+            MessageKind::Private
+        };
+        Some(ReceivedMessage {
+            timestamp: chrono::Local::now().naive_local(),
+            sender: self.get_user_name(msg.get_actor())?,
+            sender_session: msg.get_actor(),
+            message: msg.get_message().to_string(),
+            kind,
+            channels: channel_names(msg.get_channel_id()),
+            trees: channel_names(msg.get_tree_id()),
+        })
+    }
+
+    /// Returns the config of the current server, if there is one.
+    fn server_config(&self) -> Option<&ServerConfig> {
+        let server = self.server()?;
+        let host = server.host()?;
+        self.config.servers.iter().find(|s| {
+            format!("{}:{}", s.host, s.port.unwrap_or(mumlib::DEFAULT_PORT)) == host
+                && (s.username.is_none() || s.username.as_deref() == server.username())
+        })
+    }
+
+    /// Returns the path of the avatar that should be set when connecting to
+    /// the current server and the largest avatar the server accepts, if an
+    /// avatar is configured. It should be read with [read_avatar] without
+    /// holding the state lock and passed to [State::initial_user_state].
+    pub fn initial_avatar(&self) -> Option<(PathBuf, Option<u32>)> {
+        let avatar = self.server_config()?.avatar.as_ref()?;
+        Some((PathBuf::from(avatar), self.server()?.max_image_length()))
+    }
+
+    /// Returns a user state containing the comment and avatar that should be
+    /// set when connecting to the current server, if any are configured, and
+    /// the game we're sending our position from, if any.
+    pub fn initial_user_state(&self, avatar: Option<Vec<u8>>) -> Option<msgs::UserState> {
+        let comment = self.server_config().and_then(|s| s.comment.as_ref());
+        if comment.is_none() && avatar.is_none() && self.plugin.is_none() {
+            return None;
+        }
+
+        let mut msg = msgs::UserState::new();
+        if let Some(comment) = comment {
+            msg.set_comment(comment.clone());
+        }
+        if let Some(avatar) = avatar {
+            msg.set_texture(avatar);
+        }
+        if let Some((context, identity)) = &self.plugin {
+            msg.set_plugin_context(context.clone().into_bytes());
+            msg.set_plugin_identity(identity.clone());
+        }
+        Some(msg)
+    }
+
+    /// Sets the game we're sending our position from, telling the server if
+    /// it changed.
+    fn set_plugin(
+        &mut self,
+        plugin: Option<(String, String)>,
+        packet_sender: &mut mpsc::UnboundedSender<ControlPacket<Serverbound>>,
+    ) {
+        if self.plugin == plugin {
+            return;
+        }
+        self.plugin = plugin;
+        if !matches!(*self.phase_receiver().borrow(), StatePhase::Connected(_)) {
+            // Sent when connecting instead.
+            return;
         }
+        let (context, identity) = self.plugin.clone().unwrap_or_default();
+        let mut msg = msgs::UserState::new();
+        msg.set_session(self.server().unwrap().session_id().unwrap());
+        msg.set_plugin_context(context.into_bytes());
+        msg.set_plugin_identity(identity);
+        packet_sender.send(msg.into()).unwrap();
     }
 
-    pub fn register_message(&mut self, msg: (String, u32)) {
-        self.message_buffer.push((chrono::Local::now().naive_local(), msg.0, msg.1));
+    /// Updates who is talking from the audio that is being played and sent.
+    pub fn update_talking(&mut self) {
+        let connected = matches!(*self.phase_receiver().borrow(), StatePhase::Connected(_));
+        let talking_sessions = self.audio_output.talking_sessions();
+        let open = self.audio_input.level_receiver().borrow().open;
+
+        let mut talking = Talking::default();
+        let mut events = Vec::new();
+        if let Some(server) = self.server.as_mut() {
+            let own_session = server.session_id();
+            for (session, user) in server.users_mut() {
+                let is_us = Some(*session) == own_session;
+                let is_talking = connected
+                    && if is_us {
+                        open && !user.self_mute() && !user.self_deaf()
+                    } else {
+                        talking_sessions.contains(session)
+                    };
+                if is_us {
+                    talking.transmitting = is_talking;
+                } else if is_talking {
+                    talking.users.push(user.name().to_string());
+                }
+
+                if user.talking() != is_talking {
+                    user.set_talking(is_talking);
+                    if !is_us {
+                        let name = user.name().to_string();
+                        events.push(if is_talking {
+                            MumbleEventKind::UserStartedTalking(name)
+                        } else {
+                            MumbleEventKind::UserStoppedTalking(name)
+                        });
+                    }
+                }
+            }
+        }
+        talking.users.sort();
+
+        for event in events {
+            self.push_event(event);
+        }
+        if *self.talking_watcher.1.borrow() != talking {
+            self.talking_watcher.0.send(talking).unwrap();
+        }
     }
 
     pub fn broadcast_phase(&self, phase: StatePhase) {
@@ -323,9 +720,45 @@ impl State {
             .play_effect(NotificationEvents::ServerConnect);
     }
 
-    /// Store a new event
+    /// Store a new event, run its hooks and send a notification about it.
+    /// The notification is held back until do not disturb is turned off if
+    /// it's on. Users starting and stopping to talk aren't stored since it
+    /// happens all the time; they're streamed by [Command::Talking] instead.
     pub fn push_event(&mut self, kind: MumbleEventKind) {
-        self.events.push(MumbleEvent { timestamp: chrono::Local::now().naive_local(), kind });
+        hooks::run(&self.config.hooks, &kind);
+        let talking = matches!(
+            kind,
+            MumbleEventKind::UserStartedTalking(_) | MumbleEventKind::UserStoppedTalking(_)
+        );
+        if !self.do_not_disturb {
+            notifications::send_event(
+                self.notification_backend.as_ref(),
+                &self.config.notifications,
+                &kind,
+            );
+        } else if !talking {
+            // Talking is over by the time the notification would be shown.
+            self.held_notifications.push(kind.clone());
+        }
+        if !talking {
+            self.events.push(MumbleEvent { timestamp: chrono::Local::now().naive_local(), kind });
+        }
+    }
+
+    /// Turns do not disturb on or off. The notifications that were held back
+    /// are sent when it's turned off.
+    fn set_do_not_disturb(&mut self, enabled: bool) {
+        self.do_not_disturb = enabled;
+        if enabled {
+            return;
+        }
+        for kind in std::mem::take(&mut self.held_notifications) {
+            notifications::send_event(
+                self.notification_backend.as_ref(),
+                &self.config.notifications,
+                &kind,
+            );
+        }
     }
 
     pub fn audio_input(&self) -> &AudioInput {
@@ -334,6 +767,18 @@ impl State {
     pub fn audio_output(&self) -> &AudioOutput {
         &self.audio_output
     }
+    pub fn connection_stats(&self) -> &ConnectionStats {
+        &self.connection_stats
+    }
+    pub fn connection_stats_mut(&mut self) -> &mut ConnectionStats {
+        &mut self.connection_stats
+    }
+
+    /// Returns a receiver of the configured input and output devices.
+    pub fn device_receiver(&self) -> watch::Receiver<AudioDevices> {
+        self.device_watcher.1.clone()
+    }
+
     pub fn phase_receiver(&self) -> watch::Receiver<StatePhase> {
         self.phase_watcher.1.clone()
     }
@@ -381,6 +826,72 @@ pub fn handle_command(
 ) -> ExecutionContext {
     let mut state = og_state.write().unwrap();
     match command {
+        Command::AudioLoopback { delay_ms } => {
+            // The loopback stops when the stream is dropped, which happens
+            // when a response can't be sent to the client.
+            let loopback = state.audio_input.loopback(Duration::from_millis(delay_ms));
+            let interval = tokio::time::interval(LOOPBACK_KEEPALIVE_INTERVAL);
+            ExecutionContext::Stream(Box::pin(stream::unfold(
+                (interval, loopback),
+                |(mut interval, loopback)| async move {
+                    interval.tick().await;
+                    Some((Ok(None), (interval, loopback)))
+                },
+            )))
+        }
+        Command::ChannelInfo { channel_identifier } => {
+            if !matches!(*state.phase_receiver().borrow(), StatePhase::Connected(_)) {
+                return now!(Err(Error::Disconnected));
+            }
+
+            let (id, channel) = match state.server().unwrap().channel_name(&channel_identifier) {
+                Ok(channel) => channel,
+                Err(e) => return now!(Err(Error::ChannelIdentifierError(channel_identifier, e))),
+            };
+            if !channel.description_missing() {
+                let channel = channel.into();
+                return now!(Ok(Some(CommandResponse::ChannelInfo { channel })));
+            }
+
+            let mut msg = msgs::RequestBlob::new();
+            msg.mut_channel_description().push(id);
+            packet_sender.send(msg.into()).unwrap();
+
+            let ref_state = Arc::clone(&og_state);
+            ExecutionContext::TcpEventSubscriber(
+                TcpEvent::ChannelState,
+                Box::new(move |data, sender| {
+                    if let TcpEventData::ChannelState(changed) = data {
+                        if matches!(changed, Some(changed) if changed != id) {
+                            return true;
+                        }
+                        let state = ref_state.read().unwrap();
+                        if !matches!(*state.phase_receiver().borrow(), StatePhase::Connected(_)) {
+                            let _ = sender.send(Err(Error::Disconnected));
+                            return false;
+                        }
+                        match state.server().and_then(|s| s.channels().get(&id)) {
+                            Some(channel) if channel.description_missing() => true,
+                            Some(channel) => {
+                                let _ = sender.send(Ok(Some(CommandResponse::ChannelInfo {
+                                    channel: channel.into(),
+                                })));
+                                false
+                            }
+                            None => {
+                                let _ = sender.send(Err(Error::ChannelIdentifierError(
+                                    channel_identifier.clone(),
+                                    ChannelIdentifierError::Invalid,
+                                )));
+                                false
+                            }
+                        }
+                    } else {
+                        unreachable!("Should only receive a ChannelState data when listening to ChannelState events");
+                    }
+                }),
+            )
+        }
         Command::ChannelJoin { channel_identifier } => {
             if !matches!(*state.phase_receiver().borrow(), StatePhase::Connected(_)) {
                 return now!(Err(Error::Disconnected));
@@ -407,6 +918,12 @@ pub fn handle_command(
             );
             now!(Ok(Some(CommandResponse::ChannelList { channels: list })))
         }
+        Command::ClearPosition => {
+            state.audio_input.set_position(None);
+            state.audio_output.set_listener(None);
+            state.set_plugin(None, packet_sender);
+            now!(Ok(None))
+        }
         Command::ConfigReload => {
             state.reload_config();
             now!(Ok(None))
@@ -467,6 +984,11 @@ pub fn handle_command(
                 new_deaf.map(|b| CommandResponse::DeafenStatus { is_deafened: b })
             ))
         }
+        Command::DoNotDisturb(toggle) => {
+            let enabled = toggle.unwrap_or(!state.do_not_disturb);
+            state.set_do_not_disturb(enabled);
+            now!(Ok(Some(CommandResponse::DoNotDisturbStatus { enabled })))
+        }
         Command::Events { block } => {
             if block {
                 warn!("Blocking event list is unimplemented");
@@ -480,6 +1002,23 @@ pub fn handle_command(
                 ExecutionContext::Now(Box::new(move || Box::new(events.into_iter())))
             }
         }
+        Command::InputLevel { block } => {
+            let level_receiver = state.audio_input.level_receiver();
+            if block {
+                let interval = tokio::time::interval(INPUT_LEVEL_INTERVAL);
+                ExecutionContext::Stream(Box::pin(stream::unfold(
+                    (interval, level_receiver),
+                    |(mut interval, level_receiver)| async move {
+                        interval.tick().await;
+                        let response = input_level_response(&level_receiver.borrow());
+                        Some((Ok(Some(response)), (interval, level_receiver)))
+                    },
+                )))
+            } else {
+                let response = input_level_response(&level_receiver.borrow());
+                now!(Ok(Some(response)))
+            }
+        }
         Command::InputVolumeSet(volume) => {
             state.audio_input.set_volume(volume);
             now!(Ok(None))
@@ -515,6 +1054,7 @@ pub fn handle_command(
             if let Some(action) = action {
                 user.set_suppressed(action);
                 state.audio_output.set_mute(id, action);
+                state.save_user_config(id);
             }
 
             return now!(Ok(None));
@@ -598,6 +1138,8 @@ pub fn handle_command(
             *server.password_mut() = password;
             *server.host_mut() = Some(format!("{}:{}", host, port));
             state.server = Some(server);
+            state.audio_output.clear_users();
+            state.connection_stats = ConnectionStats::default();
             state.phase_watcher.0.send(StatePhase::Connecting).unwrap();
 
             let socket_addr = match (host.as_ref(), port)
@@ -647,6 +1189,7 @@ pub fn handle_command(
             }
 
             state.server = None;
+            state.audio_output.clear_users();
 
             state
                 .phase_watcher
@@ -679,15 +1222,239 @@ pub fn handle_command(
                 }))
             }),
         ),
+        Command::SetAvatar(path) => {
+            if !matches!(*state.phase_receiver().borrow(), StatePhase::Connected(_)) {
+                return now!(Err(Error::Disconnected));
+            }
+
+            let server = state.server().unwrap();
+            let session = server.session_id().unwrap();
+            let max_length = server.max_image_length();
+            let packet_sender = packet_sender.clone();
+            // The avatar is read without holding the state lock.
+            ExecutionContext::Stream(Box::pin(stream::once(async move {
+                let texture = match path {
+                    Some(path) => read_avatar(path, max_length).await?,
+                    None => Vec::new(),
+                };
+
+                let mut msg = msgs::UserState::new();
+                msg.set_session(session);
+                msg.set_texture(texture);
+                packet_sender.send(msg.into()).unwrap();
+                Ok(None)
+            })))
+        }
+        Command::SetComment(comment) => {
+            if !matches!(*state.phase_receiver().borrow(), StatePhase::Connected(_)) {
+                return now!(Err(Error::Disconnected));
+            }
+
+            let mut msg = msgs::UserState::new();
+            msg.set_session(state.server().unwrap().session_id().unwrap());
+            msg.set_comment(comment);
+            packet_sender.send(msg.into()).unwrap();
+            now!(Ok(None))
+        }
+        Command::SetPosition {
+            pos,
+            front,
+            top,
+            context,
+            identity,
+        } => {
+            state.audio_input.set_position(Some(pos));
+            state
+                .audio_output
+                .set_listener(Some(Listener { pos, front, top }));
+            state.set_plugin(Some((context, identity)), packet_sender);
+            now!(Ok(None))
+        }
         Command::Status => {
             if !matches!(*state.phase_receiver().borrow(), StatePhase::Connected(_)) {
                 return now!(Err(Error::Disconnected));
             }
+            let udp = matches!(
+                *state.phase_receiver().borrow(),
+                StatePhase::Connected(VoiceStreamType::Udp)
+            );
+            let connection_stats = state.connection_stats.to_mumlib(udp);
             let state = state.server.as_ref().unwrap().into();
             now!(Ok(Some(CommandResponse::Status {
                 server_state: state, //guaranteed not to panic because if we are connected, server is guaranteed to be Some
+                connection_stats,
             })))
         }
+        Command::Talking { block } => {
+            let talking_receiver = state.talking_watcher.1.clone();
+            if block {
+                ExecutionContext::Stream(Box::pin(stream::unfold(
+                    (talking_receiver, true),
+                    |(mut talking_receiver, first)| async move {
+                        if !first {
+                            talking_receiver.changed().await.ok()?;
+                        }
+                        let response = talking_response(&talking_receiver.borrow());
+                        Some((Ok(Some(response)), (talking_receiver, false)))
+                    },
+                )))
+            } else {
+                let response = talking_response(&talking_receiver.borrow());
+                now!(Ok(Some(response)))
+            }
+        }
+        Command::UserInfo { user } => {
+            if !matches!(*state.phase_receiver().borrow(), StatePhase::Connected(_)) {
+                return now!(Err(Error::Disconnected));
+            }
+
+            let (session, user) = match state
+                .server()
+                .unwrap()
+                .users()
+                .iter()
+                .find(|(_, u)| u.name() == user)
+            {
+                Some((session, user)) => (*session, user),
+                None => return now!(Err(Error::InvalidUsername(user))),
+            };
+            if !user.comment_missing() && !user.texture_missing() {
+                let texture = user.texture().map(|t| t.to_vec());
+                let user = user.into();
+                return now!(Ok(Some(CommandResponse::UserInfo { user, texture })));
+            }
+
+            let name = user.name().to_string();
+            let mut msg = msgs::RequestBlob::new();
+            if user.comment_missing() {
+                msg.mut_session_comment().push(session);
+            }
+            if user.texture_missing() {
+                msg.mut_session_texture().push(session);
+            }
+            packet_sender.send(msg.into()).unwrap();
+
+            // The comment and texture might arrive in separate user states, so
+            // wait until we have both.
+            let ref_state = Arc::clone(&og_state);
+            ExecutionContext::TcpEventSubscriber(
+                TcpEvent::UserState,
+                Box::new(move |data, sender| {
+                    if let TcpEventData::UserState(changed) = data {
+                        if matches!(changed, Some(changed) if changed != session) {
+                            return true;
+                        }
+                        let state = ref_state.read().unwrap();
+                        if !matches!(*state.phase_receiver().borrow(), StatePhase::Connected(_)) {
+                            let _ = sender.send(Err(Error::Disconnected));
+                            return false;
+                        }
+                        match state.server().and_then(|s| s.users().get(&session)) {
+                            Some(user) if user.comment_missing() || user.texture_missing() => true,
+                            Some(user) => {
+                                let _ = sender.send(Ok(Some(CommandResponse::UserInfo {
+                                    user: user.into(),
+                                    texture: user.texture().map(|t| t.to_vec()),
+                                })));
+                                false
+                            }
+                            None => {
+                                let _ = sender.send(Err(Error::InvalidUsername(name.clone())));
+                                false
+                            }
+                        }
+                    } else {
+                        unreachable!("Should only receive a UserState data when listening to UserState events");
+                    }
+                }),
+            )
+        }
+        Command::UserStats { user } => {
+            if !matches!(*state.phase_receiver().borrow(), StatePhase::Connected(_)) {
+                return now!(Err(Error::Disconnected));
+            }
+
+            let session = match state
+                .server()
+                .unwrap()
+                .users()
+                .iter()
+                .find(|(_, u)| u.name() == user)
+            {
+                Some((session, _)) => *session,
+                None => return now!(Err(Error::InvalidUsername(user))),
+            };
+
+            let mut msg = msgs::UserStats::new();
+            msg.set_session(session);
+            msg.set_stats_only(false);
+            packet_sender.send(msg.into()).unwrap();
+
+            // Stats of other users might arrive first, e.g. if they're
+            // requested at the same time.
+            ExecutionContext::TcpEventSubscriber(
+                TcpEvent::UserStats,
+                Box::new(move |data, sender| {
+                    if let TcpEventData::UserStats(msg) = data {
+                        match msg {
+                            Ok(msg) if msg.get_session() != session => true,
+                            Ok(msg) => {
+                                let _ = sender.send(Ok(Some(CommandResponse::UserStats {
+                                    stats: user::user_stats(user.clone(), msg),
+                                })));
+                                false
+                            }
+                            Err(Error::InvalidUsername(name)) if name != user => true,
+                            Err(e) => {
+                                let _ = sender.send(Err(e));
+                                false
+                            }
+                        }
+                    } else {
+                        unreachable!("Should only receive a UserStats data when listening to UserStats events");
+                    }
+                }),
+            )
+        }
+        Command::UserPanSet(string, pan) => {
+            if !matches!(*state.phase_receiver().borrow(), StatePhase::Connected(_)) {
+                return now!(Err(Error::Disconnected));
+            }
+            let user_id = match state
+                .server()
+                .unwrap()
+                .users()
+                .iter()
+                .find(|e| e.1.name() == string)
+                .map(|e| *e.0)
+            {
+                None => return now!(Err(Error::InvalidUsername(string))),
+                Some(v) => v,
+            };
+
+            state.audio_output.set_user_pan(user_id, pan);
+            state.save_user_config(user_id);
+            now!(Ok(None))
+        }
+        Command::UserVolumeGet(string) => {
+            if !matches!(*state.phase_receiver().borrow(), StatePhase::Connected(_)) {
+                return now!(Err(Error::Disconnected));
+            }
+            let user_id = match state
+                .server()
+                .unwrap()
+                .users()
+                .iter()
+                .find(|e| e.1.name() == string)
+                .map(|e| *e.0)
+            {
+                None => return now!(Err(Error::InvalidUsername(string))),
+                Some(v) => v,
+            };
+
+            let (volume, _) = state.audio_output.user_volume(user_id);
+            now!(Ok(Some(CommandResponse::UserVolume { volume })))
+        }
         Command::UserVolumeSet(string, volume) => {
             if !matches!(*state.phase_receiver().borrow(), StatePhase::Connected(_)) {
                 return now!(Err(Error::Disconnected));
@@ -705,6 +1472,7 @@ pub fn handle_command(
             };
 
             state.audio_output.set_user_volume(user_id, volume);
+            state.save_user_config(user_id);
             now!(Ok(None))
         }
         Command::PastMessages { block } => {
@@ -718,18 +1486,12 @@ pub fn handle_command(
                     TcpEvent::TextMessage,
                     Box::new(move |data, sender| {
                         if let TcpEventData::TextMessage(a) = data {
-                            let message = (
-                                chrono::Local::now().naive_local(),
-                                a.get_message().to_owned(),
-                                ref_state
-                                    .read()
-                                    .unwrap()
-                                    .get_user_name(a.get_actor())
-                                    .unwrap(),
-                            );
-                            sender
-                                .send(Ok(Some(CommandResponse::PastMessage { message })))
-                                .is_ok()
+                            match ref_state.read().unwrap().received_message(a) {
+                                Some(message) => sender
+                                    .send(Ok(Some(CommandResponse::PastMessage { message })))
+                                    .is_ok(),
+                                None => true,
+                            }
                         } else {
                             unreachable!("Should only receive a TextMessage data when listening to TextMessage events");
                         }
@@ -739,8 +1501,7 @@ pub fn handle_command(
                 let messages = std::mem::take(&mut state.message_buffer);
                 let messages: Vec<_> = messages
                     .into_iter()
-                    .map(|(timestamp, msg, user)| (timestamp, msg, state.get_user_name(user).unwrap()))
-                    .map(|e| Ok(Some(CommandResponse::PastMessage { message: e })))
+                    .map(|message| Ok(Some(CommandResponse::PastMessage { message })))
                     .collect();
 
                 ExecutionContext::Now(Box::new(move || Box::new(messages.into_iter())))
@@ -800,3 +1561,52 @@ pub fn handle_command(
         }
     }
 }
+
+/// Reads an avatar from disk, making sure that it is a PNG or JPEG image and
+/// that it isn't larger than what the server accepts.
+pub async fn read_avatar(path: PathBuf, max_length: Option<u32>) -> mumlib::error::Result<Vec<u8>> {
+    const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+    const JPEG_MAGIC: &[u8] = b"\xff\xd8\xff";
+
+    let texture = tokio::fs::read(&path)
+        .await
+        .map_err(|e| Error::InvalidAvatar(format!("{}: {}", path.display(), e)))?;
+    if !texture.starts_with(PNG_MAGIC) && !texture.starts_with(JPEG_MAGIC) {
+        return Err(Error::InvalidAvatar(format!(
+            "{}: not a PNG or JPEG image",
+            path.display()
+        )));
+    }
+    match max_length {
+        // A limit of 0 means that the server doesn't limit the size.
+        Some(max_length) if max_length != 0 && texture.len() > max_length as usize => {
+            Err(Error::AvatarTooLarge(texture.len(), max_length))
+        }
+        _ => Ok(texture),
+    }
+}
+
+fn input_level_response(level: &InputLevel) -> CommandResponse {
+    CommandResponse::InputLevel {
+        level: level.level,
+        open: level.open,
+        open_threshold: level.thresholds.map(|(open, _)| open),
+        close_threshold: level.thresholds.map(|(_, close)| close),
+    }
+}
+
+fn talking_response(talking: &Talking) -> CommandResponse {
+    CommandResponse::Talking {
+        users: talking.users.clone(),
+        transmitting: talking.transmitting,
+    }
+}
+
+/// Keeps track of who is talking.
+pub async fn handle_talking(state: Arc<RwLock<State>>) {
+    let mut interval = tokio::time::interval(TALKING_INTERVAL);
+    loop {
+        interval.tick().await;
+        state.write().unwrap().update_talking();
+    }
+}
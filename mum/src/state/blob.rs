@@ -0,0 +1,66 @@
+//! Comments, channel descriptions and textures that are larger than some limit
+//! are only sent by the server as a SHA-1 hash and need to be requested
+//! separately. Since they rarely change they are cached on disk, indexed by
+//! their hash.
+//!
+//! The cache is never read or written while the state is locked.
+
+use log::*;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Returns the hash the server uses to refer to a blob.
+pub fn hash(data: &[u8]) -> Vec<u8> {
+    Sha1::digest(data).to_vec()
+}
+
+fn path(hash: &[u8]) -> Option<PathBuf> {
+    let name: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+    Some(dirs::cache_dir()?.join("mumd").join("blobs").join(name))
+}
+
+/// Returns the cached blobs with the specified hashes that exist, by their
+/// hash.
+pub async fn load_all(hashes: Vec<Vec<u8>>) -> HashMap<Vec<u8>, Vec<u8>> {
+    tokio::task::spawn_blocking(move || {
+        hashes
+            .into_iter()
+            .filter_map(|hash| load(&hash).map(|data| (hash, data)))
+            .collect()
+    })
+    .await
+    .unwrap_or_default()
+}
+
+/// Returns the cached blob with the specified hash, if it exists.
+fn load(hash: &[u8]) -> Option<Vec<u8>> {
+    let data = fs::read(path(hash)?).ok()?;
+    if self::hash(&data) == hash {
+        Some(data)
+    } else {
+        warn!("Cached blob {:?} is corrupt", path(hash));
+        None
+    }
+}
+
+/// Caches a blob on disk in the background. Failing to do so isn't fatal
+/// since the blob can always be requested again.
+pub fn store(hash: &[u8], data: &[u8]) {
+    let path = match path(hash) {
+        Some(path) => path,
+        None => return,
+    };
+    let data = data.to_vec();
+    tokio::task::spawn_blocking(move || {
+        let res = match path.parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(()),
+        }
+        .and_then(|_| fs::write(&path, data));
+        if let Err(e) = res {
+            warn!("Couldn't cache blob at {}: {}", path.display(), e);
+        }
+    });
+}
@@ -1,3 +1,4 @@
+use crate::state::blob;
 use crate::state::user::User;
 
 use mumble_protocol::control::msgs;
@@ -7,6 +8,7 @@ use std::collections::HashMap;
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Channel {
     description: Option<String>,
+    description_hash: Option<Vec<u8>>,
     links: Vec<u32>,
     max_users: u32,
     name: String,
@@ -16,12 +18,9 @@ pub struct Channel {
 
 impl Channel {
     pub fn new(mut msg: msgs::ChannelState) -> Self {
-        Self {
-            description: if msg.has_description() {
-                Some(msg.take_description())
-            } else {
-                None
-            },
+        let mut channel = Self {
+            description: None,
+            description_hash: None,
             links: Vec::new(),
             max_users: msg.get_max_users(),
             name: msg.take_name(),
@@ -31,12 +30,22 @@ impl Channel {
                 None
             },
             position: msg.get_position(),
+        };
+        if msg.has_description_hash() {
+            channel.set_description_hash(msg.take_description_hash());
+        }
+        if msg.has_description() {
+            channel.set_description(msg.take_description());
         }
+        channel
     }
 
     pub fn parse_channel_state(&mut self, mut msg: msgs::ChannelState) {
+        if msg.has_description_hash() {
+            self.set_description_hash(msg.take_description_hash());
+        }
         if msg.has_description() {
-            self.description = Some(msg.take_description());
+            self.set_description(msg.take_description());
         }
         self.links = msg.take_links();
         if msg.has_max_users() {
@@ -53,6 +62,38 @@ impl Channel {
         }
     }
 
+    fn set_description(&mut self, description: String) {
+        // See User::set_comment.
+        if self.description_hash.as_ref() == Some(&blob::hash(description.as_bytes())) {
+            blob::store(self.description_hash.as_ref().unwrap(), description.as_bytes());
+        } else {
+            self.description_hash = None;
+        }
+        self.description = Some(description);
+    }
+
+    fn set_description_hash(&mut self, hash: Vec<u8>) {
+        if self.description_hash.as_ref() != Some(&hash) {
+            self.description = None;
+            self.description_hash = Some(hash);
+        }
+    }
+
+    /// Sets the description from a cached blob, if it's missing and there is
+    /// a blob with its hash.
+    pub fn load_cached_blobs(&mut self, blobs: &HashMap<Vec<u8>, Vec<u8>>) {
+        if self.description_missing() {
+            self.description = blobs
+                .get(self.description_hash.as_ref().unwrap())
+                .and_then(|description| String::from_utf8(description.clone()).ok());
+        }
+    }
+
+    /// Returns true if the description needs to be requested from the server.
+    pub fn description_missing(&self) -> bool {
+        self.description_hash.is_some() && self.description.is_none()
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -19,6 +19,11 @@ pub struct Server {
     session_id: Option<u32>,
     muted: bool,
     deafened: bool,
+    /// The maximum size of an image (including avatars) the server accepts.
+    max_image_length: Option<u32>,
+    /// The maximum bandwidth in bits per second the server allows each user
+    /// to send.
+    max_bandwidth: Option<u32>,
 
     host: Option<String>,
 }
@@ -34,6 +39,8 @@ impl Server {
             session_id: None,
             muted: false,
             deafened: false,
+            max_image_length: None,
+            max_bandwidth: None,
             host: None,
         }
     }
@@ -42,6 +49,18 @@ impl Server {
         if msg.has_welcome_text() {
             self.welcome_text = Some(msg.take_welcome_text());
         }
+        if msg.has_max_bandwidth() {
+            self.max_bandwidth = Some(msg.get_max_bandwidth());
+        }
+    }
+
+    pub fn parse_server_config(&mut self, msg: msgs::ServerConfig) {
+        if msg.has_image_message_length() {
+            self.max_image_length = Some(msg.get_image_message_length());
+        }
+        if msg.has_max_bandwidth() {
+            self.max_bandwidth = Some(msg.get_max_bandwidth());
+        }
     }
 
     pub fn parse_channel_state(&mut self, msg: msgs::ChannelState) {
@@ -89,6 +108,10 @@ impl Server {
         &self.channels
     }
 
+    pub fn channels_mut(&mut self) -> &mut HashMap<u32, Channel> {
+        &mut self.channels
+    }
+
     /// Takes a channel name and returns either a tuple with the channel id and a reference to the
     /// channel struct if the channel name unambiguosly refers to a channel, or an error describing
     /// if the channel identifier was ambigous or invalid.
@@ -141,6 +164,10 @@ impl Server {
         Some((channel_id, channel))
     }
 
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
     pub fn host_mut(&mut self) -> &mut Option<String> {
         &mut self.host
     }
@@ -192,6 +219,14 @@ impl Server {
     pub fn set_deafened(&mut self, value: bool) {
         self.deafened = value;
     }
+
+    pub fn max_image_length(&self) -> Option<u32> {
+        self.max_image_length
+    }
+
+    pub fn max_bandwidth(&self) -> Option<u32> {
+        self.max_bandwidth
+    }
 }
 
 impl From<&Server> for mumlib::state::Server {
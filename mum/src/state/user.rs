@@ -1,14 +1,25 @@
+use crate::state::blob;
+
 use mumble_protocol::control::msgs;
+use mumlib::state::{PacketStats, UserStats};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct User {
     channel: u32,
     comment: Option<String>,
+    comment_hash: Option<Vec<u8>>,
     hash: Option<String>,
     name: String,
     priority_speaker: bool,
     recording: bool,
+    texture: Option<Vec<u8>>,
+    texture_hash: Option<Vec<u8>>,
+    /// Whether audio from the user is being played, or for ourselves whether
+    /// we're sending audio.
+    talking: bool,
 
     suppress: bool,  // by me
     self_mute: bool, // by self
@@ -19,13 +30,10 @@ pub struct User {
 
 impl User {
     pub fn new(mut msg: msgs::UserState) -> Self {
-        Self {
+        let mut user = Self {
             channel: msg.get_channel_id(),
-            comment: if msg.has_comment() {
-                Some(msg.take_comment())
-            } else {
-                None
-            },
+            comment: None,
+            comment_hash: None,
             hash: if msg.has_hash() {
                 Some(msg.take_hash())
             } else {
@@ -34,20 +42,39 @@ impl User {
             name: msg.take_name(),
             priority_speaker: msg.has_priority_speaker() && msg.get_priority_speaker(),
             recording: msg.has_recording() && msg.get_recording(),
+            texture: None,
+            texture_hash: None,
+            talking: false,
             suppress: msg.has_suppress() && msg.get_suppress(),
             self_mute: msg.has_self_mute() && msg.get_self_mute(),
             self_deaf: msg.has_self_deaf() && msg.get_self_deaf(),
             mute: msg.has_mute() && msg.get_mute(),
             deaf: msg.has_deaf() && msg.get_deaf(),
+        };
+        if msg.has_comment_hash() {
+            user.set_comment_hash(msg.take_comment_hash());
+        }
+        if msg.has_comment() {
+            user.set_comment(msg.take_comment());
+        }
+        if msg.has_texture_hash() {
+            user.set_texture_hash(msg.take_texture_hash());
         }
+        if msg.has_texture() {
+            user.set_texture(msg.take_texture());
+        }
+        user
     }
 
     pub fn parse_user_state(&mut self, mut msg: msgs::UserState) {
         if msg.has_channel_id() {
             self.channel = msg.get_channel_id();
         }
+        if msg.has_comment_hash() {
+            self.set_comment_hash(msg.take_comment_hash());
+        }
         if msg.has_comment() {
-            self.comment = Some(msg.take_comment());
+            self.set_comment(msg.take_comment());
         }
         if msg.has_hash() {
             self.hash = Some(msg.take_hash());
@@ -61,6 +88,12 @@ impl User {
         if msg.has_recording() {
             self.recording = msg.get_recording();
         }
+        if msg.has_texture_hash() {
+            self.set_texture_hash(msg.take_texture_hash());
+        }
+        if msg.has_texture() {
+            self.set_texture(msg.take_texture());
+        }
         if msg.has_suppress() {
             self.suppress = msg.get_suppress();
         }
@@ -79,8 +112,11 @@ impl User {
     }
 
     pub fn apply_user_diff(&mut self, diff: &UserDiff) {
+        if let Some(comment_hash) = diff.comment_hash.clone() {
+            self.set_comment_hash(comment_hash);
+        }
         if let Some(comment) = diff.comment.clone() {
-            self.comment = Some(comment);
+            self.set_comment(comment);
         }
         if let Some(hash) = diff.hash.clone() {
             self.hash = Some(hash);
@@ -94,6 +130,12 @@ impl User {
         if let Some(recording) = diff.recording {
             self.recording = recording;
         }
+        if let Some(texture_hash) = diff.texture_hash.clone() {
+            self.set_texture_hash(texture_hash);
+        }
+        if let Some(texture) = diff.texture.clone() {
+            self.set_texture(texture);
+        }
         if let Some(suppress) = diff.suppress {
             self.suppress = suppress;
         }
@@ -114,10 +156,82 @@ impl User {
         }
     }
 
+    fn set_comment(&mut self, comment: String) {
+        // If the comment matches the hash we know of, it was sent as a response
+        // to a blob request. Otherwise the comment was short enough to be sent
+        // directly and any old hash is outdated.
+        if self.comment_hash.as_ref() == Some(&blob::hash(comment.as_bytes())) {
+            blob::store(self.comment_hash.as_ref().unwrap(), comment.as_bytes());
+        } else {
+            self.comment_hash = None;
+        }
+        self.comment = Some(comment);
+    }
+
+    fn set_comment_hash(&mut self, hash: Vec<u8>) {
+        if self.comment_hash.as_ref() != Some(&hash) {
+            self.comment = None;
+            self.comment_hash = Some(hash);
+        }
+    }
+
+    fn set_texture(&mut self, texture: Vec<u8>) {
+        if self.texture_hash.as_ref() == Some(&blob::hash(&texture)) {
+            blob::store(self.texture_hash.as_ref().unwrap(), &texture);
+        } else {
+            self.texture_hash = None;
+        }
+        // An empty texture means that the texture was removed.
+        self.texture = if texture.is_empty() {
+            None
+        } else {
+            Some(texture)
+        };
+    }
+
+    fn set_texture_hash(&mut self, hash: Vec<u8>) {
+        if self.texture_hash.as_ref() != Some(&hash) {
+            self.texture = None;
+            self.texture_hash = Some(hash);
+        }
+    }
+
+    /// Sets the comment and texture from cached blobs, if they're missing and
+    /// there are blobs with their hashes.
+    pub fn load_cached_blobs(&mut self, blobs: &HashMap<Vec<u8>, Vec<u8>>) {
+        if self.comment_missing() {
+            self.comment = blobs
+                .get(self.comment_hash.as_ref().unwrap())
+                .and_then(|comment| String::from_utf8(comment.clone()).ok());
+        }
+        if self.texture_missing() {
+            self.texture = blobs.get(self.texture_hash.as_ref().unwrap()).cloned();
+        }
+    }
+
+    /// Returns true if the server has sent the hash of our comment but we
+    /// don't have the comment itself, meaning it needs to be requested.
+    pub fn comment_missing(&self) -> bool {
+        self.comment_hash.is_some() && self.comment.is_none()
+    }
+
+    /// Returns true if the texture needs to be requested from the server.
+    pub fn texture_missing(&self) -> bool {
+        self.texture_hash.is_some() && self.texture.is_none()
+    }
+
+    pub fn texture(&self) -> Option<&[u8]> {
+        self.texture.as_deref()
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
 
+    pub fn hash(&self) -> Option<&str> {
+        self.hash.as_deref()
+    }
+
     pub fn channel(&self) -> u32 {
         self.channel
     }
@@ -130,6 +244,18 @@ impl User {
         self.self_deaf
     }
 
+    pub fn talking(&self) -> bool {
+        self.talking
+    }
+
+    pub fn set_talking(&mut self, value: bool) {
+        self.talking = value;
+    }
+
+    pub fn priority_speaker(&self) -> bool {
+        self.priority_speaker
+    }
+
     pub fn suppressed(&self) -> bool {
         self.suppress
     }
@@ -147,6 +273,7 @@ impl From<&User> for mumlib::state::User {
             name: user.name.clone(),
             priority_speaker: user.priority_speaker,
             recording: user.recording,
+            talking: user.talking,
             suppress: user.suppress,
             self_mute: user.self_mute,
             self_deaf: user.self_deaf,
@@ -159,10 +286,13 @@ impl From<&User> for mumlib::state::User {
 #[derive(Debug, Default)]
 pub struct UserDiff {
     pub comment: Option<String>,
+    pub comment_hash: Option<Vec<u8>>,
     pub hash: Option<String>,
     pub name: Option<String>,
     pub priority_speaker: Option<bool>,
     pub recording: Option<bool>,
+    pub texture: Option<Vec<u8>>,
+    pub texture_hash: Option<Vec<u8>>,
 
     pub suppress: Option<bool>,  // by me
     pub self_mute: Option<bool>, // by self
@@ -185,6 +315,9 @@ impl From<msgs::UserState> for UserDiff {
         if msg.has_comment() {
             ud.comment = Some(msg.take_comment());
         }
+        if msg.has_comment_hash() {
+            ud.comment_hash = Some(msg.take_comment_hash());
+        }
         if msg.has_hash() {
             ud.hash = Some(msg.take_hash());
         }
@@ -197,6 +330,12 @@ impl From<msgs::UserState> for UserDiff {
         if msg.has_recording() {
             ud.recording = Some(msg.get_recording());
         }
+        if msg.has_texture() {
+            ud.texture = Some(msg.take_texture());
+        }
+        if msg.has_texture_hash() {
+            ud.texture_hash = Some(msg.take_texture_hash());
+        }
         if msg.has_suppress() {
             ud.suppress = Some(msg.get_suppress());
         }
@@ -218,3 +357,57 @@ impl From<msgs::UserState> for UserDiff {
         ud
     }
 }
+
+/// Converts a user stats message from the server.
+pub fn user_stats(name: String, msg: &msgs::UserStats) -> UserStats {
+    let packet_stats = |stats: &msgs::UserStats_Stats| PacketStats {
+        good: stats.get_good(),
+        late: stats.get_late(),
+        lost: stats.get_lost(),
+        resync: stats.get_resync(),
+    };
+    let version = if msg.has_version() {
+        Some(msg.get_version())
+    } else {
+        None
+    };
+    // Addresses are sent as IPv6, with IPv4 addresses mapped into ::ffff:0:0/96.
+    let address = match msg.get_address() {
+        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+            Some(IpAddr::V4(Ipv4Addr::new(*a, *b, *c, *d)))
+        }
+        address if address.len() == 16 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(address);
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    };
+    let optional = |has: bool, value: u32| if has { Some(value) } else { None };
+
+    UserStats {
+        name,
+        tcp_ping: (msg.get_tcp_ping_avg(), msg.get_tcp_ping_var()),
+        udp_ping: (msg.get_udp_ping_avg(), msg.get_udp_ping_var()),
+        tcp_packets: msg.get_tcp_packets(),
+        udp_packets: msg.get_udp_packets(),
+        from_client: packet_stats(msg.get_from_client()),
+        from_server: packet_stats(msg.get_from_server()),
+        version: version.filter(|v| v.has_version()).map(|v| {
+            let v = v.get_version();
+            ((v >> 16) as u16, (v >> 8) as u8, v as u8)
+        }),
+        release: version.filter(|v| v.has_release()).map(|v| v.get_release().to_string()),
+        os: version.filter(|v| v.has_os()).map(|v| v.get_os().to_string()),
+        os_version: version
+            .filter(|v| v.has_os_version())
+            .map(|v| v.get_os_version().to_string()),
+        opus: msg.get_opus(),
+        address,
+        bandwidth: optional(msg.has_bandwidth(), msg.get_bandwidth()),
+        online_secs: optional(msg.has_onlinesecs(), msg.get_onlinesecs()),
+        idle_secs: optional(msg.has_idlesecs(), msg.get_idlesecs()),
+        certificates: msg.get_certificates().len(),
+        strong_certificate: msg.get_strong_certificate(),
+    }
+}
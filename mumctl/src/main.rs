@@ -17,13 +17,14 @@ use colored::Colorize;
 use log::{Level, LevelFilter, Metadata, Record, error, warn};
 use mumlib::command::{ChannelTarget, Command as MumCommand, CommandResponse, MessageTarget};
 use mumlib::config::{self, Config, ServerConfig};
-use mumlib::state::Channel as MumChannel;
+use mumlib::state::{Channel as MumChannel, ConnectionStats, PacketStats, UserStats};
 use serde::de::DeserializeOwned;
 use std::fmt;
 use std::io::{self, BufRead, Read, Write};
 use std::iter;
 use std::marker::PhantomData;
 use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
 use std::thread;
 use structopt::{clap::Shell, StructOpt};
 
@@ -81,6 +82,14 @@ enum Command {
     Channel(Channel),
     /// Show current status
     Status,
+    /// Show statistics about the connection to the server
+    Stats {
+        /// Keep refreshing the statistics every second
+        #[structopt(short = "w", long = "watch")]
+        watch: bool,
+    },
+    /// Handle audio input and output
+    Audio(Audio),
     /// Change config values
     Config { key: String, value: String },
     /// Reload the config file
@@ -89,6 +98,9 @@ enum Command {
     Completions(Completions),
     /// Change volume of either you or someone else
     Volume { user: String, volume: Option<f32> },
+    /// Place someone in the stereo field, from -1 (left) to 1 (right)
+    #[structopt(setting = structopt::clap::AppSettings::AllowNegativeNumbers)]
+    Pan { user: String, pan: f32 },
     /// Mute someone/yourself
     Mute { user: Option<String> },
     /// Unmute someone/yourself
@@ -97,6 +109,12 @@ enum Command {
     Deafen,
     /// Undeafen yourself
     Undeafen,
+    /// Stop or start sending desktop notifications. Toggles if neither on nor
+    /// off is given
+    Dnd {
+        #[structopt(subcommand)]
+        state: Option<Dnd>,
+    },
     /// Get messages sent to the server you're currently connected to
     Messages {
         #[structopt(short = "f", long = "follow")]
@@ -109,6 +127,97 @@ enum Command {
         #[structopt(short = "f", long = "follow")]
         follow: bool,
     },
+    /// Show who is talking
+    Talking {
+        /// Keep printing who is talking every time it changes
+        #[structopt(short = "f", long = "follow")]
+        follow: bool,
+    },
+    /// Set or clear your comment
+    Comment(Comment),
+    /// Set or clear your avatar
+    Avatar(Avatar),
+    /// Handle users in the connected server
+    User(User),
+    /// Set or clear your position for positional audio
+    Position(Position),
+}
+
+#[derive(Debug, StructOpt)]
+enum Audio {
+    /// Show the live level of the microphone together with the noise gate
+    /// thresholds
+    Meter,
+    /// Play your own audio back to you until enter is pressed. Audio isn't sent
+    /// to the server meanwhile
+    Test {
+        /// How long to delay the audio, in milliseconds
+        #[structopt(short = "d", long = "delay", default_value = "0")]
+        delay: u64,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum Dnd {
+    /// Stop sending desktop notifications
+    On,
+    /// Start sending desktop notifications again
+    Off,
+}
+
+#[derive(Debug, StructOpt)]
+enum Comment {
+    /// Set your comment
+    Set { comment: String },
+    /// Clear your comment
+    Clear,
+}
+
+#[derive(Debug, StructOpt)]
+enum Avatar {
+    /// Set your avatar to a PNG or JPEG image
+    Set { path: PathBuf },
+    /// Clear your avatar
+    Clear,
+}
+
+#[derive(Debug, StructOpt)]
+enum Position {
+    /// Set your position in meters. Only users with the same context hear you
+    /// positionally
+    #[structopt(setting = structopt::clap::AppSettings::AllowNegativeNumbers)]
+    Set {
+        x: f32,
+        y: f32,
+        z: f32,
+        /// The direction you're facing
+        #[structopt(long = "front", number_of_values = 3, default_value = "0 0 1", use_delimiter = true, value_delimiter = " ")]
+        front: Vec<f32>,
+        /// The direction of the top of your head
+        #[structopt(long = "top", number_of_values = 3, default_value = "0 1 0", use_delimiter = true, value_delimiter = " ")]
+        top: Vec<f32>,
+        /// Identifies the game and the server you're playing on
+        #[structopt(short = "c", long = "context", default_value = "mumctl")]
+        context: String,
+        /// Identifies you within the game
+        #[structopt(short = "i", long = "identity", default_value = "")]
+        identity: String,
+    },
+    /// Stop sending your position
+    Clear,
+}
+
+#[derive(Debug, StructOpt)]
+enum User {
+    /// Show a user's comment and avatar
+    Info {
+        name: String,
+        /// Save the user's avatar to a file
+        #[structopt(short = "a", long = "avatar")]
+        avatar: Option<PathBuf>,
+    },
+    /// Show a user's connection statistics
+    Stats { name: String },
 }
 
 #[derive(Debug, StructOpt)]
@@ -165,6 +274,10 @@ enum Channel {
     Connect {
         name: String,
     },
+    /// Show a channel's description
+    Info {
+        name: String,
+    },
 }
 
 #[derive(Debug, StructOpt)]
@@ -251,6 +364,7 @@ fn main() {
 }
 fn match_opt() -> Result<(), Error> {
     let mut config = config::read_cfg(&config::default_cfg_path())?;
+    let original_config = config.clone();
 
     let opt = Mum::from_args();
     match opt.command {
@@ -339,14 +453,68 @@ fn match_opt() -> Result<(), Error> {
                         channel_identifier: name,
                     })??;
                 }
+                Channel::Info { name } => {
+                    match send_command(MumCommand::ChannelInfo {
+                        channel_identifier: name,
+                    })?? {
+                        Some(CommandResponse::ChannelInfo { channel }) => {
+                            println!("{}", channel.name.bold());
+                            if let Some(description) = channel.description {
+                                println!("{}", description);
+                            }
+                        }
+                        _ => unreachable!("Response should only be a ChannelInfo"),
+                    }
+                }
             }
         }
         Command::Status => match send_command(MumCommand::Status)?? {
-            Some(CommandResponse::Status { server_state }) => {
+            Some(CommandResponse::Status { server_state, .. }) => {
                 parse_state(&server_state);
             }
             _ => unreachable!("Response should only be a Status"),
         },
+        Command::Stats { watch } => loop {
+            match send_command(MumCommand::Status)?? {
+                Some(CommandResponse::Status {
+                    connection_stats, ..
+                }) => {
+                    if watch {
+                        // Clear the screen and move the cursor to the top left.
+                        print!("\x1B[2J\x1B[1;1H");
+                    }
+                    print_connection_stats(&connection_stats);
+                }
+                _ => unreachable!("Response should only be a Status"),
+            }
+            if !watch {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_secs(1));
+        },
+        Command::Audio(audio) => match audio {
+            Audio::Meter => {
+                for response in send_command_multi(MumCommand::InputLevel { block: true })? {
+                    match response {
+                        Ok(Some(CommandResponse::InputLevel {
+                            level,
+                            open,
+                            open_threshold,
+                            close_threshold,
+                        })) => print_input_level(level, open, open_threshold, close_threshold)?,
+                        Ok(_) => unreachable!("Response should only be a Some(InputLevel)"),
+                        Err(e) => error!("{}", e),
+                    }
+                }
+            }
+            Audio::Test { delay } => {
+                // The test stops when the connection to mumd is closed, so
+                // it's also stopped if mumctl is killed.
+                let _responses = send_command_multi(MumCommand::AudioLoopback { delay_ms: delay })?;
+                println!("Playing your audio back to you. Press enter to stop.");
+                io::stdin().lock().lines().next();
+            }
+        },
         Command::Config { key, value } => match key.as_str() {
             "audio.input_volume" => {
                 if let Ok(volume) = value.parse() {
@@ -387,11 +555,17 @@ fn match_opt() -> Result<(), Error> {
             if let Some(volume) = volume {
                 send_command(MumCommand::UserVolumeSet(user, volume))??;
             } else {
-                //TODO report volume of user
-                //     needs work on mumd
-                warn!("Currently unimplemented");
+                match send_command(MumCommand::UserVolumeGet(user))?? {
+                    Some(CommandResponse::UserVolume { volume }) => {
+                        println!("{}", volume);
+                    }
+                    _ => unreachable!("Response should only be a UserVolume"),
+                }
             }
         }
+        Command::Pan { user, pan } => {
+            send_command(MumCommand::UserPanSet(user, pan))??;
+        }
         Command::Mute { user } => match user {
             Some(user) => {
                 send_command(MumCommand::MuteOther(user, Some(true)))??;
@@ -414,11 +588,38 @@ fn match_opt() -> Result<(), Error> {
         Command::Undeafen => {
             send_command(MumCommand::DeafenSelf(Some(false)))??;
         }
+        Command::Dnd { state } => {
+            let toggle = state.map(|state| matches!(state, Dnd::On));
+            match send_command(MumCommand::DoNotDisturb(toggle))?? {
+                Some(CommandResponse::DoNotDisturbStatus { enabled }) => {
+                    println!("Do not disturb is {}", if enabled { "on" } else { "off" });
+                }
+                _ => unreachable!("Response should only be a DoNotDisturbStatus"),
+            }
+        }
         Command::Messages { follow } => {
             for response in send_command_multi(MumCommand::PastMessages { block: follow })? {
                 match response {
                     Ok(Some(CommandResponse::PastMessage { message })) => {
-                        println!("[{}] {}: {}", message.0.format("%d %b %H:%M"), message.2, message.1)
+                        let mut targets = Vec::new();
+                        if !message.channels.is_empty() {
+                            targets.push(format!("in {}", message.channels.join(", ")));
+                        }
+                        if !message.trees.is_empty() {
+                            targets.push(format!("in {} and subchannels", message.trees.join(", ")));
+                        }
+                        let to = if targets.is_empty() {
+                            "privately".to_string()
+                        } else {
+                            targets.join(" and ")
+                        };
+                        println!(
+                            "[{}] {} ({}): {}",
+                            message.timestamp.format("%d %b %H:%M"),
+                            message.sender,
+                            to,
+                            message.message
+                        )
                     }
                     Ok(_) => unreachable!("Response should only be a Some(PastMessages)"),
                     Err(e) => error!("{}", e),
@@ -465,6 +666,87 @@ fn match_opt() -> Result<(), Error> {
                 }
             }
         }
+        Command::Talking { follow } => {
+            for response in send_command_multi(MumCommand::Talking { block: follow })? {
+                match response {
+                    Ok(Some(CommandResponse::Talking {
+                        users,
+                        transmitting,
+                    })) => {
+                        if transmitting {
+                            print!("[transmitting] ");
+                        }
+                        println!("{}", users.join(", "));
+                    }
+                    Ok(_) => unreachable!("Response should only be a Some(Talking)"),
+                    Err(e) => error!("{}", e),
+                }
+            }
+        }
+        Command::Comment(comment) => match comment {
+            Comment::Set { comment } => {
+                send_command(MumCommand::SetComment(comment))??;
+            }
+            Comment::Clear => {
+                send_command(MumCommand::SetComment(String::new()))??;
+            }
+        },
+        Command::Avatar(avatar) => match avatar {
+            Avatar::Set { path } => {
+                // mumd doesn't necessarily share our working directory.
+                let path = std::fs::canonicalize(path)?;
+                send_command(MumCommand::SetAvatar(Some(path)))??;
+            }
+            Avatar::Clear => {
+                send_command(MumCommand::SetAvatar(None))??;
+            }
+        },
+        Command::Position(position) => match position {
+            Position::Set {
+                x,
+                y,
+                z,
+                front,
+                top,
+                context,
+                identity,
+            } => {
+                send_command(MumCommand::SetPosition {
+                    pos: [x, y, z],
+                    front: [front[0], front[1], front[2]],
+                    top: [top[0], top[1], top[2]],
+                    context,
+                    identity,
+                })??;
+            }
+            Position::Clear => {
+                send_command(MumCommand::ClearPosition)??;
+            }
+        },
+        Command::User(user) => match user {
+            User::Info { name, avatar } => {
+                match send_command(MumCommand::UserInfo { user: name })?? {
+                    Some(CommandResponse::UserInfo { user, texture }) => {
+                        println!("{}", user.to_string().bold());
+                        if let Some(comment) = user.comment {
+                            println!("{}", comment);
+                        }
+                        match (texture, avatar) {
+                            (Some(texture), Some(path)) => std::fs::write(path, texture)?,
+                            (Some(texture), None) => println!("Avatar: {} bytes", texture.len()),
+                            (None, _) => println!("No avatar"),
+                        }
+                    }
+                    _ => unreachable!("Response should only be a UserInfo"),
+                }
+            }
+            User::Stats { name } => {
+                match send_command(MumCommand::UserStats { user: name })?? {
+                    Some(CommandResponse::UserStats { stats }) => print_user_stats(&stats),
+                    _ => unreachable!("Response should only be a UserStats"),
+                }
+            }
+        },
     }
 
     let config_path = config::default_cfg_path();
@@ -478,7 +760,9 @@ fn match_opt() -> Result<(), Error> {
         if let Some(Ok(true)) = response.map(|e| e.map(|e| &e == "Y")) {
             config.write(&config_path, true)?;
         }
-    } else {
+    } else if config != original_config {
+        // mumd might have changed the config meanwhile, so it's only written
+        // if we changed something.
         config.write(&config_path, false)?;
     }
     Ok(())
@@ -509,7 +793,7 @@ fn match_server_command(server_command: Server, config: &mut Config) -> Result<(
             match (key.as_deref(), value) {
                 (None, _) => {
                     print!(
-                        "{}{}{}{}{}",
+                        "{}{}{}{}{}{}{}",
                         format!("host: {}\n", server.host.to_string()),
                         server
                             .port
@@ -529,6 +813,16 @@ fn match_server_command(server_command: Server, config: &mut Config) -> Result<(
                             .accept_invalid_cert
                             .map(|b| format!("accept_invalid_cert: {}\n", if b { "true" } else { "false" }))
                             .unwrap_or_else(|| "".to_string()),
+                        server
+                            .comment
+                            .as_ref()
+                            .map(|s| format!("comment: {}\n", s))
+                            .unwrap_or_else(|| "".to_string()),
+                        server
+                            .avatar
+                            .as_ref()
+                            .map(|s| format!("avatar: {}\n", s))
+                            .unwrap_or_else(|| "".to_string()),
                     );
                 }
                 (Some("name"), None) => {
@@ -570,6 +864,24 @@ fn match_server_command(server_command: Server, config: &mut Config) -> Result<(
                             .ok_or_else(|| CliError::NotSet("accept_invalid_cert".to_string()))?
                     );
                 }
+                (Some("comment"), None) => {
+                    println!(
+                        "{}",
+                        server
+                            .comment
+                            .as_ref()
+                            .ok_or_else(|| CliError::NotSet("comment".to_string()))?
+                    );
+                }
+                (Some("avatar"), None) => {
+                    println!(
+                        "{}",
+                        server
+                            .avatar
+                            .as_ref()
+                            .ok_or_else(|| CliError::NotSet("avatar".to_string()))?
+                    );
+                }
                 (Some("name"), Some(_)) => {
                     return Err(CliError::UseServerRename.into());
                 }
@@ -592,6 +904,13 @@ fn match_server_command(server_command: Server, config: &mut Config) -> Result<(
                         Err(e) => warn!("{}", e)
                     }
                 }
+                (Some("comment"), Some(value)) => {
+                    server.comment = Some(value);
+                }
+                (Some("avatar"), Some(value)) => {
+                    let path = std::fs::canonicalize(value)?;
+                    server.avatar = Some(path.display().to_string());
+                }
                 (Some(_), _) => {
                     return Err(CliError::ConfigKeyNotFound(key.unwrap()).into());
                 }
@@ -622,6 +941,8 @@ fn match_server_command(server_command: Server, config: &mut Config) -> Result<(
                     username,
                     password,
                     accept_invalid_cert: None,
+                    comment: None,
+                    avatar: None,
                 });
             }
         }
@@ -793,6 +1114,130 @@ where
     }
 }
 
+fn print_connection_stats(stats: &ConnectionStats) {
+    println!(
+        "Voice: {}",
+        if stats.udp {
+            "UDP"
+        } else {
+            "tunneled through TCP"
+        }
+    );
+    println!(
+        "TCP: {} pings, ping {:.1} ms (variance {:.1})",
+        stats.tcp_packets, stats.tcp_ping.0, stats.tcp_ping.1
+    );
+    println!(
+        "UDP: {} pings, ping {:.1} ms (variance {:.1})",
+        stats.udp_packets, stats.udp_ping.0, stats.udp_ping.1
+    );
+    print_packet_stats(&stats.from_client, &stats.from_server);
+}
+
+/// Draws a level meter on the current line. The open and close thresholds are
+/// marked with `|` and `:` respectively.
+fn print_input_level(
+    level: f32,
+    open: bool,
+    open_threshold: Option<f32>,
+    close_threshold: Option<f32>,
+) -> Result<(), io::Error> {
+    const WIDTH: usize = 60;
+    const FLOOR: f32 = -60.0;
+    let position = |db: f32| ((1.0 - db.clamp(FLOOR, 0.0) / FLOOR) * WIDTH as f32) as usize;
+
+    let filled = position(level);
+    let meter: String = (0..WIDTH)
+        .map(|i| {
+            if open_threshold.map(position) == Some(i) {
+                '|'
+            } else if close_threshold.map(position) == Some(i) {
+                ':'
+            } else if i < filled {
+                '#'
+            } else {
+                ' '
+            }
+        })
+        .collect();
+    let meter = if open { meter.green() } else { meter.normal() };
+
+    let mut stdout = io::stdout();
+    write!(
+        stdout,
+        "\r[{}] {:>6.1} dBFS {}",
+        meter,
+        level.max(FLOOR),
+        if open { "open  " } else { "closed" },
+    )?;
+    stdout.flush()
+}
+
+fn print_packet_stats(from_client: &PacketStats, from_server: &PacketStats) {
+    println!("{:12}{:>8}{:>8}{:>8}{:>8}", "", "Good", "Late", "Lost", "Resync");
+    for (direction, packets) in [("To server", from_client), ("From server", from_server)].iter() {
+        println!(
+            "{:12}{:>8}{:>8}{:>8}{:>8}",
+            direction, packets.good, packets.late, packets.lost, packets.resync
+        );
+    }
+}
+
+fn print_user_stats(stats: &UserStats) {
+    fn duration(secs: u32) -> String {
+        match secs {
+            0..=59 => format!("{}s", secs),
+            60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+            _ => format!("{}h {}m", secs / 3600, secs / 60 % 60),
+        }
+    }
+
+    println!("{}", stats.name.bold());
+    if let Some((major, minor, patch)) = stats.version {
+        print!("Version: {}.{}.{}", major, minor, patch);
+        if let Some(release) = &stats.release {
+            print!(" ({})", release);
+        }
+        if let Some(os) = &stats.os {
+            print!(" on {} {}", os, stats.os_version.as_deref().unwrap_or(""));
+        }
+        println!("{}", if stats.opus { "" } else { ", no Opus support" });
+    }
+    if let Some(address) = stats.address {
+        println!("Address: {}", address);
+    }
+    if let Some(online) = stats.online_secs {
+        print!("Online: {}", duration(online));
+        if let Some(idle) = stats.idle_secs {
+            print!(", idle {}", duration(idle));
+        }
+        println!();
+    }
+    println!(
+        "Certificate: {}",
+        match (stats.certificates, stats.strong_certificate) {
+            (0, _) => "none".to_string(),
+            (n, strong) => format!(
+                "{} in chain, {}",
+                n,
+                if strong { "strong" } else { "self-signed" }
+            ),
+        }
+    );
+    if let Some(bandwidth) = stats.bandwidth {
+        println!("Bandwidth: {:.1} kbit/s", bandwidth as f32 / 1000.0);
+    }
+    println!(
+        "TCP: {} packets, ping {:.1} ms (variance {:.1})",
+        stats.tcp_packets, stats.tcp_ping.0, stats.tcp_ping.1
+    );
+    println!(
+        "UDP: {} packets, ping {:.1} ms (variance {:.1})",
+        stats.udp_packets, stats.udp_ping.0, stats.udp_ping.1
+    );
+    print_packet_stats(&stats.from_client, &stats.from_server);
+}
+
 fn print_channel(channel: &MumChannel, depth: usize) {
     println!(
         "{}{}{}",
@@ -20,7 +20,6 @@ async fn main() {
     }
 
     setup_logger(std::io::stderr(), true);
-    mum::notifications::init();
 
     // check if another instance is live
     let connection = UnixStream::connect(mumlib::SOCKET_PATH).await;
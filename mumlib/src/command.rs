@@ -1,11 +1,13 @@
 //! [Command]s can be sent from a controller to mumd which might respond with a
 //! [CommandResponse].
 
-use crate::state::{Channel, Server};
+use crate::state::{Channel, ConnectionStats, Server, User, UserStats};
 
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::fmt;
+use std::path::PathBuf;
 
 /// Something that happened in our channel at a point in time.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,39 +33,117 @@ pub enum MumbleEventKind {
     UserDisconnected(String, String),
     /// A user {un,}{muted,deafened}. Contains a rendered message with what changed and the user.
     UserMuteStateChanged(String),  // This logic is kinda weird so we only store the rendered message.
-    /// A text message was received. Contains who sent the message.
-    TextMessageReceived(String),
+    /// A text message was received.
+    TextMessageReceived(ReceivedMessage),
     /// A user switched to our channel from some other channel. Contains `(user, previous-channel)`.
     UserJoinedChannel(String, String),
     /// A user switched from our channel to some other channel. Contains `(user, new-channel)`.
     UserLeftChannel(String, String),
+    /// We started hearing a user talk. Contains the user.
+    UserStartedTalking(String),
+    /// We stopped hearing a user talk. Contains the user.
+    UserStoppedTalking(String),
+    /// The input was moved to another device, e.g. since the previous one was
+    /// unplugged. Contains the name of the new device.
+    InputDeviceChanged(String),
+    /// The output was moved to another device, e.g. since the previous one was
+    /// unplugged. Contains the name of the new device.
+    OutputDeviceChanged(String),
 }
 
-//TODO These strings are (mostly) duplicated with their respective notifications.
-//     The only difference is that the text message event doesn't contain the text message.
-impl fmt::Display for MumbleEventKind {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl MumbleEventKind {
+    /// The name of this kind of event, as used in the config.
+    pub fn name(&self) -> &'static str {
         match self {
-            MumbleEventKind::UserConnected(user, channel) => {
-                write!(f, "{} connected to {}", user, channel)
-            }
-            MumbleEventKind::UserDisconnected(user, channel) => {
-                write!(f, "{} disconnected from {}", user, channel)
-            }
-            MumbleEventKind::UserMuteStateChanged(message) => {
-                write!(f, "{}", message)
-            }
-            MumbleEventKind::TextMessageReceived(user) => {
-                write!(f, "{} sent a text message", user)
-            }
-            MumbleEventKind::UserJoinedChannel(name, from) => {
-                write!(f, "{} moved to your channel from {}", name, from)
-            }
-            MumbleEventKind::UserLeftChannel(name, to) => {
-                write!(f, "{} moved to {}", name, to)
+            MumbleEventKind::UserConnected(..) => "user_connected",
+            MumbleEventKind::UserDisconnected(..) => "user_disconnected",
+            MumbleEventKind::UserMuteStateChanged(..) => "user_mute_state_changed",
+            MumbleEventKind::TextMessageReceived(..) => "text_message_received",
+            MumbleEventKind::UserJoinedChannel(..) => "user_joined_channel",
+            MumbleEventKind::UserLeftChannel(..) => "user_left_channel",
+            MumbleEventKind::UserStartedTalking(..) => "user_started_talking",
+            MumbleEventKind::UserStoppedTalking(..) => "user_stopped_talking",
+            MumbleEventKind::InputDeviceChanged(..) => "input_device_changed",
+            MumbleEventKind::OutputDeviceChanged(..) => "output_device_changed",
+        }
+    }
+
+    /// The template this kind of event is displayed with unless another one
+    /// is configured. See [MumbleEventKind::render].
+    pub fn default_template(&self) -> &'static str {
+        match self {
+            MumbleEventKind::UserConnected(..) => "{user} connected to {channel}",
+            MumbleEventKind::UserDisconnected(..) => "{user} disconnected from {channel}",
+            MumbleEventKind::UserMuteStateChanged(..) => "{message}",
+            MumbleEventKind::TextMessageReceived(..) => "{user}: {message}",
+            MumbleEventKind::UserJoinedChannel(..) => "{user} moved to your channel from {channel}",
+            MumbleEventKind::UserLeftChannel(..) => "{user} moved to {channel}",
+            MumbleEventKind::UserStartedTalking(..) => "{user} started talking",
+            MumbleEventKind::UserStoppedTalking(..) => "{user} stopped talking",
+            MumbleEventKind::InputDeviceChanged(..) => "Recording from {device}",
+            MumbleEventKind::OutputDeviceChanged(..) => "Playing on {device}",
+        }
+    }
+
+    /// The values that can be placed in a template, as `(placeholder, value)`.
+    pub fn placeholders(&self) -> Vec<(&'static str, Cow<'_, str>)> {
+        match self {
+            MumbleEventKind::UserConnected(user, channel)
+            | MumbleEventKind::UserDisconnected(user, channel)
+            | MumbleEventKind::UserJoinedChannel(user, channel)
+            | MumbleEventKind::UserLeftChannel(user, channel) => {
+                vec![("user", user.into()), ("channel", channel.into())]
             }
+            MumbleEventKind::UserMuteStateChanged(message) => vec![("message", message.into())],
+            MumbleEventKind::TextMessageReceived(message) => vec![
+                ("user", (&message.sender).into()),
+                ("session", message.sender_session.to_string().into()),
+                ("message", (&message.message).into()),
+                ("kind", message.kind.name().into()),
+                ("channel", message.channels.join(", ").into()),
+                ("tree", message.trees.join(", ").into()),
+            ],
+            MumbleEventKind::UserStartedTalking(user)
+            | MumbleEventKind::UserStoppedTalking(user) => vec![("user", user.into())],
+            MumbleEventKind::InputDeviceChanged(device)
+            | MumbleEventKind::OutputDeviceChanged(device) => vec![("device", device.into())],
+        }
+    }
 
+    /// Renders this event with a template, where each `{placeholder}` is
+    /// replaced by its value. Unknown placeholders are kept as they are.
+    pub fn render(&self, template: &str) -> String {
+        let placeholders = self.placeholders();
+        let mut rendered = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            rendered.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let value = rest.find('}').and_then(|end| {
+                placeholders
+                    .iter()
+                    .find(|(placeholder, _)| *placeholder == &rest[1..end])
+                    .map(|(_, value)| (end, value))
+            });
+            match value {
+                Some((end, value)) => {
+                    rendered.push_str(value);
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    rendered.push('{');
+                    rest = &rest[1..];
+                }
+            }
         }
+        rendered.push_str(rest);
+        rendered
+    }
+}
+
+impl fmt::Display for MumbleEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.render(self.default_template()))
     }
 }
 
@@ -71,6 +151,21 @@ impl fmt::Display for MumbleEventKind {
 /// all commands receive a response.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum Command {
+    /// Play our own audio back to us after delay_ms milliseconds instead of
+    /// sending it to the server, until the connection the command was sent on
+    /// is closed. Works without being connected. Response: an empty response
+    /// every second, until the connection is closed.
+    AudioLoopback {
+        delay_ms: u64,
+    },
+
+    /// Request information about a channel. The channel description is
+    /// requested from the server if it's too large to have been sent
+    /// directly. Response: [CommandResponse::ChannelInfo].
+    ChannelInfo {
+        channel_identifier: String,
+    },
+
     /// No response.
     ChannelJoin {
         channel_identifier: String,
@@ -79,13 +174,25 @@ pub enum Command {
     /// Response: [CommandResponse::ChannelList].
     ChannelList,
 
+    /// Stop sending our position. No response.
+    ClearPosition,
+
     /// Force reloading of config file from disk. No response.
     ConfigReload,
     /// Response: [CommandResponse::DeafenStatus]. Toggles if None.
     DeafenSelf(Option<bool>),
+    /// Stop or start sending desktop notifications. Notifications that were
+    /// held back are sent when it's turned off. Works without being connected.
+    /// Response: [CommandResponse::DoNotDisturbStatus]. Toggles if None.
+    DoNotDisturb(Option<bool>),
     Events {
         block: bool
     },
+    /// Response: [CommandResponse::InputLevel]. If block is true, a new
+    /// response is sent continuously until the connection is closed.
+    InputLevel {
+        block: bool,
+    },
     /// Set the outgoing audio volume (i.e. from you to the server). No response.
     InputVolumeSet(f32),
 
@@ -141,9 +248,60 @@ pub enum Command {
         port: u16,
     },
 
+    /// Set our own avatar from a PNG or JPEG file. The avatar is cleared if None.
+    /// No response.
+    SetAvatar(Option<PathBuf>),
+
+    /// Set our own comment. An empty comment clears it. No response.
+    SetComment(String),
+
+    /// Set our position, which is sent with our audio and used to render
+    /// other users' audio. Meant to be sent continuously by e.g. a game.
+    /// Positional audio is only heard between users with the same context,
+    /// which should identify the game and the server being played on. The
+    /// identity identifies us within the game. No response.
+    SetPosition {
+        /// Our position in meters.
+        pos: [f32; 3],
+        /// The direction we're facing.
+        front: [f32; 3],
+        /// The direction of the top of our head.
+        top: [f32; 3],
+        context: String,
+        identity: String,
+    },
+
     /// Request the status of the current server. Response: [CommandResponse::Status].
     Status,
 
+    /// Request who is talking. Response: [CommandResponse::Talking]. If block
+    /// is true, a new response is sent every time it changes until the
+    /// connection is closed.
+    Talking {
+        block: bool,
+    },
+
+    /// Request information about a user. Their comment and avatar are
+    /// requested from the server if they're too large to have been sent
+    /// directly. Response: [CommandResponse::UserInfo].
+    UserInfo {
+        user: String,
+    },
+
+    /// Request connection statistics for a user from the server.
+    /// Response: [CommandResponse::UserStats].
+    UserStats {
+        user: String,
+    },
+
+    /// Place the specified user in the stereo field, from -1 (left) to 1
+    /// (right). No response.
+    UserPanSet(String, f32),
+
+    /// Request the volume of the specified user. Response:
+    /// [CommandResponse::UserVolume].
+    UserVolumeGet(String),
+
     /// The the volume of the specified user. No response.
     UserVolumeSet(String, f32),
 }
@@ -151,6 +309,10 @@ pub enum Command {
 /// A response to a sent [Command].
 #[derive(Debug, Deserialize, Serialize)]
 pub enum CommandResponse {
+    ChannelInfo {
+        channel: Channel,
+    },
+
     ChannelList {
         channels: Channel,
     },
@@ -159,16 +321,30 @@ pub enum CommandResponse {
         is_deafened: bool,
     },
 
+    DoNotDisturbStatus {
+        enabled: bool,
+    },
+
     Event {
         event: MumbleEvent,
     },
 
+    InputLevel {
+        /// The peak level of the microphone in dBFS.
+        level: f32,
+        /// Whether the audio is currently being sent.
+        open: bool,
+        /// The thresholds of the noise gate in dBFS, if there is one.
+        open_threshold: Option<f32>,
+        close_threshold: Option<f32>,
+    },
+
     MuteStatus {
         is_muted: bool,
     },
 
     PastMessage {
-        message: (NaiveDateTime, String, String),
+        message: ReceivedMessage,
     },
 
     Pong,
@@ -187,6 +363,28 @@ pub enum CommandResponse {
 
     Status {
         server_state: Server,
+        connection_stats: ConnectionStats,
+    },
+
+    Talking {
+        /// The names of the users we hear talking.
+        users: Vec<String>,
+        /// Whether we're sending audio.
+        transmitting: bool,
+    },
+
+    UserInfo {
+        user: User,
+        /// The user's avatar as a PNG or JPEG image, if set.
+        texture: Option<Vec<u8>>,
+    },
+
+    UserStats {
+        stats: UserStats,
+    },
+
+    UserVolume {
+        volume: f32,
     },
 }
 
@@ -204,3 +402,78 @@ pub enum MessageTarget {
     Channel(Vec<(ChannelTarget, bool)>),  // (target, recursive)
     User(Vec<String>),
 }
+
+/// A text message that we received.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ReceivedMessage {
+    /// When the message was received.
+    pub timestamp: NaiveDateTime,
+    /// The name of the user that sent the message.
+    pub sender: String,
+    /// The session of the user that sent the message.
+    pub sender_session: u32,
+    /// The text of the message.
+    pub message: String,
+    /// Who the message was sent to.
+    pub kind: MessageKind,
+    /// The names of the channels the message was sent to, not including
+    /// their subchannels.
+    pub channels: Vec<String>,
+    /// The names of the channels the message was sent to together with all of
+    /// their subchannels.
+    pub trees: Vec<String>,
+}
+
+/// Who a received message was sent to.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum MessageKind {
+    /// Sent directly to us, and possibly other users.
+    Private,
+    /// Sent to channels.
+    Channel,
+    /// Sent to channels and all of their subchannels, and possibly to other
+    /// channels.
+    Tree,
+}
+
+impl MessageKind {
+    /// The name of this kind of message, e.g. "private".
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageKind::Private => "private",
+            MessageKind::Channel => "channel",
+            MessageKind::Tree => "tree",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn connected(user: &str) -> MumbleEventKind {
+        MumbleEventKind::UserConnected(user.to_string(), "Lobby".to_string())
+    }
+
+    #[test]
+    fn render_placeholders() {
+        assert_eq!(connected("alice").render("{user} joined {channel}"), "alice joined Lobby");
+        assert_eq!(connected("alice").render("{user}{user}"), "alicealice");
+        assert_eq!(connected("alice").render("no placeholders"), "no placeholders");
+    }
+
+    #[test]
+    fn render_keeps_unknown_placeholders_and_braces() {
+        assert_eq!(connected("alice").render("{device}: {user}"), "{device}: alice");
+        assert_eq!(connected("alice").render("{ {user} }"), "{ alice }");
+        assert_eq!(connected("alice").render("{{user}}"), "{alice}");
+        assert_eq!(connected("alice").render("{user"), "{user");
+        assert_eq!(connected("alice").render("}{"), "}{");
+    }
+
+    #[test]
+    fn render_doesnt_expand_values() {
+        assert_eq!(connected("{channel}").render("{user} in {channel}"), "{channel} in Lobby");
+        assert_eq!(connected("{").render("{user}{channel}"), "{Lobby");
+    }
+}
@@ -22,17 +22,26 @@ struct TOMLConfig {
 
     // Tables
     audio: Option<AudioConfig>,
+    hooks: Option<Array>,
+    notifications: Option<NotificationsConfig>,
     servers: Option<Array>,
+    users: Option<Array>,
 }
 
 /// Our representation of the mumdrc config file.
 // Deserialized via [TOMLConfig].
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Config {
     /// General audio configuration.
     pub audio: AudioConfig,
+    /// Commands that are run when events happen.
+    pub hooks: Vec<HookConfig>,
+    /// When and how desktop notifications are sent.
+    pub notifications: NotificationsConfig,
     /// Saved servers.
     pub servers: Vec<ServerConfig>,
+    /// Local settings for other users.
+    pub users: Vec<UserConfig>,
     /// Whether we allow connecting to servers with invalid server certificates.
     ///
     /// None implies false but we can show a better message to the user.
@@ -71,27 +80,250 @@ impl Config {
 }
 
 /// Overwrite a specific sound effect with a file that should be played instead.
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct SoundEffect {
     /// During which event the effect should be played.
     pub event: String,
-    /// The file that should be played.
-    pub file: String,
+    /// The file that should be played, or "none" to not play anything. The
+    /// default sound effect is played if unset.
+    pub file: Option<String>,
+    /// The volume of the effect, where 1.0 is unchanged.
+    pub volume: Option<f32>,
+}
+
+/// When and how desktop notifications are sent. Unset values use a default
+/// value.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct NotificationsConfig {
+    /// Whether notifications are sent at all.
+    pub enabled: Option<bool>,
+    /// How notifications are shown.
+    pub backend: Option<NotificationBackendKind>,
+    /// The command that is run for each notification when using the command
+    /// backend, as the program followed by its arguments.
+    pub command: Option<Vec<String>>,
+    /// The urgency of notifications.
+    pub urgency: Option<NotificationUrgency>,
+    /// How long notifications are shown, in milliseconds.
+    pub timeout: Option<u32>,
+    /// Overriden settings for specific kinds of events.
+    pub events: Option<Vec<NotificationEventConfig>>,
+}
+
+/// Overwrite how notifications are sent for a specific kind of event.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct NotificationEventConfig {
+    /// The kind of event, e.g. "text_message_received".
+    pub event: String,
+    /// Whether notifications are sent for the event.
+    pub enabled: Option<bool>,
+    /// What the notification says, with placeholders like {user} replaced by
+    /// their values.
+    pub template: Option<String>,
+    /// The urgency of the notification.
+    pub urgency: Option<NotificationUrgency>,
+    /// How long the notification is shown, in milliseconds.
+    pub timeout: Option<u32>,
+}
+
+/// How notifications are shown.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationBackendKind {
+    /// Through libnotify, if mumd is built with the notifications feature.
+    Libnotify,
+    /// Directly over D-Bus, if mumd is built with the dbus feature.
+    Dbus,
+    /// By running a command.
+    Command,
+    /// By printing them to standard output.
+    Stdout,
+}
+
+/// How urgent a notification is.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationUrgency {
+    Low,
+    Normal,
+    Critical,
+}
+
+/// How the audio of users that send their position is rendered. Unset values
+/// use a default value.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct PositionalConfig {
+    /// Whether to pan and attenuate users by their position.
+    pub enabled: Option<bool>,
+    /// The distance in meters within which users are played at full volume.
+    pub min_distance: Option<f32>,
+    /// The distance in meters at which users are played at the minimum volume.
+    pub max_distance: Option<f32>,
+    /// The volume of users that are far away, between 0 and 1.
+    pub min_volume: Option<f32>,
+}
+
+/// How the mixed output audio is kept from clipping. Unset values use a
+/// default value.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct LimiterConfig {
+    /// Whether to limit the output. Loud audio is clipped otherwise.
+    pub enabled: Option<bool>,
+    /// The maximum level in dBFS.
+    pub threshold: Option<f32>,
+    /// How far ahead the limiter looks, in milliseconds.
+    pub lookahead: Option<f32>,
+    /// How fast the limiter recovers, in milliseconds.
+    pub release: Option<f32>,
+}
+
+/// How other users are made quieter while some users are talking. Unset
+/// values use a default value.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct DuckingConfig {
+    /// How much quieter other users are made, in dB.
+    pub amount: Option<f32>,
+    /// How long in milliseconds other users stay quieter after the talking
+    /// stopped.
+    pub hold: Option<f32>,
+    /// Whether priority speakers make other users quieter.
+    pub priority_speakers: Option<bool>,
+    /// The names of users that make other users quieter.
+    pub users: Option<Vec<String>>,
+    /// Whether sound effects make users quieter.
+    pub sound_effects: Option<bool>,
+}
+
+/// A step in the processing of microphone audio before it's sent to the
+/// server. Unset parameters use a default value.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TransformerConfig {
+    /// Mutes the audio while it's quiet.
+    NoiseGate {
+        /// The level in dBFS above which the gate opens.
+        open_threshold: Option<f32>,
+        /// The level in dBFS below which the gate closes.
+        close_threshold: Option<f32>,
+        /// How long to wait in milliseconds before closing the gate.
+        hold: Option<f32>,
+        /// How far in dB below the recent peak level the thresholds are
+        /// raised. 0 disables this.
+        range: Option<f32>,
+        /// How fast the recent peak level decays, in dB per second.
+        decay: Option<f32>,
+    },
+    /// Automatic gain control. Evens out the volume by adjusting the gain
+    /// towards a target level.
+    Agc {
+        /// The target level in dBFS.
+        target: Option<f32>,
+        /// The maximum gain in dB.
+        max_gain: Option<f32>,
+    },
+    /// Reduces the dynamic range of audio above a threshold.
+    Compressor {
+        /// The level in dBFS above which audio is compressed.
+        threshold: Option<f32>,
+        /// How much audio above the threshold is reduced, e.g. 4 for 4:1.
+        ratio: Option<f32>,
+        /// How fast the compressor reacts to loud audio, in milliseconds.
+        attack: Option<f32>,
+        /// How fast the compressor recovers, in milliseconds.
+        release: Option<f32>,
+        /// Gain applied after compression, in dB.
+        makeup_gain: Option<f32>,
+    },
+    /// Keeps the audio from going above a threshold. A compressor with an
+    /// infinite ratio.
+    Limiter {
+        /// The maximum level in dBFS.
+        threshold: Option<f32>,
+        /// How fast the limiter recovers, in milliseconds.
+        release: Option<f32>,
+    },
+    /// Removes low-frequency rumble, e.g. from desks and keyboards.
+    HighPass {
+        /// The cutoff frequency in Hz.
+        cutoff: Option<f32>,
+    },
+    /// Reduces harsh "s" and "sh" sounds.
+    DeEsser {
+        /// The frequency in Hz above which sibilance is detected and reduced.
+        frequency: Option<f32>,
+        /// The level in dBFS of sibilance above which it's reduced.
+        threshold: Option<f32>,
+        /// The maximum reduction in dB.
+        max_reduction: Option<f32>,
+    },
+    /// Removes stationary background noise like fans and air conditioning.
+    NoiseSuppressor {
+        /// The maximum reduction of noise in dB.
+        reduction: Option<f32>,
+        /// How much the estimated noise is scaled before it's removed.
+        over_subtraction: Option<f32>,
+        /// The time in seconds it takes the noise estimate to rise by 10 dB.
+        adaptation: Option<f32>,
+    },
+    /// Removes echo of the audio that is played.
+    EchoCanceller {
+        /// The length in milliseconds of the echo that can be removed.
+        filter_length: Option<f32>,
+        /// The largest delay in milliseconds between audio being played and
+        /// recorded that is detected.
+        max_delay: Option<f32>,
+    },
 }
 
 /// General audio configuration.
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct AudioConfig {
+    /// The name of the device to record from. The default device is used if
+    /// unset or if there's no such device.
+    pub input_device: Option<String>,
+    /// The name of the device to play on. The default device is used if unset
+    /// or if there's no such device.
+    pub output_device: Option<String>,
     /// The microphone input sensitivity.
     pub input_volume: Option<f32>,
     /// The output main gain.
     pub output_volume: Option<f32>,
+    /// Whether sound effects are played at all. Default true.
+    pub sound_effects_enabled: Option<bool>,
+    /// The bitrate of outgoing audio in bits per second.
+    pub bitrate: Option<u32>,
+    /// How many 10 ms frames are sent in each packet. One of 1, 2, 4 and 6.
+    pub frames_per_packet: Option<u32>,
+    /// Whether to use in-band forward error correction.
+    pub fec: Option<bool>,
+    /// The expected packet loss in percent, used to tune the forward error
+    /// correction.
+    pub packet_loss: Option<u8>,
+    /// Whether to use a variable bitrate.
+    pub vbr: Option<bool>,
+    /// The complexity of the encoder, from 0 to 10.
+    pub complexity: Option<u8>,
+    /// Whether to use discontinuous transmission, where fewer packets are sent
+    /// during silence.
+    pub dtx: Option<bool>,
+    // Arrays of tables have to come after all values, or the config can't be
+    // written.
     /// Overriden sound effects.
     pub sound_effects: Option<Vec<SoundEffect>>,
+    /// How microphone audio is processed, in order. Only a noise gate is used
+    /// if unset.
+    pub transformers: Option<Vec<TransformerConfig>>,
+    /// How positional audio is rendered.
+    pub positional: Option<PositionalConfig>,
+    /// How other users are made quieter while some users are talking. No
+    /// ducking is done if None.
+    pub ducking: Option<DuckingConfig>,
+    /// How the mixed output audio is kept from clipping.
+    pub limiter: Option<LimiterConfig>,
 }
 
 /// A saved server.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct ServerConfig {
     /// The alias of the server.
     pub name: String,
@@ -105,6 +337,10 @@ pub struct ServerConfig {
     pub password: Option<String>,
     /// Whether to accept invalid server certifications for this server.
     pub accept_invalid_cert: Option<bool>,
+    /// The comment to set when connected to this server.
+    pub comment: Option<String>,
+    /// Path to a PNG or JPEG file to use as avatar when connected to this server.
+    pub avatar: Option<String>,
 }
 
 impl ServerConfig {
@@ -120,6 +356,45 @@ impl ServerConfig {
     }
 }
 
+/// Local settings for another user, remembered across sessions.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct UserConfig {
+    /// The name of the user.
+    pub name: String,
+    /// The hash of the user's certificate, which identifies them even if they
+    /// change their name.
+    pub hash: Option<String>,
+    /// The volume the user is played at.
+    pub volume: Option<f32>,
+    /// Whether the user is muted by us.
+    pub mute: Option<bool>,
+    /// Where in the stereo field the user is placed, from -1 (left) to 1
+    /// (right).
+    pub pan: Option<f32>,
+}
+
+impl UserConfig {
+    /// Whether this is the config of a user. Users are matched by their
+    /// certificate hash if both have one, and by name otherwise.
+    pub fn matches(&self, name: &str, hash: Option<&str>) -> bool {
+        match (self.hash.as_deref(), hash) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.name == name,
+        }
+    }
+}
+
+/// A command that is run when an event happens.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct HookConfig {
+    /// The kind of event, e.g. "user_connected".
+    pub event: String,
+    /// The shell command to run.
+    pub command: String,
+    /// How long the command may run before it's killed, in milliseconds.
+    pub timeout: Option<u64>,
+}
+
 /// Finds the default path of the configuration file.
 /// 
 /// The user config dir is looked for first (cross-platform friendly) and
@@ -141,6 +416,17 @@ impl TryFrom<TOMLConfig> for Config {
     fn try_from(config: TOMLConfig) -> Result<Self, Self::Error> {
         Ok(Config {
             audio: config.audio.unwrap_or_default(),
+            hooks: config
+                .hooks
+                .map(|hooks| {
+                    hooks
+                        .into_iter()
+                        .map(|h| h.try_into::<HookConfig>())
+                        .collect()
+                })
+                .transpose()?
+                .unwrap_or_default(),
+            notifications: config.notifications.unwrap_or_default(),
             servers: config
                 .servers
                 .map(|servers| {
@@ -151,6 +437,16 @@ impl TryFrom<TOMLConfig> for Config {
                 })
                 .transpose()?
                 .unwrap_or_default(),
+            users: config
+                .users
+                .map(|users| {
+                    users
+                        .into_iter()
+                        .map(|u| u.try_into::<UserConfig>())
+                        .collect()
+                })
+                .transpose()?
+                .unwrap_or_default(),
             allow_invalid_server_cert: config.accept_all_invalid_certs,
         })
     }
@@ -159,19 +455,54 @@ impl TryFrom<TOMLConfig> for Config {
 impl From<Config> for TOMLConfig {
     fn from(config: Config) -> Self {
         TOMLConfig {
-            audio: if config.audio.output_volume.is_some() || config.audio.input_volume.is_some() {
+            audio: if config.audio != AudioConfig::default() {
                 Some(config.audio)
             } else {
                 None
             },
-            servers: Some(
-                config
-                    .servers
-                    .into_iter()
-                    // Safe since all ServerConfigs are valid TOML
-                    .map(|s| Value::try_from::<ServerConfig>(s).unwrap())
-                    .collect(),
-            ),
+            hooks: if config.hooks.is_empty() {
+                None
+            } else {
+                Some(
+                    config
+                        .hooks
+                        .into_iter()
+                        // Safe since all HookConfigs are valid TOML
+                        .map(|h| Value::try_from::<HookConfig>(h).unwrap())
+                        .collect(),
+                )
+            },
+            notifications: if config.notifications != NotificationsConfig::default() {
+                Some(config.notifications)
+            } else {
+                None
+            },
+            // Empty arrays are values, which can't be placed after the audio
+            // table.
+            servers: if config.servers.is_empty() {
+                None
+            } else {
+                Some(
+                    config
+                        .servers
+                        .into_iter()
+                        // Safe since all ServerConfigs are valid TOML
+                        .map(|s| Value::try_from::<ServerConfig>(s).unwrap())
+                        .collect(),
+                )
+            },
+            users: if config.users.is_empty() {
+                None
+            } else {
+                Some(
+                    config
+                        .users
+                        .into_iter()
+                        // Safe since all UserConfigs are valid TOML
+                        .map(|u| Value::try_from::<UserConfig>(u).unwrap())
+                        .collect(),
+                )
+            },
             accept_all_invalid_certs: config.allow_invalid_server_cert,
         }
     }
@@ -201,3 +532,61 @@ pub fn read_cfg(path: &Path) -> Result<Config, ConfigError> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn write_and_read_config() {
+        let config = Config {
+            audio: AudioConfig {
+                bitrate: Some(40000),
+                vbr: Some(false),
+                complexity: Some(5),
+                sound_effects: Some(vec![SoundEffect {
+                    event: "kicked".to_string(),
+                    file: None,
+                    volume: Some(0.5),
+                }]),
+                transformers: Some(vec![TransformerConfig::Limiter {
+                    threshold: Some(-3.0),
+                    release: None,
+                }]),
+                ..AudioConfig::default()
+            },
+            users: vec![UserConfig {
+                name: "user".to_string(),
+                hash: None,
+                volume: Some(0.5),
+                mute: None,
+                pan: Some(-1.0),
+            }],
+            ..Config::default()
+        };
+        let path = std::env::temp_dir().join(format!("mumdrc-test-{}", std::process::id()));
+        config.write(&path, true).unwrap();
+        let read = read_cfg(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read.unwrap(), config);
+    }
+
+    #[test]
+    fn user_config_matches_hash_before_name() {
+        let user = |name: &str, hash: Option<&str>| UserConfig {
+            name: name.to_string(),
+            hash: hash.map(str::to_string),
+            volume: None,
+            mute: None,
+            pan: None,
+        };
+        // Users are recognized by their certificate if both have one, even if
+        // they changed their name.
+        assert!(user("alice", Some("abc")).matches("alicia", Some("abc")));
+        assert!(!user("alice", Some("abc")).matches("alice", Some("def")));
+        // Otherwise by their name.
+        assert!(user("alice", None).matches("alice", Some("abc")));
+        assert!(user("alice", Some("abc")).matches("alice", None));
+        assert!(!user("alice", None).matches("bob", None));
+    }
+}
@@ -14,6 +14,9 @@ pub enum Error {
     Unimplemented,
     NotConnectedToChannel,
     ServerCertReject,
+    InvalidAvatar(String),
+    AvatarTooLarge(usize, u32),
+    PermissionDenied(String),
 }
 
 impl std::error::Error for Error {}
@@ -32,6 +35,11 @@ impl fmt::Display for Error {
             Error::Unimplemented => write!(f, "Unimplemented"),
             Error::NotConnectedToChannel => write!(f, "Not connected to a channel"),
             Error::ServerCertReject => write!(f, "Invalid server certificate"),
+            Error::InvalidAvatar(reason) => write!(f, "Invalid avatar: {}", reason),
+            Error::AvatarTooLarge(size, max) => {
+                write!(f, "Avatar too large: {} bytes (server allows {})", size, max)
+            }
+            Error::PermissionDenied(reason) => write!(f, "Permission denied: {}", reason),
         }
     }
 }
@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::net::IpAddr;
 
 /// The state of the currently connected Mumble server.
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -159,6 +160,9 @@ pub struct User {
     pub name: String,
     pub priority_speaker: bool,
     pub recording: bool,
+    /// Whether the user is talking, or for ourselves whether we're sending
+    /// audio.
+    pub talking: bool,
 
     pub suppress: bool,  // by me
     pub self_mute: bool, // by self
@@ -181,8 +185,9 @@ impl fmt::Display for User {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{} {}{}{}{}{}",
+            "{} {}{}{}{}{}{}",
             self.name,
+            true_to_str!(self.talking, "T"),
             true_to_str!(self.suppress, "s"),
             true_to_str!(self.self_mute, "M"),
             true_to_str!(self.self_deaf, "D"),
@@ -191,3 +196,72 @@ impl fmt::Display for User {
         )
     }
 }
+
+/// Statistics about a user's connection, as reported by the server.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UserStats {
+    /// The name of the user.
+    pub name: String,
+    /// Average and variance of the TCP ping, in milliseconds.
+    pub tcp_ping: (f32, f32),
+    /// Average and variance of the UDP ping, in milliseconds.
+    pub udp_ping: (f32, f32),
+    /// Number of TCP packets sent by the user.
+    pub tcp_packets: u32,
+    /// Number of UDP packets sent by the user.
+    pub udp_packets: u32,
+    /// Voice packets received by the server from the user.
+    pub from_client: PacketStats,
+    /// Voice packets received by the user from the server.
+    pub from_server: PacketStats,
+    /// The user's client version as `(major, minor, patch)`, if known.
+    pub version: Option<(u16, u8, u8)>,
+    /// The user's client release name, e.g. "1.3.4".
+    pub release: Option<String>,
+    /// The user's operating system.
+    pub os: Option<String>,
+    /// The version of the user's operating system.
+    pub os_version: Option<String>,
+    /// Whether the user's client supports Opus.
+    pub opus: bool,
+    /// The user's IP address. Only sent if we're allowed to see it.
+    pub address: Option<IpAddr>,
+    /// Bandwidth used by the user, in bits per second.
+    pub bandwidth: Option<u32>,
+    /// Seconds since the user connected.
+    pub online_secs: Option<u32>,
+    /// Seconds since the user was last active.
+    pub idle_secs: Option<u32>,
+    /// Length of the user's certificate chain.
+    pub certificates: usize,
+    /// Whether the user's certificate is signed by a trusted CA.
+    pub strong_certificate: bool,
+}
+
+/// Counters for voice packets sent in one direction.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct PacketStats {
+    pub good: u32,
+    pub late: u32,
+    pub lost: u32,
+    pub resync: u32,
+}
+
+/// Statistics about our own connection to the server.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ConnectionStats {
+    /// Average and variance of the TCP ping, in milliseconds.
+    pub tcp_ping: (f32, f32),
+    /// Average and variance of the UDP ping, in milliseconds.
+    pub udp_ping: (f32, f32),
+    /// Number of TCP pings the server has answered.
+    pub tcp_packets: u32,
+    /// Number of UDP pings the server has answered.
+    pub udp_packets: u32,
+    /// Voice packets received by the server from us, as reported by the server.
+    pub from_client: PacketStats,
+    /// Voice packets received by us from the server.
+    pub from_server: PacketStats,
+    /// Whether voice is sent over UDP. If not, it's tunneled through TCP.
+    pub udp: bool,
+}